@@ -0,0 +1,178 @@
+//! Helpers for walking a chain of sol boxes off-chain
+//!
+//! Boxes are linked through `next_box` and `prev_box`. The root of a chain
+//! points `prev_box` at itself and the tail points `next_box` at itself.
+
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+use std::collections::HashSet;
+use crate::{error::Sol2SolError, state::SolBox};
+
+/// Follows `next_box` links from `start` up to and including the tail
+///
+/// `get_account_data` returns the raw data of an account, or `None` if it
+/// does not exist.
+pub fn walk_forward<F>(start: &Pubkey, get_account_data: F) -> Result<Vec<(Pubkey, SolBox)>, ProgramError>
+where
+    F: FnMut(&Pubkey) -> Option<Vec<u8>>,
+{
+    walk(start, get_account_data, |sol_box| sol_box.next_box)
+}
+
+/// Follows `prev_box` links from `start` back to and including the root
+pub fn walk_backward<F>(start: &Pubkey, get_account_data: F) -> Result<Vec<(Pubkey, SolBox)>, ProgramError>
+where
+    F: FnMut(&Pubkey) -> Option<Vec<u8>>,
+{
+    walk(start, get_account_data, |sol_box| sol_box.prev_box)
+}
+
+/// Finds the last box of the chain containing `start`
+pub fn find_tail<F>(start: &Pubkey, get_account_data: F) -> Result<(Pubkey, SolBox), ProgramError>
+where
+    F: FnMut(&Pubkey) -> Option<Vec<u8>>,
+{
+    walk_forward(start, get_account_data)?
+        .pop()
+        .ok_or_else(|| Sol2SolError::IncorrectSolBox.into())
+}
+
+/// Finds the first box of the chain containing `start`
+pub fn find_root<F>(start: &Pubkey, get_account_data: F) -> Result<(Pubkey, SolBox), ProgramError>
+where
+    F: FnMut(&Pubkey) -> Option<Vec<u8>>,
+{
+    walk_backward(start, get_account_data)?
+        .pop()
+        .ok_or_else(|| Sol2SolError::IncorrectSolBox.into())
+}
+
+/// Finds the first box from `start` onwards with a free slot for WriteMessage
+///
+/// Returns `None` when every box is full and the owner needs to append one.
+pub fn find_sol_box_with_space<F>(start: &Pubkey, get_account_data: F) -> Result<Option<(Pubkey, SolBox)>, ProgramError>
+where
+    F: FnMut(&Pubkey) -> Option<Vec<u8>>,
+{
+    Ok(walk_forward(start, get_account_data)?
+        .into_iter()
+        .find(|(_, sol_box)| sol_box.has_space()))
+}
+
+fn walk<F, N>(start: &Pubkey, mut get_account_data: F, next: N) -> Result<Vec<(Pubkey, SolBox)>, ProgramError>
+where
+    F: FnMut(&Pubkey) -> Option<Vec<u8>>,
+    N: Fn(&SolBox) -> Pubkey,
+{
+    let mut boxes: Vec<(Pubkey, SolBox)> = Vec::new();
+    let mut visited = HashSet::new();
+    let mut current = *start;
+    loop {
+        if !visited.insert(current) {
+            // A well-formed chain ends at a box pointing to itself
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let data = get_account_data(&current).ok_or(Sol2SolError::IncorrectSolBox)?;
+        let sol_box = SolBox::unpack(&data)?;
+        if let Some((_, last)) = boxes.last() {
+            if last.owner != sol_box.owner {
+                return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+            }
+        }
+        let following = next(&sol_box);
+        boxes.push((current, sol_box));
+        if following == current {
+            return Ok(boxes);
+        }
+        current = following;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MIN_SOL_BOX_NUM_SPOTS;
+    use std::collections::HashMap;
+
+    fn chain(owner: &Pubkey, keys: &[Pubkey]) -> HashMap<Pubkey, Vec<u8>> {
+        let mut accounts = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let sol_box = SolBox {
+                owner: *owner,
+                next_box: *keys.get(i + 1).unwrap_or(key),
+                prev_box: if i == 0 { *key } else { keys[i - 1] },
+                num_spots: MIN_SOL_BOX_NUM_SPOTS,
+                num_in_use: 0,
+                is_initialized: true,
+                message_seq: 0,
+                pending_owner: Pubkey::default(),
+                access_list: Pubkey::default(),
+                postage_lamports: 0,
+                postage_mint: Pubkey::default(),
+                postage_token_amount: 0,
+                encryption_key: [0; 32],
+                read_receipts: false,
+                occupied: SolBox::get_empty_occupancy(MIN_SOL_BOX_NUM_SPOTS),
+                message_slots: SolBox::get_empty_message_slots(MIN_SOL_BOX_NUM_SPOTS),
+            };
+            let mut data = vec![0; SolBox::get_packed_len(MIN_SOL_BOX_NUM_SPOTS)];
+            SolBox::pack(sol_box, &mut data).unwrap();
+            accounts.insert(*key, data);
+        }
+        accounts
+    }
+
+    #[test]
+    fn test_walk_chain() {
+        let owner = Pubkey::new_unique();
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let accounts = chain(&owner, &keys);
+        let get = |key: &Pubkey| accounts.get(key).cloned();
+
+        let forward: Vec<Pubkey> = walk_forward(&keys[0], get).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys.to_vec(), forward);
+        let backward: Vec<Pubkey> = walk_backward(&keys[2], get).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(vec![keys[2], keys[1], keys[0]], backward);
+
+        assert_eq!(keys[2], find_tail(&keys[1], get).unwrap().0);
+        assert_eq!(keys[0], find_root(&keys[1], get).unwrap().0);
+    }
+
+    #[test]
+    fn test_find_sol_box_with_space() {
+        let owner = Pubkey::new_unique();
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut accounts = chain(&owner, &keys);
+
+        let mut root = SolBox::unpack(&accounts[&keys[0]]).unwrap();
+        while root.has_space() {
+            root.add_message(&Pubkey::new_unique()).unwrap();
+        }
+        SolBox::pack(root, accounts.get_mut(&keys[0]).unwrap()).unwrap();
+
+        let (key, _) = find_sol_box_with_space(&keys[0], |key| accounts.get(key).cloned())
+            .unwrap()
+            .unwrap();
+        assert_eq!(keys[1], key);
+    }
+
+    #[test]
+    fn test_walk_rejects_cycles_and_missing_boxes() {
+        let owner = Pubkey::new_unique();
+        let keys = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let mut accounts = chain(&owner, &keys);
+
+        let mut tail = SolBox::unpack(&accounts[&keys[1]]).unwrap();
+        tail.next_box = keys[0];
+        SolBox::pack(tail, accounts.get_mut(&keys[1]).unwrap()).unwrap();
+        assert_eq!(
+            walk_forward(&keys[0], |key| accounts.get(key).cloned()).unwrap_err(),
+            Sol2SolError::InvalidAccountData.into()
+        );
+
+        accounts.remove(&keys[1]);
+        assert_eq!(
+            walk_forward(&keys[0], |key| accounts.get(key).cloned()).unwrap_err(),
+            Sol2SolError::IncorrectSolBox.into()
+        );
+    }
+}
@@ -0,0 +1,457 @@
+//! Async mailbox client for wallets and tests
+//!
+//! `MailboxClient` drives the program on behalf of one wallet, the payer of
+//! its `Transport`. It sizes and funds the accounts it creates, finds the
+//! box a message goes to, and walks chains so callers only deal in wallet
+//! and message addresses. The inbox of a wallet is the chain rooted at its
+//! sol box number 0.
+
+use solana_program::{
+    instruction::Instruction, program_error::ProgramError, pubkey::Pubkey, rent::Rent,
+};
+use solana_sdk::{
+    account::Account,
+    packet::PACKET_DATA_SIZE,
+    signature::Keypair,
+    transaction::{Transaction, TransactionError},
+};
+use std::collections::HashSet;
+use thiserror::Error;
+use crate::{
+    error::Sol2SolError,
+    instruction::{
+        append_sol_box, close_sol_box, delete_message, find_message_address, find_sol_box_address,
+        init_sol_box, mark_messages, write_message,
+    },
+    state::{Message, SolBox, MESSAGE_STATUS_READ},
+};
+
+/// Most derived addresses tried when looking for the index of a tail box
+///
+/// Boxes are appended at the tail's index + 1, so the tail's index only
+/// exceeds the chain length by the boxes closed in between.
+pub const SOL_BOX_INDEX_SCAN_LIMIT: usize = 256;
+
+/// Errors from `MailboxClient`
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ClientError {
+    /// The transport could not reach the cluster
+    #[error("transport error: {0}")]
+    Transport(String),
+    /// A transaction was rejected
+    #[error("transaction failed: {0}")]
+    Transaction(TransactionError),
+    /// An account could not be decoded or an instruction built
+    #[error("program error: {0}")]
+    Program(ProgramError),
+    /// The wallet has no inbox
+    #[error("no inbox for {0}")]
+    InboxNotFound(Pubkey),
+    /// Every box of the recipient's inbox is full
+    #[error("inbox of {0} is full")]
+    InboxFull(Pubkey),
+    /// The message is not in the payer's inbox
+    #[error("message {0} not found")]
+    MessageNotFound(Pubkey),
+    /// The recipient charges postage in tokens, which the client does not pay
+    #[error("box {0} charges token postage")]
+    TokenPostageRequired(Pubkey),
+    /// The box only accepts sealed messages, and the client was built
+    /// without the `encryption` feature
+    #[error("box {0} requires encrypted messages")]
+    EncryptionRequired(Pubkey),
+    /// The payer cannot fund the message account and postage
+    #[error("need {needed} lamports, have {available}")]
+    InsufficientFunds {
+        /// Lamports the transaction would cost, before fees
+        needed: u64,
+        /// Lamports the payer holds
+        available: u64,
+    },
+}
+
+impl From<ProgramError> for ClientError {
+    fn from(e: ProgramError) -> Self {
+        ClientError::Program(e)
+    }
+}
+
+impl From<Sol2SolError> for ClientError {
+    fn from(e: Sol2SolError) -> Self {
+        ClientError::Program(e.into())
+    }
+}
+
+/// How `MailboxClient` talks to a cluster
+///
+/// Implementations sign every transaction with the wallet returned by
+/// `payer`, which also pays its fees.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// Wallet the client acts for
+    fn payer(&self) -> Pubkey;
+
+    /// Fetches an account, `None` if it does not exist
+    async fn get_account(&mut self, pubkey: &Pubkey) -> Result<Option<Account>, ClientError>;
+
+    /// Fetches the cluster's rent parameters
+    async fn get_rent(&mut self) -> Result<Rent, ClientError>;
+
+    /// Sends `instructions` in one transaction and waits for it to land
+    ///
+    /// `signers` are needed besides the payer.
+    async fn process_instructions(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), ClientError>;
+}
+
+/// Mailbox of the transport's payer
+pub struct MailboxClient<T: Transport> {
+    program_id: Pubkey,
+    transport: T,
+}
+
+impl<T: Transport> MailboxClient<T> {
+    /// Creates a client for the program deployed at `program_id`
+    pub fn new(program_id: Pubkey, transport: T) -> Self {
+        Self { program_id, transport }
+    }
+
+    /// Wallet the client acts for
+    pub fn owner(&self) -> Pubkey {
+        self.transport.payer()
+    }
+
+    /// The underlying transport
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Consumes the client, returning its transport
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Address of the root box of `owner`'s inbox
+    pub fn inbox_address(&self, owner: &Pubkey) -> Pubkey {
+        find_sol_box_address(&self.program_id, owner, 0).0
+    }
+
+    /// Gives the payer room for `num_spots` more messages, returning the new box
+    ///
+    /// Creates the inbox root if the payer has none, otherwise has the
+    /// program create a box after the tail of the chain.
+    pub async fn create_inbox(&mut self, num_spots: u32) -> Result<Pubkey, ClientError> {
+        SolBox::check_num_spots(num_spots)?;
+        let owner = self.owner();
+        let root = self.inbox_address(&owner);
+        if self.transport.get_account(&root).await?.is_none() {
+            let instruction = init_sol_box(&self.program_id, &owner, 0, num_spots)?;
+            self.transport.process_instructions(&[instruction], &[]).await?;
+            return Ok(root);
+        }
+
+        let chain = self.chain(&owner).await?;
+        let (tail, _) = chain.last().ok_or(ClientError::InboxNotFound(owner))?;
+        let index = self.sol_box_index(&owner, tail, chain.len() as u32 - 1)?;
+        let instruction = append_sol_box(&self.program_id, &owner, index, num_spots)?;
+        self.transport.process_instructions(&[instruction], &[]).await?;
+        Ok(find_sol_box_address(&self.program_id, &owner, index + 1).0)
+    }
+
+    /// Sends `msg_data` to `recipient`'s inbox, returning the message address
+    ///
+    /// The message goes to the first box of the chain with a free slot. The
+    /// payer funds the message account and any lamport postage the box
+    /// charges. Boxes with an encryption key get the body sealed to it.
+    pub async fn send(&mut self, recipient: &Pubkey, msg_data: &[u8]) -> Result<Pubkey, ClientError> {
+        let (sol_box_pubkey, sol_box) = self
+            .chain(recipient)
+            .await?
+            .into_iter()
+            .find(|(_, sol_box)| sol_box.has_space())
+            .ok_or(ClientError::InboxFull(*recipient))?;
+        let payer = self.owner();
+        // Owners write to their own inbox for free
+        let charges_postage = payer != *recipient;
+        if charges_postage && sol_box.postage_mint != Pubkey::default() {
+            return Err(ClientError::TokenPostageRequired(sol_box_pubkey));
+        }
+        let sealed;
+        let msg_data = if sol_box.encryption_key == [0; 32] {
+            msg_data
+        } else {
+            sealed = seal(&sol_box_pubkey, &sol_box.encryption_key, msg_data)?;
+            &sealed[..]
+        };
+
+        let rent = self.transport.get_rent().await?;
+        let mut needed = rent.minimum_balance(Message::get_packed_len(msg_data.len() as u32));
+        if charges_postage {
+            needed = needed.saturating_add(sol_box.postage_lamports);
+        }
+        let available = self.transport.get_account(&payer).await?.map_or(0, |account| account.lamports);
+        if available < needed {
+            return Err(ClientError::InsufficientFunds { needed, available });
+        }
+
+        let access_list = Some(sol_box.access_list).filter(|key| *key != Pubkey::default());
+        let instruction = write_message(
+            &self.program_id,
+            &payer,
+            recipient,
+            &sol_box_pubkey,
+            sol_box.message_seq,
+            msg_data,
+            access_list.as_ref(),
+            None,
+        )?;
+        self.transport.process_instructions(&[instruction], &[]).await?;
+        Ok(find_message_address(&self.program_id, &sol_box_pubkey, sol_box.message_seq).0)
+    }
+
+    /// Messages in the payer's inbox, box by box in chain order
+    pub async fn list(&mut self) -> Result<Vec<(Pubkey, Message)>, ClientError> {
+        let owner = self.owner();
+        let mut messages = Vec::new();
+        for (_, sol_box) in self.chain(&owner).await? {
+            for message_pubkey in sol_box.stored_messages() {
+                let message = self.get_message(&message_pubkey).await?;
+                messages.push((message_pubkey, message));
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Fetches a message in the payer's inbox, marking it read
+    pub async fn read(&mut self, message_pubkey: &Pubkey) -> Result<Message, ClientError> {
+        let mut message = self.get_message(message_pubkey).await?;
+        let sol_box_pubkey = self.find_holding_box(message_pubkey, &message).await?;
+        if message.status & MESSAGE_STATUS_READ == 0 {
+            let instruction = mark_messages(
+                &self.program_id,
+                &self.owner(),
+                &sol_box_pubkey,
+                &[*message_pubkey],
+                MESSAGE_STATUS_READ,
+                0,
+            )?;
+            self.transport.process_instructions(&[instruction], &[]).await?;
+            message.status |= MESSAGE_STATUS_READ;
+        }
+        Ok(message)
+    }
+
+    /// Deletes a message in the payer's inbox, refunding its rent to the payer
+    pub async fn delete(&mut self, message_pubkey: &Pubkey) -> Result<(), ClientError> {
+        let message = self.get_message(message_pubkey).await?;
+        let sol_box_pubkey = self.find_holding_box(message_pubkey, &message).await?;
+        let owner = self.owner();
+        let instruction = delete_message(&self.program_id, &owner, &sol_box_pubkey, message_pubkey, &owner)?;
+        self.transport.process_instructions(&[instruction], &[]).await
+    }
+
+    /// Closes every box of the payer's inbox and the messages in them
+    ///
+    /// Boxes are closed from the tail back to the root, and every lamport
+    /// is refunded to the payer. A box whose messages do not all fit in
+    /// one transaction has them deleted in batches first, each as large as
+    /// `PACKET_DATA_SIZE` allows.
+    pub async fn close(&mut self) -> Result<(), ClientError> {
+        let owner = self.owner();
+        for (sol_box_pubkey, sol_box) in self.chain(&owner).await?.into_iter().rev() {
+            let mut messages = sol_box.stored_messages();
+            loop {
+                // Every later box is gone, so this one is the tail
+                let instructions = [close_sol_box(
+                    &self.program_id,
+                    &owner,
+                    &sol_box_pubkey,
+                    &sol_box.prev_box,
+                    &sol_box_pubkey,
+                    &owner,
+                    &messages,
+                )?];
+                if transaction_size(&instructions, &owner)? <= PACKET_DATA_SIZE {
+                    self.transport.process_instructions(&instructions, &[]).await?;
+                    break;
+                }
+                let mut instructions = Vec::new();
+                while let Some(message_pubkey) = messages.pop() {
+                    instructions.push(delete_message(
+                        &self.program_id,
+                        &owner,
+                        &sol_box_pubkey,
+                        &message_pubkey,
+                        &owner,
+                    )?);
+                    if instructions.len() > 1 && transaction_size(&instructions, &owner)? > PACKET_DATA_SIZE {
+                        instructions.pop();
+                        messages.push(message_pubkey);
+                        break;
+                    }
+                }
+                self.transport.process_instructions(&instructions, &[]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index `sol_box` was derived at, trying indexes from `from` up
+    fn sol_box_index(&self, owner: &Pubkey, sol_box: &Pubkey, from: u32) -> Result<u32, ClientError> {
+        (from..=u32::MAX)
+            .take(SOL_BOX_INDEX_SCAN_LIMIT)
+            .find(|index| find_sol_box_address(&self.program_id, owner, *index).0 == *sol_box)
+            .ok_or_else(|| Sol2SolError::IncorrectSolBox.into())
+    }
+
+    /// Boxes of `owner`'s inbox from root to tail
+    ///
+    /// Mirrors `chain::walk_forward`, fetching each box as it goes.
+    async fn chain(&mut self, owner: &Pubkey) -> Result<Vec<(Pubkey, SolBox)>, ClientError> {
+        let root = self.inbox_address(owner);
+        let mut boxes: Vec<(Pubkey, SolBox)> = Vec::new();
+        let mut visited = HashSet::new();
+        let mut current = root;
+        loop {
+            if !visited.insert(current) {
+                return Err(Sol2SolError::InvalidAccountData.into());
+            }
+            let account = match self.transport.get_account(&current).await? {
+                Some(account) => account,
+                None if current == root => return Err(ClientError::InboxNotFound(*owner)),
+                None => return Err(Sol2SolError::IncorrectSolBox.into()),
+            };
+            if account.owner != self.program_id {
+                return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+            }
+            let sol_box = SolBox::unpack(&account.data)?;
+            if sol_box.owner != *owner {
+                return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+            }
+            let next = sol_box.next_box;
+            boxes.push((current, sol_box));
+            if next == current {
+                return Ok(boxes);
+            }
+            current = next;
+        }
+    }
+
+    async fn get_message(&mut self, message_pubkey: &Pubkey) -> Result<Message, ClientError> {
+        match self.transport.get_account(message_pubkey).await? {
+            Some(account) if account.owner == self.program_id => Ok(Message::unpack(&account.data)?),
+            _ => Err(ClientError::MessageNotFound(*message_pubkey)),
+        }
+    }
+
+    /// Finds the box of the payer's inbox storing `message`
+    ///
+    /// `message.recipient` is not checked, as it keeps naming the previous
+    /// owner of an inbox that changed hands.
+    async fn find_holding_box(&mut self, message_pubkey: &Pubkey, message: &Message) -> Result<Pubkey, ClientError> {
+        let owner = self.owner();
+        let chain = match self.chain(&owner).await {
+            Err(ClientError::InboxNotFound(_)) => return Err(ClientError::MessageNotFound(*message_pubkey)),
+            chain => chain?,
+        };
+        chain
+            .into_iter()
+            .find(|(_, sol_box)| sol_box.holds(message.slot, message_pubkey))
+            .map(|(sol_box_pubkey, _)| sol_box_pubkey)
+            .ok_or(ClientError::MessageNotFound(*message_pubkey))
+    }
+}
+
+/// Serialized size of an unsigned transaction of `instructions` paid by `payer`
+///
+/// Signatures are counted at full size, so a transaction no larger than
+/// `PACKET_DATA_SIZE` fits in a packet once signed.
+pub fn transaction_size(instructions: &[Instruction], payer: &Pubkey) -> Result<usize, ClientError> {
+    let transaction = Transaction::new_with_payer(instructions, Some(payer));
+    bincode::serialized_size(&transaction)
+        .map(|size| size as usize)
+        .map_err(|e| ClientError::Transport(e.to_string()))
+}
+
+/// Seals `msg_data` to a box's encryption key
+#[cfg(feature = "encryption")]
+fn seal(_sol_box_pubkey: &Pubkey, encryption_key: &[u8; 32], msg_data: &[u8]) -> Result<Vec<u8>, ClientError> {
+    Ok(crate::envelope::seal(encryption_key, msg_data)?.pack())
+}
+
+/// Without the `encryption` feature, sealed boxes cannot be written to
+#[cfg(not(feature = "encryption"))]
+fn seal(sol_box_pubkey: &Pubkey, _encryption_key: &[u8; 32], _msg_data: &[u8]) -> Result<Vec<u8>, ClientError> {
+    Err(ClientError::EncryptionRequired(*sol_box_pubkey))
+}
+
+#[cfg(feature = "banks-client")]
+pub use banks::BanksTransport;
+
+#[cfg(feature = "banks-client")]
+mod banks {
+    use super::{ClientError, Transport};
+    use solana_banks_client::{BanksClient, BanksClientError};
+    use solana_program::{instruction::Instruction, pubkey::Pubkey, rent::Rent};
+    use solana_sdk::{
+        account::Account,
+        signature::{Keypair, Signer},
+        transaction::Transaction,
+    };
+
+    /// `Transport` over a `BanksClient`, as used by `solana-program-test`
+    pub struct BanksTransport {
+        /// Client of the bank
+        pub banks_client: BanksClient,
+        /// Wallet signing and paying for every transaction
+        pub payer: Keypair,
+    }
+
+    impl BanksTransport {
+        /// Creates a transport acting for `payer`
+        pub fn new(banks_client: BanksClient, payer: Keypair) -> Self {
+            Self { banks_client, payer }
+        }
+    }
+
+    impl From<BanksClientError> for ClientError {
+        fn from(e: BanksClientError) -> Self {
+            match e {
+                BanksClientError::TransactionError(err) | BanksClientError::SimulationError { err, .. } => {
+                    ClientError::Transaction(err)
+                }
+                e => ClientError::Transport(e.to_string()),
+            }
+        }
+    }
+
+    impl Transport for BanksTransport {
+        fn payer(&self) -> Pubkey {
+            self.payer.pubkey()
+        }
+
+        async fn get_account(&mut self, pubkey: &Pubkey) -> Result<Option<Account>, ClientError> {
+            Ok(self.banks_client.get_account(*pubkey).await?)
+        }
+
+        async fn get_rent(&mut self) -> Result<Rent, ClientError> {
+            Ok(self.banks_client.get_rent().await?)
+        }
+
+        async fn process_instructions(
+            &mut self,
+            instructions: &[Instruction],
+            signers: &[&Keypair],
+        ) -> Result<(), ClientError> {
+            let recent_blockhash = self.banks_client.get_latest_blockhash().await?;
+            let mut transaction = Transaction::new_with_payer(instructions, Some(&self.payer.pubkey()));
+            let mut all_signers = vec![&self.payer];
+            all_signers.extend_from_slice(signers);
+            transaction.sign(&all_signers, recent_blockhash);
+            Ok(self.banks_client.process_transaction(transaction).await?)
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Sealed message bodies
+//!
+//! Boxes with an `encryption_key` only accept bodies wrapped in an
+//! `Envelope`. The program checks the envelope header but never sees the
+//! plaintext; sealing and opening happen client side behind the
+//! `encryption` feature.
+
+use arrayref::{array_ref, array_refs};
+use solana_program::program_error::ProgramError;
+use crate::error::Sol2SolError;
+
+/// Envelope format written by this version of the crate
+pub const ENVELOPE_VERSION: u8 = 1;
+/// Bytes before the ciphertext (version + ephemeral_key + nonce)
+pub const ENVELOPE_HEADER_LEN: usize = 57;
+/// Bytes the authentication tag adds to the plaintext
+pub const ENVELOPE_TAG_LEN: usize = 16;
+
+/// Envelope
+///
+/// The sender seals to the box's x25519 key using a fresh ephemeral key,
+/// so only the holder of the box's secret key can open the ciphertext.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Envelope {
+    /// Format of the rest of the envelope
+    pub version: u8,
+    /// Sender's one-time x25519 public key
+    pub ephemeral_key: [u8; 32],
+    /// XChaCha20-Poly1305 nonce
+    pub nonce: [u8; 24],
+    /// Encrypted body followed by its authentication tag
+    pub ciphertext: Vec<u8>,
+}
+impl Envelope {
+    /// Message body size needed to hold an envelope around `plaintext_len` bytes
+    pub fn get_packed_len(plaintext_len: usize) -> usize {
+        ENVELOPE_HEADER_LEN + ENVELOPE_TAG_LEN + plaintext_len
+    }
+
+    /// Errors unless `src` is a well-formed envelope of a known version
+    pub fn check(src: &[u8]) -> Result<(), ProgramError> {
+        if src.len() < Self::get_packed_len(0) || src[0] != ENVELOPE_VERSION {
+            return Err(Sol2SolError::InvalidMessageEncoding.into());
+        }
+        Ok(())
+    }
+
+    /// Decodes an envelope from a message body
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        Self::check(src)?;
+        let (header_src, ciphertext) = src.split_at(ENVELOPE_HEADER_LEN);
+        let header_src = array_ref![header_src, 0, ENVELOPE_HEADER_LEN];
+        let (version, ephemeral_key, nonce) = array_refs![header_src, 1, 32, 24];
+        Ok(Self {
+            version: version[0],
+            ephemeral_key: *ephemeral_key,
+            nonce: *nonce,
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+
+    /// Encodes the envelope as a message body
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(ENVELOPE_HEADER_LEN + self.ciphertext.len());
+        buf.push(self.version);
+        buf.extend_from_slice(&self.ephemeral_key);
+        buf.extend_from_slice(&self.nonce);
+        buf.extend_from_slice(&self.ciphertext);
+        buf
+    }
+}
+
+#[cfg(feature = "encryption")]
+pub use self::sealing::{open, public_key, seal};
+
+#[cfg(feature = "encryption")]
+mod sealing {
+    use super::*;
+    use chacha20poly1305::{
+        aead::{Aead, NewAead, Payload},
+        Key, XChaCha20Poly1305, XNonce,
+    };
+    use rand_core::{OsRng, RngCore};
+    use solana_program::hash::hashv;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    /// Domain separator mixed into every envelope key
+    const ENVELOPE_KEY_DOMAIN: &[u8] = b"sol2sol-envelope-v1";
+
+    fn envelope_cipher(shared_secret: &[u8; 32], ephemeral_key: &[u8; 32], recipient_key: &[u8; 32]) -> XChaCha20Poly1305 {
+        let key = hashv(&[ENVELOPE_KEY_DOMAIN, shared_secret, ephemeral_key, recipient_key]);
+        XChaCha20Poly1305::new(&Key::from(key.to_bytes()))
+    }
+
+    /// The x25519 public key to store as a box's `encryption_key`
+    pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+        PublicKey::from(&StaticSecret::from(*secret_key)).to_bytes()
+    }
+
+    /// Seals `plaintext` so only the holder of `recipient_key`'s secret can read it
+    pub fn seal(recipient_key: &[u8; 32], plaintext: &[u8]) -> Result<Envelope, ProgramError> {
+        let ephemeral_secret = StaticSecret::new(OsRng);
+        let ephemeral_key = PublicKey::from(&ephemeral_secret).to_bytes();
+        let shared_secret = ephemeral_secret.diffie_hellman(&PublicKey::from(*recipient_key));
+        let mut nonce = [0; 24];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = envelope_cipher(shared_secret.as_bytes(), &ephemeral_key, recipient_key)
+            .encrypt(&XNonce::from(nonce), Payload { msg: plaintext, aad: &[ENVELOPE_VERSION] })
+            .map_err(|_| Sol2SolError::InvalidMessageEncoding)?;
+        Ok(Envelope {
+            version: ENVELOPE_VERSION,
+            ephemeral_key,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Opens an envelope sealed to the public key of `secret_key`
+    pub fn open(secret_key: &[u8; 32], envelope: &Envelope) -> Result<Vec<u8>, ProgramError> {
+        if envelope.version != ENVELOPE_VERSION {
+            return Err(Sol2SolError::InvalidMessageEncoding.into());
+        }
+        let secret = StaticSecret::from(*secret_key);
+        let recipient_key = PublicKey::from(&secret).to_bytes();
+        let shared_secret = secret.diffie_hellman(&PublicKey::from(envelope.ephemeral_key));
+
+        envelope_cipher(shared_secret.as_bytes(), &envelope.ephemeral_key, &recipient_key)
+            .decrypt(
+                &XNonce::from(envelope.nonce),
+                Payload { msg: &envelope.ciphertext, aad: &[envelope.version] },
+            )
+            .map_err(|_| Sol2SolError::MessageDecryptionFailed.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_format() {
+        let envelope = Envelope {
+            version: ENVELOPE_VERSION,
+            ephemeral_key: [7; 32],
+            nonce: [9; 24],
+            ciphertext: vec![1; ENVELOPE_TAG_LEN + 3],
+        };
+        let packed = envelope.pack();
+        assert_eq!(Envelope::get_packed_len(3), packed.len());
+        assert_eq!(envelope, Envelope::unpack(&packed).unwrap());
+
+        assert_eq!(
+            Envelope::unpack(&packed[..Envelope::get_packed_len(0) - 1]),
+            Err(Sol2SolError::InvalidMessageEncoding.into())
+        );
+        let mut unknown_version = packed;
+        unknown_version[0] = ENVELOPE_VERSION + 1;
+        assert_eq!(Envelope::check(&unknown_version), Err(Sol2SolError::InvalidMessageEncoding.into()));
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_seal_and_open() {
+        let secret_key = [42; 32];
+        let envelope = seal(&public_key(&secret_key), b"hello!").unwrap();
+        assert_eq!(Envelope::get_packed_len(6), envelope.pack().len());
+
+        let recreated = Envelope::unpack(&envelope.pack()).unwrap();
+        assert_eq!(b"hello!".to_vec(), open(&secret_key, &recreated).unwrap());
+        assert_eq!(open(&[43; 32], &recreated), Err(Sol2SolError::MessageDecryptionFailed.into()));
+
+        let mut tampered = recreated;
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(open(&secret_key, &tampered), Err(Sol2SolError::MessageDecryptionFailed.into()));
+    }
+}
@@ -36,6 +36,75 @@ pub enum Sol2SolError {
     /// Make sure instruction data matches account info 
     #[error("Sol box info does not match passed pubkey")]
     IncorrectSolBox,
+    /// Message being deleted is not referenced by the sol box
+    #[error("Message is not stored in this sol box")]
+    MessageNotInSolBox,
+    /// Only the paying signer may be recorded as a message's sender
+    #[error("Sender must be the signing payer")]
+    SenderMismatch,
+    /// Message accounts are write-once
+    #[error("Message account already holds a message")]
+    MessageAccountInUse,
+    /// Sol box accounts can only be initialized once
+    #[error("Sol box is already initialized")]
+    SolBoxAlreadyInUse,
+    /// New boxes can only be linked after the last box of a chain
+    #[error("Sol box is not the tail of its chain")]
+    SolBoxNotChainTail,
+    /// A message's `msg_size` must match the bytes stored after its header
+    #[error("Message size does not match message data")]
+    MessageSizeMismatch,
+    /// Plaintext bodies must be utf-8 and sealed bodies well-formed envelopes
+    #[error("Message body is not in the expected encoding")]
+    InvalidMessageEncoding,
+    /// Sol boxes can only be closed once their messages are gone
+    #[error("Sol box still stores messages")]
+    SolBoxNotEmpty,
+    /// Closing the root would strand the rest of the chain
+    #[error("Sol box is the root of a chain with other boxes")]
+    SolBoxIsChainRoot,
+    /// The sol box's access list rejects this sender
+    #[error("Sender is not allowed to write to this sol box")]
+    SenderNotAllowed,
+    /// Access list account does not match the one linked from the sol box
+    #[error("Access list does not belong to this sol box")]
+    IncorrectAccessList,
+    /// Access list has no room for another sender
+    #[error("Access list is full")]
+    AccessListFull,
+    /// Sender being removed is not on the access list
+    #[error("Sender is not on the access list")]
+    SenderNotInAccessList,
+    /// Token postage must be paid in the sol box's postage mint
+    #[error("Token account mint does not match the sol box postage mint")]
+    PostageMintMismatch,
+    /// A sealed message can only be opened with the box's encryption secret
+    #[error("Message could not be decrypted")]
+    MessageDecryptionFailed,
+    /// Only anonymous messages carry a reply token to answer through
+    #[error("Message was not sent anonymously")]
+    MessageNotAnonymous,
+    /// A reply must name an existing message and that message's thread
+    #[error("Reply does not point to an existing message in its thread")]
+    InvalidThreadReference,
+    /// Only messages past their expiry can be purged
+    #[error("Message has not expired")]
+    MessageNotExpired,
+    /// A sol box can only be linked to one access list
+    #[error("Sol box already has an access list")]
+    AccessListAlreadyLinked,
+    /// Version 0 accounts can be read but must be migrated before they are written
+    #[error("Account uses an old layout and must be migrated first")]
+    AccountNotMigrated,
+    /// realloc can only grow an account by `MAX_PERMITTED_DATA_INCREASE` per instruction
+    #[error("Account cannot grow by more than 10KB in one instruction")]
+    AccountResizeTooLarge,
+    /// A sol box link does not point where the chain requires
+    #[error("Sol box is not linked to this box")]
+    InvalidSolBoxLink,
+    /// Anonymous messages record no funder to refund, so they never expire
+    #[error("Anonymous messages cannot expire")]
+    MessageIsAnonymous,
 }
 impl From<Sol2SolError> for ProgramError {
     fn from(e: Sol2SolError) -> Self {
@@ -0,0 +1,358 @@
+//! Structured events for indexers
+//!
+//! Every instruction that changes program state logs one `Sol2SolEvent`
+//! per change as `Program log: sol2sol:event:<base64>`, where the payload is
+//! the Borsh encoding of the event. Variants are only ever appended, so old
+//! logs keep decoding. `decode_events` rebuilds the events of a transaction
+//! from its log messages.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{msg, program_error::ProgramError, pubkey::Pubkey};
+use std::str::FromStr;
+use crate::{
+    error::Sol2SolError,
+    state::{AccessListMode, MessageExpiry},
+};
+
+/// Text an event log starts with, after the runtime's `Program log: `
+pub const EVENT_LOG_PREFIX: &str = "sol2sol:event:";
+
+/// Something the program did
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum Sol2SolEvent {
+    /// A root box was created by InitializeSolBox
+    BoxInitialized {
+        /// Address of the box
+        sol_box: Pubkey,
+        /// Owner of the box
+        owner: Pubkey,
+        /// How many messages the box holds
+        num_spots: u32,
+        /// Which of the owner's boxes this is
+        index: u32,
+    },
+    /// A box was linked after the tail of a chain
+    BoxAppended {
+        /// Address of the new box
+        sol_box: Pubkey,
+        /// Former tail of the chain
+        prev_box: Pubkey,
+        /// Owner of the chain
+        owner: Pubkey,
+        /// How many messages the box holds
+        num_spots: u32,
+        /// Which of the owner's boxes this is
+        index: u32,
+    },
+    /// A box was unlinked and closed, after `MessageDeleted` for its messages
+    BoxClosed {
+        /// Address of the box
+        sol_box: Pubkey,
+        /// Owner of the box
+        owner: Pubkey,
+    },
+    /// A message was delivered to a box
+    MessageWritten {
+        /// Box the message was stored in
+        sol_box: Pubkey,
+        /// Address of the message account
+        message: Pubkey,
+        /// Owner of the box
+        recipient: Pubkey,
+        /// Sender, or the reply token if `is_anonymous`
+        sender: Pubkey,
+        /// Slot of the box holding the message
+        slot: u32,
+        /// Sequence number the message address was derived from
+        message_seq: u64,
+        /// Length in bytes of the body
+        msg_size: u32,
+        /// Was written without recording the sender
+        is_anonymous: bool,
+        /// Message this one answers, `Pubkey::default()` if it starts a thread
+        reply_to: Pubkey,
+        /// First message of the thread, `Pubkey::default()` if it starts a thread
+        thread_root: Pubkey,
+    },
+    /// The box owner deleted a message, or it went with its box
+    MessageDeleted {
+        /// Box the message was stored in
+        sol_box: Pubkey,
+        /// Address of the message account
+        message: Pubkey,
+    },
+    /// An expired message was purged by PurgeExpired
+    MessagePurged {
+        /// Box the message was stored in
+        sol_box: Pubkey,
+        /// Address of the message account
+        message: Pubkey,
+        /// Who earned the bounty
+        caller: Pubkey,
+    },
+    /// The recipient changed the `MESSAGE_STATUS_*` flags of a message
+    MessageStatusChanged {
+        /// Box the message is stored in
+        sol_box: Pubkey,
+        /// Address of the message account
+        message: Pubkey,
+        /// Flags after the change
+        status: u8,
+    },
+    /// A message was first marked read in a box with read receipts on
+    ReadReceipt {
+        /// Address of the message account
+        message: Pubkey,
+        /// Sender of the message
+        sender: Pubkey,
+        /// Owner of the box who read it
+        reader: Pubkey,
+    },
+    /// The expiry of a message changed
+    MessageExpirySet {
+        /// Box the message is stored in
+        sol_box: Pubkey,
+        /// Address of the message account
+        message: Pubkey,
+        /// New expiry
+        expiry: MessageExpiry,
+    },
+    /// The recipient of an anonymous message answered it
+    ReplyWritten {
+        /// Address of the reply account
+        reply: Pubkey,
+        /// Anonymous message answered
+        message: Pubkey,
+        /// Reply token the reply is addressed to
+        reply_token: Pubkey,
+    },
+    /// A reply was closed by its reply token
+    ReplyDeleted {
+        /// Address of the reply account
+        reply: Pubkey,
+        /// Reply token the reply was addressed to
+        reply_token: Pubkey,
+    },
+    /// A two-step ownership transfer was started, or cancelled if
+    /// `pending_owner` is `Pubkey::default()`
+    OwnershipTransferPending {
+        /// Root box of the chain
+        sol_box: Pubkey,
+        /// Current owner of the chain
+        owner: Pubkey,
+        /// Who may accept the chain
+        pending_owner: Pubkey,
+    },
+    /// A chain moved to a new owner
+    OwnershipTransferred {
+        /// First box of the moved chain, at its new address
+        sol_box: Pubkey,
+        /// Previous owner
+        owner: Pubkey,
+        /// New owner
+        new_owner: Pubkey,
+    },
+    /// An access list was created and linked from a box
+    AccessListInitialized {
+        /// Address of the access list
+        access_list: Pubkey,
+        /// Box the list was created for
+        sol_box: Pubkey,
+        /// Owner of the box
+        owner: Pubkey,
+        /// How entries are interpreted
+        mode: AccessListMode,
+        /// How many senders the list can hold
+        capacity: u32,
+    },
+    /// An existing access list was linked from another box
+    AccessListLinked {
+        /// Address of the access list
+        access_list: Pubkey,
+        /// Box now linking the list
+        sol_box: Pubkey,
+    },
+    /// A sender was put on an access list
+    AccessListEntryAdded {
+        /// Address of the access list
+        access_list: Pubkey,
+        /// Sender listed
+        sender: Pubkey,
+    },
+    /// A sender was taken off an access list
+    AccessListEntryRemoved {
+        /// Address of the access list
+        access_list: Pubkey,
+        /// Sender removed
+        sender: Pubkey,
+    },
+    /// The mode of an access list changed
+    AccessListModeSet {
+        /// Address of the access list
+        access_list: Pubkey,
+        /// New mode
+        mode: AccessListMode,
+    },
+    /// The lamport postage of a box changed
+    PostageSet {
+        /// Address of the box
+        sol_box: Pubkey,
+        /// Lamports charged per message
+        lamports: u64,
+    },
+    /// The token postage of a box changed
+    TokenPostageSet {
+        /// Address of the box
+        sol_box: Pubkey,
+        /// Mint of the postage token, `Pubkey::default()` for none
+        mint: Pubkey,
+        /// Tokens charged per message
+        amount: u64,
+    },
+    /// The encryption key of a box changed
+    EncryptionKeySet {
+        /// Address of the box
+        sol_box: Pubkey,
+        /// New x25519 key, all zeros for none
+        encryption_key: [u8; 32],
+    },
+    /// Read receipts were turned on or off for a box
+    ReadReceiptsSet {
+        /// Address of the box
+        sol_box: Pubkey,
+        /// Whether receipts are logged
+        enabled: bool,
+    },
+    /// MigrateAccount rewrote an account in the current layout
+    AccountMigrated {
+        /// Address of the account
+        account: Pubkey,
+        /// Account tag, one of the `state::*_TAG` constants
+        tag: u8,
+        /// Layout version the account had before
+        from_version: u8,
+    },
+    /// A box moved to the new owner's derived address, before
+    /// `OwnershipTransferred` for its chain
+    BoxMoved {
+        /// Former address of the box, now closed
+        sol_box: Pubkey,
+        /// Address of the box under the new owner
+        new_sol_box: Pubkey,
+        /// Box before it under the new owner: the box moved before it, the
+        /// tail of the new owner's inbox, or `new_sol_box` for a root
+        prev_box: Pubkey,
+        /// Which of the new owner's boxes this is
+        index: u32,
+    },
+}
+impl Sol2SolEvent {
+    /// Encodes the event as the payload of its log line
+    pub fn pack(&self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
+    }
+
+    /// Decodes an event from the payload of its log line
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        Self::try_from_slice(input).map_err(|_| Sol2SolError::InvalidInstructionData.into())
+    }
+
+    /// Logs the event
+    pub fn emit(&self) {
+        msg!("{}{}", EVENT_LOG_PREFIX, STANDARD.encode(self.pack()));
+    }
+
+    /// Decodes an event from a single log message, `None` if it is not one
+    ///
+    /// This does not check which program wrote the line; use
+    /// `decode_events` for a transaction's logs.
+    pub fn from_log(log: &str) -> Option<Self> {
+        let payload = log.strip_prefix("Program log: ")?.strip_prefix(EVENT_LOG_PREFIX)?;
+        Self::unpack(&STANDARD.decode(payload).ok()?).ok()
+    }
+}
+
+/// Rebuilds the events `program_id` emitted from a transaction's log messages
+///
+/// Lines are attributed to the program running when they were logged, so
+/// lines other programs log to look like events are skipped. Events are
+/// returned in the order they happened.
+pub fn decode_events(program_id: &Pubkey, logs: &[String]) -> Vec<Sol2SolEvent> {
+    let mut invoked: Vec<Pubkey> = Vec::new();
+    let mut events = Vec::new();
+    for log in logs {
+        let mut words = log.split(' ');
+        if words.next() != Some("Program") {
+            continue;
+        }
+        let (first, second) = (words.next(), words.next());
+        match (first.and_then(|word| Pubkey::from_str(word).ok()), second) {
+            (Some(program), Some("invoke")) => invoked.push(program),
+            (Some(_), Some("success" | "failed:")) => {
+                invoked.pop();
+            }
+            (None, Some(_)) if first == Some("log:") && invoked.last() == Some(program_id) => {
+                events.extend(Sol2SolEvent::from_log(log));
+            }
+            _ => {}
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log_round_trip() {
+        let event = Sol2SolEvent::MessageExpirySet {
+            sol_box: Pubkey::new_unique(),
+            message: Pubkey::new_unique(),
+            expiry: MessageExpiry::Slot(42),
+        };
+        let log = format!("Program log: {}{}", EVENT_LOG_PREFIX, STANDARD.encode(event.pack()));
+        assert_eq!(Some(event), Sol2SolEvent::from_log(&log));
+
+        assert_eq!(None, Sol2SolEvent::from_log("Program log: Writing to sol box"));
+        assert_eq!(None, Sol2SolEvent::from_log(&format!("Program log: {}AAAA", EVENT_LOG_PREFIX)));
+        assert_eq!(None, Sol2SolEvent::from_log(&format!("Program log: {}not base64!", EVENT_LOG_PREFIX)));
+    }
+
+    #[test]
+    fn test_decode_events() {
+        let program_id = Pubkey::new_unique();
+        let other_program_id = Pubkey::new_unique();
+        let event_log = |event: &Sol2SolEvent| {
+            format!("Program log: {}{}", EVENT_LOG_PREFIX, STANDARD.encode(event.pack()))
+        };
+        let deleted = Sol2SolEvent::MessageDeleted {
+            sol_box: Pubkey::new_unique(),
+            message: Pubkey::new_unique(),
+        };
+        let closed = Sol2SolEvent::BoxClosed {
+            sol_box: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+        };
+        let forged = Sol2SolEvent::ReadReceiptsSet {
+            sol_box: Pubkey::new_unique(),
+            enabled: true,
+        };
+        let logs = vec![
+            format!("Program {} invoke [1]", other_program_id),
+            event_log(&forged),
+            format!("Program {} invoke [2]", program_id),
+            "Program log: [process]closing sol box".to_string(),
+            event_log(&deleted),
+            format!("Program {} invoke [3]", solana_program::system_program::id()),
+            format!("Program {} success", solana_program::system_program::id()),
+            event_log(&closed),
+            format!("Program {} consumed 2000 of 200000 compute units", program_id),
+            format!("Program {} success", program_id),
+            event_log(&forged),
+            format!("Program {} success", other_program_id),
+        ];
+        assert_eq!(vec![deleted, closed], decode_events(&program_id, &logs));
+    }
+}
@@ -0,0 +1,626 @@
+//! In-memory index of the program's mailboxes, built off-chain
+//!
+//! An `Indexer` is fed raw account snapshots, `Sol2SolEvent`s (usually
+//! through a transaction's log messages) and instruction data, in the order
+//! they happened, and answers per-owner queries about boxes, messages,
+//! threads and read state. It never talks to an RPC node, so it can be
+//! driven from recorded data.
+//!
+//! Snapshots are authoritative and replace whatever was known about an
+//! account. Events carry everything but message bodies; those come from
+//! snapshots or from the WriteMessage instruction data. Every update is
+//! idempotent, so an event and the instruction that caused it can both be
+//! applied.
+
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{
+    error::Sol2SolError,
+    event::{decode_events, Sol2SolEvent},
+    instruction::Sol2SolInstruction,
+    state::{
+        unpack_account_prefix,
+        AccessList,
+        Message,
+        SolBox,
+        ACCESS_LIST_TAG,
+        MESSAGE_STATUS_READ,
+        MESSAGE_TAG,
+        SOL_BOX_TAG,
+    },
+    thread::{group_threads, ThreadNode},
+};
+
+/// Decoded program accounts, kept up to date from snapshots, events and
+/// instructions
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Indexer {
+    program_id: Pubkey,
+    sol_boxes: BTreeMap<Pubkey, SolBox>,
+    messages: BTreeMap<Pubkey, Message>,
+    access_lists: BTreeMap<Pubkey, AccessList>,
+}
+impl Indexer {
+    /// An empty index of the accounts of `program_id`
+    pub fn new(program_id: Pubkey) -> Self {
+        Self {
+            program_id,
+            ..Self::default()
+        }
+    }
+
+    /// Records the current state of an account
+    ///
+    /// Accounts the program no longer owns, or whose data is empty or
+    /// zeroed, are dropped from the index.
+    pub fn apply_account(&mut self, pubkey: &Pubkey, owner: &Pubkey, data: &[u8]) -> Result<(), ProgramError> {
+        if *owner != self.program_id || data.iter().all(|byte| *byte == 0) {
+            self.forget(pubkey);
+            return Ok(());
+        }
+        let (tag, _) = unpack_account_prefix(data)?;
+        self.forget(pubkey);
+        match tag {
+            SOL_BOX_TAG => {
+                self.sol_boxes.insert(*pubkey, SolBox::unpack(data)?);
+            }
+            MESSAGE_TAG => {
+                self.messages.insert(*pubkey, Message::unpack(data)?);
+            }
+            ACCESS_LIST_TAG => {
+                self.access_lists.insert(*pubkey, AccessList::unpack(data)?);
+            }
+            _ => return Err(Sol2SolError::InvalidAccountData.into()),
+        }
+        Ok(())
+    }
+
+    /// Applies the events `program_id` logged in a transaction
+    pub fn apply_logs(&mut self, logs: &[String]) {
+        for event in decode_events(&self.program_id, logs) {
+            self.apply_event(&event);
+        }
+    }
+
+    /// Applies a single event
+    ///
+    /// Events about accounts the index has not seen yet are ignored, except
+    /// those creating them.
+    pub fn apply_event(&mut self, event: &Sol2SolEvent) {
+        match event {
+            Sol2SolEvent::BoxInitialized { sol_box, owner, num_spots, .. } => {
+                self.sol_boxes.entry(*sol_box).or_insert_with(|| new_sol_box(owner, sol_box, sol_box, *num_spots));
+            }
+            Sol2SolEvent::BoxAppended { sol_box, prev_box, owner, num_spots, .. } => {
+                let mut new_box = new_sol_box(owner, sol_box, prev_box, *num_spots);
+                if let Some(tail) = self.sol_boxes.get_mut(prev_box) {
+                    tail.next_box = *sol_box;
+                    new_box.access_list = tail.access_list;
+                    new_box.postage_lamports = tail.postage_lamports;
+                    new_box.postage_mint = tail.postage_mint;
+                    new_box.postage_token_amount = tail.postage_token_amount;
+                    new_box.encryption_key = tail.encryption_key;
+                    new_box.read_receipts = tail.read_receipts;
+                }
+                self.sol_boxes.entry(*sol_box).or_insert(new_box);
+            }
+            Sol2SolEvent::BoxClosed { sol_box, .. } => {
+                if let Some(closed) = self.sol_boxes.remove(sol_box) {
+                    let is_root = closed.prev_box == *sol_box;
+                    let is_tail = closed.next_box == *sol_box;
+                    if !is_root {
+                        if let Some(prev_box) = self.sol_boxes.get_mut(&closed.prev_box) {
+                            prev_box.next_box = if is_tail { closed.prev_box } else { closed.next_box };
+                        }
+                    }
+                    if !is_tail {
+                        if let Some(next_box) = self.sol_boxes.get_mut(&closed.next_box) {
+                            next_box.prev_box = closed.prev_box;
+                        }
+                    }
+                }
+            }
+            Sol2SolEvent::MessageWritten {
+                sol_box,
+                message,
+                recipient,
+                sender,
+                slot,
+                message_seq,
+                msg_size,
+                is_anonymous,
+                reply_to,
+                thread_root,
+            } => {
+                if let Some(sol_box) = self.sol_boxes.get_mut(sol_box) {
+                    fill_slot(sol_box, *slot, message);
+                    sol_box.message_seq = sol_box.message_seq.max(message_seq.saturating_add(1));
+                }
+                // Merge into what is known, keeping status and expiry set by
+                // instructions and a body that still fits
+                let known = self.messages.entry(*message).or_default();
+                if known.msg_data.len() != *msg_size as usize {
+                    known.msg_data = Vec::new();
+                }
+                known.recipient = *recipient;
+                known.sender = *sender;
+                known.msg_size = *msg_size;
+                known.slot = *slot;
+                known.is_anonymous = *is_anonymous;
+                known.reply_to = *reply_to;
+                known.thread_root = *thread_root;
+                known.is_initialized = true;
+            }
+            Sol2SolEvent::MessageDeleted { sol_box, message }
+            | Sol2SolEvent::MessagePurged { sol_box, message, .. } => {
+                self.remove_message(sol_box, message);
+            }
+            Sol2SolEvent::MessageStatusChanged { message, status, .. } => {
+                if let Some(message) = self.messages.get_mut(message) {
+                    message.status = *status;
+                }
+            }
+            Sol2SolEvent::MessageExpirySet { message, expiry, .. } => {
+                if let Some(message) = self.messages.get_mut(message) {
+                    message.expiry = *expiry;
+                }
+            }
+            Sol2SolEvent::ReplyDeleted { reply, .. } => {
+                self.messages.remove(reply);
+            }
+            Sol2SolEvent::OwnershipTransferPending { sol_box, pending_owner, .. } => {
+                if let Some(root) = self.sol_boxes.get_mut(sol_box) {
+                    root.pending_owner = *pending_owner;
+                }
+            }
+            Sol2SolEvent::BoxMoved { sol_box, new_sol_box, prev_box, .. } => {
+                if let Some(mut moved) = self.sol_boxes.remove(sol_box) {
+                    // The next box links back once it moves too
+                    moved.prev_box = *prev_box;
+                    if moved.next_box == *sol_box {
+                        moved.next_box = *new_sol_box;
+                    }
+                    self.sol_boxes.insert(*new_sol_box, moved);
+                    if prev_box != new_sol_box {
+                        self.update_sol_box(prev_box, |prev_box| prev_box.next_box = *new_sol_box);
+                    }
+                }
+            }
+            Sol2SolEvent::OwnershipTransferred { sol_box, new_owner, .. } => {
+                for key in self.chain(sol_box) {
+                    if let Some(sol_box) = self.sol_boxes.get_mut(&key) {
+                        sol_box.owner = *new_owner;
+                        sol_box.pending_owner = Pubkey::default();
+                    }
+                }
+            }
+            Sol2SolEvent::AccessListInitialized { access_list, sol_box, mode, capacity, .. } => {
+                self.access_lists.entry(*access_list).or_insert(AccessList {
+                    sol_box: *sol_box,
+                    mode: *mode,
+                    is_initialized: true,
+                    capacity: *capacity,
+                    entries: vec![],
+                });
+                self.update_sol_box(sol_box, |sol_box| sol_box.access_list = *access_list);
+            }
+            Sol2SolEvent::AccessListLinked { access_list, sol_box } => {
+                self.update_sol_box(sol_box, |sol_box| sol_box.access_list = *access_list);
+            }
+            Sol2SolEvent::AccessListEntryAdded { access_list, sender } => {
+                if let Some(access_list) = self.access_lists.get_mut(access_list) {
+                    // Already listed or full means the index is behind; a snapshot fixes it
+                    let _ = access_list.add_entry(sender);
+                }
+            }
+            Sol2SolEvent::AccessListEntryRemoved { access_list, sender } => {
+                if let Some(access_list) = self.access_lists.get_mut(access_list) {
+                    let _ = access_list.remove_entry(sender);
+                }
+            }
+            Sol2SolEvent::AccessListModeSet { access_list, mode } => {
+                if let Some(access_list) = self.access_lists.get_mut(access_list) {
+                    access_list.mode = *mode;
+                }
+            }
+            Sol2SolEvent::PostageSet { sol_box, lamports } => {
+                self.update_sol_box(sol_box, |sol_box| sol_box.postage_lamports = *lamports);
+            }
+            Sol2SolEvent::TokenPostageSet { sol_box, mint, amount } => {
+                self.update_sol_box(sol_box, |sol_box| {
+                    sol_box.postage_mint = *mint;
+                    sol_box.postage_token_amount = *amount;
+                });
+            }
+            Sol2SolEvent::EncryptionKeySet { sol_box, encryption_key } => {
+                self.update_sol_box(sol_box, |sol_box| sol_box.encryption_key = *encryption_key);
+            }
+            Sol2SolEvent::ReadReceiptsSet { sol_box, enabled } => {
+                self.update_sol_box(sol_box, |sol_box| sol_box.read_receipts = *enabled);
+            }
+            // The sender, size and body of a reply come from its instruction
+            Sol2SolEvent::ReplyWritten { reply, message, reply_token } => {
+                let sender = self.messages.get(message).map(|answered| answered.recipient).unwrap_or_default();
+                let known = self.messages.entry(*reply).or_default();
+                known.recipient = *reply_token;
+                if known.sender == Pubkey::default() {
+                    known.sender = sender;
+                }
+                known.is_initialized = true;
+            }
+            // Migrations do not change what the accounts hold
+            Sol2SolEvent::ReadReceipt { .. }
+            | Sol2SolEvent::AccountMigrated { .. } => {}
+        }
+    }
+
+    /// Applies an instruction of a successful transaction, `accounts` being
+    /// the keys it was passed. Apply the transaction's logs first, so the
+    /// messages it wrote are known.
+    ///
+    /// WriteMessage and WriteAnonymousMessage fill in message bodies, and
+    /// ReplyAnonymous the sender, size and body of a reply. Deletes,
+    /// status, expiry and box settings are applied directly. Instructions
+    /// whose outcome depends on account state, like where a message lands,
+    /// are left to events and snapshots.
+    pub fn apply_instruction(&mut self, data: &[u8], accounts: &[Pubkey]) -> Result<(), ProgramError> {
+        match Sol2SolInstruction::unpack(data)? {
+            Sol2SolInstruction::WriteMessage { message_pubkey, msg_data, .. }
+            | Sol2SolInstruction::WriteAnonymousMessage { message_pubkey, msg_data, .. } => {
+                if let Some(message) = self.messages.get_mut(&message_pubkey) {
+                    if message.msg_size as usize == msg_data.len() {
+                        message.msg_data = msg_data;
+                    }
+                }
+            }
+            Sol2SolInstruction::ReplyAnonymous { owner, msg_data, .. } => {
+                if let Some(reply) = accounts.get(3).and_then(|key| self.messages.get_mut(key)) {
+                    reply.sender = owner;
+                    reply.msg_size = msg_data.len() as u32;
+                    reply.msg_data = msg_data;
+                }
+            }
+            Sol2SolInstruction::DeleteMessage { message_id, sol_box_id, .. } => {
+                self.remove_message(&sol_box_id, &message_id);
+            }
+            Sol2SolInstruction::MarkMessages { set_flags, clear_flags, .. } => {
+                for key in accounts.iter().skip(2) {
+                    if let Some(message) = self.messages.get_mut(key) {
+                        message.status = (message.status | set_flags) & !clear_flags;
+                    }
+                }
+            }
+            Sol2SolInstruction::SetMessageExpiry { expiry, .. } => {
+                if let Some(message) = accounts.get(2).and_then(|key| self.messages.get_mut(key)) {
+                    message.expiry = expiry;
+                }
+            }
+            Sol2SolInstruction::SetPostage { lamports, .. } => {
+                self.update_sol_boxes(accounts.get(1..).unwrap_or_default(), |sol_box| sol_box.postage_lamports = lamports);
+            }
+            Sol2SolInstruction::SetTokenPostage { mint, amount, .. } => {
+                self.update_sol_boxes(accounts.get(1..).unwrap_or_default(), |sol_box| {
+                    sol_box.postage_mint = mint;
+                    sol_box.postage_token_amount = amount;
+                });
+            }
+            Sol2SolInstruction::SetEncryptionKey { encryption_key, .. } => {
+                self.update_sol_boxes(accounts.get(1..).unwrap_or_default(), |sol_box| sol_box.encryption_key = encryption_key);
+            }
+            Sol2SolInstruction::SetReadReceipts { enabled, .. } => {
+                self.update_sol_boxes(accounts.get(1..).unwrap_or_default(), |sol_box| sol_box.read_receipts = enabled);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Owners of at least one indexed box
+    pub fn owners(&self) -> Vec<Pubkey> {
+        self.sol_boxes
+            .values()
+            .map(|sol_box| sol_box.owner)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// An indexed box
+    pub fn sol_box(&self, pubkey: &Pubkey) -> Option<&SolBox> {
+        self.sol_boxes.get(pubkey)
+    }
+
+    /// An indexed message or reply
+    pub fn message(&self, pubkey: &Pubkey) -> Option<&Message> {
+        self.messages.get(pubkey)
+    }
+
+    /// An indexed access list
+    pub fn access_list(&self, pubkey: &Pubkey) -> Option<&AccessList> {
+        self.access_lists.get(pubkey)
+    }
+
+    /// The boxes of `owner`, chain by chain from root to tail
+    pub fn sol_boxes(&self, owner: &Pubkey) -> Vec<(Pubkey, &SolBox)> {
+        self.sol_boxes
+            .iter()
+            .filter(|(key, sol_box)| sol_box.owner == *owner && sol_box.prev_box == **key)
+            .flat_map(|(root, _)| self.chain(root))
+            .filter_map(|key| self.sol_boxes.get(&key).map(|sol_box| (key, sol_box)))
+            .collect()
+    }
+
+    /// The messages stored in the boxes of `owner`, in box and slot order
+    ///
+    /// A message's `msg_data` is empty until its body has been seen, which
+    /// `msg_data.len() != msg_size` tells apart from an empty body.
+    pub fn messages(&self, owner: &Pubkey) -> Vec<(Pubkey, &Message)> {
+        self.sol_boxes(owner)
+            .into_iter()
+            .flat_map(|(_, sol_box)| sol_box.stored_messages())
+            .filter_map(|key| self.messages.get(&key).map(|message| (key, message)))
+            .collect()
+    }
+
+    /// The messages of `owner` not yet marked read
+    pub fn unread(&self, owner: &Pubkey) -> Vec<(Pubkey, &Message)> {
+        self.messages(owner)
+            .into_iter()
+            .filter(|(_, message)| message.status & MESSAGE_STATUS_READ == 0)
+            .collect()
+    }
+
+    /// The conversations of `owner`: messages it received, then those it
+    /// sent that are still stored, arranged into threads
+    pub fn threads(&self, owner: &Pubkey) -> Vec<ThreadNode> {
+        let mut messages: Vec<(Pubkey, Message)> = self
+            .messages(owner)
+            .into_iter()
+            .map(|(key, message)| (key, message.clone()))
+            .collect();
+        for recipient in self.owners().iter().filter(|recipient| *recipient != owner) {
+            messages.extend(
+                self.messages(recipient)
+                    .into_iter()
+                    .filter(|(_, message)| message.sender == *owner && !message.is_anonymous)
+                    .map(|(key, message)| (key, message.clone())),
+            );
+        }
+        group_threads(messages)
+    }
+
+    /// Keys of the chain starting at `start`, following `next_box` through
+    /// indexed boxes
+    fn chain(&self, start: &Pubkey) -> Vec<Pubkey> {
+        let mut keys = vec![];
+        let mut key = *start;
+        while let Some(sol_box) = self.sol_boxes.get(&key) {
+            if keys.contains(&key) {
+                break;
+            }
+            keys.push(key);
+            key = sol_box.next_box;
+        }
+        keys
+    }
+
+    fn forget(&mut self, pubkey: &Pubkey) {
+        self.sol_boxes.remove(pubkey);
+        self.messages.remove(pubkey);
+        self.access_lists.remove(pubkey);
+    }
+
+    fn remove_message(&mut self, sol_box: &Pubkey, message: &Pubkey) {
+        self.messages.remove(message);
+        self.update_sol_box(sol_box, |sol_box| {
+            if let Some(slot) = sol_box.message_slots.iter().position(|key| *key == Some(*message)) {
+                // The slot was just found, so this cannot fail
+                let _ = sol_box.remove_message(slot as u32, message);
+            }
+        });
+    }
+
+    fn update_sol_box<F: FnOnce(&mut SolBox)>(&mut self, pubkey: &Pubkey, update: F) {
+        if let Some(sol_box) = self.sol_boxes.get_mut(pubkey) {
+            update(sol_box);
+        }
+    }
+
+    fn update_sol_boxes<F: Fn(&mut SolBox)>(&mut self, pubkeys: &[Pubkey], update: F) {
+        for pubkey in pubkeys {
+            self.update_sol_box(pubkey, &update);
+        }
+    }
+}
+
+fn new_sol_box(owner: &Pubkey, sol_box: &Pubkey, prev_box: &Pubkey, num_spots: u32) -> SolBox {
+    SolBox {
+        owner: *owner,
+        next_box: *sol_box,
+        prev_box: *prev_box,
+        num_spots,
+        is_initialized: true,
+        occupied: SolBox::get_empty_occupancy(num_spots),
+        message_slots: SolBox::get_empty_message_slots(num_spots),
+        ..SolBox::default()
+    }
+}
+
+/// Puts `message` in slot `slot`, the one the program reported using
+fn fill_slot(sol_box: &mut SolBox, slot: u32, message: &Pubkey) {
+    if slot >= sol_box.num_spots || sol_box.holds(slot, message) {
+        return;
+    }
+    if !sol_box.is_occupied(slot) {
+        sol_box.num_in_use += 1;
+    }
+    sol_box.occupied[slot as usize / 8] |= 1 << (slot % 8);
+    sol_box.message_slots[slot as usize] = Some(*message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{AccessListMode, MessageExpiry, MESSAGE_STATUS_READ, MIN_SOL_BOX_NUM_SPOTS};
+
+    #[test]
+    fn test_chain_events() {
+        let owner = Pubkey::new_unique();
+        let new_owner = Pubkey::new_unique();
+        let root = Pubkey::new_unique();
+        let middle = Pubkey::new_unique();
+        let tail = Pubkey::new_unique();
+        let access_list = Pubkey::new_unique();
+        let message = Pubkey::new_unique();
+        let mut indexer = Indexer::new(Pubkey::new_unique());
+
+        indexer.apply_event(&Sol2SolEvent::BoxInitialized { sol_box: root, owner, num_spots: MIN_SOL_BOX_NUM_SPOTS, index: 0 });
+        indexer.apply_event(&Sol2SolEvent::AccessListInitialized {
+            access_list,
+            sol_box: root,
+            owner,
+            mode: AccessListMode::Blocklist,
+            capacity: 1,
+        });
+        indexer.apply_event(&Sol2SolEvent::PostageSet { sol_box: root, lamports: 7 });
+        for (index, (sol_box, prev_box)) in (1..).zip([(middle, root), (tail, middle)]) {
+            indexer.apply_event(&Sol2SolEvent::BoxAppended {
+                sol_box,
+                prev_box,
+                owner,
+                num_spots: MIN_SOL_BOX_NUM_SPOTS,
+                index,
+            });
+        }
+        let keys = |indexer: &Indexer, owner: &Pubkey| -> Vec<Pubkey> {
+            indexer.sol_boxes(owner).into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(vec![root, middle, tail], keys(&indexer, &owner));
+        assert_eq!(access_list, indexer.sol_box(&tail).unwrap().access_list);
+        assert_eq!(7, indexer.sol_box(&tail).unwrap().postage_lamports);
+
+        indexer.apply_event(&Sol2SolEvent::AccessListEntryAdded { access_list, sender: new_owner });
+        assert!(!indexer.access_list(&access_list).unwrap().allows(&new_owner));
+
+        indexer.apply_event(&Sol2SolEvent::MessageWritten {
+            sol_box: middle,
+            message,
+            recipient: owner,
+            sender: new_owner,
+            slot: 2,
+            message_seq: 4,
+            msg_size: 3,
+            is_anonymous: false,
+            reply_to: Pubkey::default(),
+            thread_root: Pubkey::default(),
+        });
+        let middle_box = indexer.sol_box(&middle).unwrap();
+        assert!(middle_box.holds(2, &message));
+        assert_eq!((1, 5), (middle_box.num_in_use, middle_box.message_seq));
+        assert_eq!(vec![message], indexer.unread(&owner).into_iter().map(|(key, _)| key).collect::<Vec<_>>());
+
+        // The boxes move after the new owner's inbox before the transfer
+        let existing = Pubkey::new_unique();
+        indexer.apply_event(&Sol2SolEvent::BoxInitialized {
+            sol_box: existing,
+            owner: new_owner,
+            num_spots: MIN_SOL_BOX_NUM_SPOTS,
+            index: 0,
+        });
+        let moved = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let prev_boxes = [existing, moved[0], moved[1]];
+        for (index, ((sol_box, new_sol_box), prev_box)) in (1..).zip([root, middle, tail].iter().zip(moved).zip(prev_boxes)) {
+            indexer.apply_event(&Sol2SolEvent::BoxMoved { sol_box: *sol_box, new_sol_box, prev_box, index });
+        }
+        let [root, middle, tail] = moved;
+        indexer.apply_event(&Sol2SolEvent::OwnershipTransferred { sol_box: root, owner, new_owner });
+        assert!(indexer.sol_boxes(&owner).is_empty());
+        assert_eq!(vec![existing, root, middle, tail], keys(&indexer, &new_owner));
+        assert_eq!((existing, middle), (indexer.sol_box(&root).unwrap().prev_box, indexer.sol_box(&root).unwrap().next_box));
+        assert_eq!((middle, tail), (indexer.sol_box(&tail).unwrap().prev_box, indexer.sol_box(&tail).unwrap().next_box));
+        assert!(indexer.sol_box(&middle).unwrap().holds(2, &message));
+
+        indexer.apply_event(&Sol2SolEvent::MessageDeleted { sol_box: middle, message });
+        indexer.apply_event(&Sol2SolEvent::BoxClosed { sol_box: middle, owner: new_owner });
+        assert_eq!(vec![existing, root, tail], keys(&indexer, &new_owner));
+        assert!(indexer.message(&message).is_none());
+        indexer.apply_event(&Sol2SolEvent::BoxClosed { sol_box: tail, owner: new_owner });
+        assert_eq!(root, indexer.sol_box(&root).unwrap().next_box);
+
+        // Snapshots of closed accounts drop them
+        indexer.apply_account(&root, &solana_program::system_program::id(), &[]).unwrap();
+        indexer.apply_account(&existing, &solana_program::system_program::id(), &[]).unwrap();
+        assert!(indexer.owners().is_empty());
+    }
+
+    #[test]
+    fn test_message_and_reply_events() {
+        let owner = Pubkey::new_unique();
+        let sender = Pubkey::new_unique();
+        let sol_box = Pubkey::new_unique();
+        let message = Pubkey::new_unique();
+        let reply = Pubkey::new_unique();
+        let mut indexer = Indexer::new(Pubkey::new_unique());
+        indexer.apply_event(&Sol2SolEvent::BoxInitialized { sol_box, owner, num_spots: MIN_SOL_BOX_NUM_SPOTS, index: 0 });
+
+        let written = Sol2SolEvent::MessageWritten {
+            sol_box,
+            message,
+            recipient: owner,
+            sender,
+            slot: 0,
+            message_seq: 0,
+            msg_size: 3,
+            is_anonymous: true,
+            reply_to: Pubkey::default(),
+            thread_root: Pubkey::default(),
+        };
+        indexer.apply_event(&written);
+        indexer
+            .apply_instruction(
+                &Sol2SolInstruction::WriteAnonymousMessage {
+                    recipient: owner,
+                    message_pubkey: message,
+                    sol_box_pubkey: sol_box,
+                    reply_token: sender,
+                    msg_data: vec![1, 2, 3],
+                }
+                .pack(),
+                &[],
+            )
+            .unwrap();
+        indexer
+            .apply_instruction(
+                &Sol2SolInstruction::MarkMessages { owner, set_flags: MESSAGE_STATUS_READ, clear_flags: 0 }.pack(),
+                &[owner, sol_box, message],
+            )
+            .unwrap();
+        indexer
+            .apply_instruction(
+                &Sol2SolInstruction::SetMessageExpiry { authority: owner, expiry: MessageExpiry::Slot(9) }.pack(),
+                &[owner, sol_box, message],
+            )
+            .unwrap();
+
+        // Replaying the write keeps what instructions set since
+        indexer.apply_event(&written);
+        let known = indexer.message(&message).unwrap();
+        assert_eq!(vec![1, 2, 3], known.msg_data);
+        assert_eq!(MESSAGE_STATUS_READ, known.status);
+        assert_eq!(MessageExpiry::Slot(9), known.expiry);
+
+        indexer.apply_event(&Sol2SolEvent::ReplyWritten { reply, message, reply_token: sender });
+        indexer
+            .apply_instruction(
+                &Sol2SolInstruction::ReplyAnonymous { owner, message_pubkey: message, msg_data: vec![4, 5] }.pack(),
+                &[message, sol_box, owner, reply],
+            )
+            .unwrap();
+        let known = indexer.message(&reply).unwrap();
+        assert_eq!((sender, owner), (known.recipient, known.sender));
+        assert_eq!((2, vec![4, 5]), (known.msg_size, known.msg_data.clone()));
+
+        indexer.apply_event(&Sol2SolEvent::ReplyDeleted { reply, reply_token: sender });
+        assert!(indexer.message(&reply).is_none());
+    }
+}
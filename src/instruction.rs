@@ -3,39 +3,74 @@ use {
     arrayref::{
         array_ref,
     },
+    borsh::{BorshDeserialize, BorshSerialize},
     solana_program::{
         instruction::{AccountMeta, Instruction},
         program_error::{ProgramError},
         pubkey::Pubkey,
         // program_option::COption,
+        system_program,
         sysvar,
     },
     std::{
         result::Result,
         // convert::TryInto,
+        convert::TryFrom,
         mem::size_of,
         vec::Vec,
         // str,
     }
 };
-use crate::{error::Sol2SolError};
+use crate::{
+    error::Sol2SolError,
+    state::{AccessListMode, Message, MessageExpiry, MESSAGE_EXPIRY_LEN},
+};
+
+/// First tag of the Borsh encoding; a Borsh-encoded instruction is the
+/// Borsh serialization of `Sol2SolInstruction` with this added to its
+/// variant index
+pub const BORSH_INSTRUCTION_TAG_OFFSET: u8 = 0x80;
 
 #[repr(C)]
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
 /// Instructions supported by the email program.
+///
+/// Instructions come in two encodings, told apart by their tag. Tags below
+/// `BORSH_INSTRUCTION_TAG_OFFSET` use the hand-packed layout of `pack`,
+/// the rest the Borsh layout of `pack_borsh`. Message bodies are sized by
+/// their `msg_data`; the hand-packed layout writes that length before the
+/// body, while Borsh prefixes it to the `Vec` itself.
 pub enum Sol2SolInstruction {
     /// InitializeSolBox
+    ///
+    /// The new box is a chain of its own, linked to itself both ways. Boxes
+    /// are only ever linked to others by `AppendSolBox`.
     InitializeSolBox {
         /// owner of the sol box
         owner: Pubkey,
         /// How many messages will this box store
         num_spots: u32,
-        /// Address of next box for extra entries
+        /// Address of next box for extra entries, must be the new box itself
         next_box: Pubkey,
-        /// Address of prev box, prevBox == self if root
+        /// Address of prev box, must be the new box itself
         prev_box: Pubkey,
+        /// Which of the owner's boxes this is, used to derive its address
+        index: u32,
     },
     /// Send email from one wallet address to another
+    ///
+    /// Anyone may deliver into a sol box as long as they sign as `sender`
+    /// and pay for the message account. The box must be owned by `recipient`;
+    /// any box in the recipient's chain works, normally the tail.
+    ///
+    /// The program creates the message account at
+    /// `find_message_address(sol_box, sol_box.message_seq)`. If the box
+    /// charges postage, the sender also pays it to `recipient`, in lamports
+    /// and/or in tokens of the box's `postage_mint`.
+    ///
+    /// A reply names the message it answers, which must be passed as the
+    /// last account. The threading fields trail the body on the wire and are
+    /// left out when the message starts a new thread.
     WriteMessage {
         /// Which address is sending the email
         sender: Pubkey,
@@ -45,10 +80,12 @@ pub enum Sol2SolInstruction {
         message_pubkey: Pubkey,
         /// Which box to send it to
         sol_box_pubkey: Pubkey,
-        /// How large is the email
-        msg_size: u32,
-        /// What is the utf-8 data of the email
-        msg_string: String,
+        /// Body of the email, opaque bytes (an `envelope::Envelope` when sealed)
+        msg_data: Vec<u8>,
+        /// Message this one answers, `Pubkey::default()` for a new thread
+        reply_to: Pubkey,
+        /// `reply_thread_root` of the message answered, `Pubkey::default()` for a new thread
+        thread_root: Pubkey,
     },
     /// Delete message & reclaim lamports
     DeleteMessage {
@@ -58,24 +95,218 @@ pub enum Sol2SolInstruction {
         message_id: Pubkey,
         /// Where the message is located
         sol_box_id: Pubkey,
-    }
+    },
+    /// Link a new, empty sol box after the tail of the owner's chain
+    ///
+    /// The program creates the new box at the owner's derived address
+    /// `index + 1`, funded by the owner. It shares the tail's access list
+    /// and postage.
+    AppendSolBox {
+        /// Owner of the chain, must sign and pay
+        owner: Pubkey,
+        /// How many messages the new box will store
+        num_spots: u32,
+        /// Index the tail box was derived at
+        index: u32,
+    },
+    /// Close a sol box, unlink it from its chain & reclaim lamports
+    ///
+    /// The box must be empty unless `cascade` is set, in which case every
+    /// message it stores is deleted too. The root of a chain can only be
+    /// closed once it is the last box left. Its neighbors must be boxes of
+    /// the same owner that link back to it.
+    CloseSolBox {
+        /// Owner of the sol box, must sign
+        owner: Pubkey,
+        /// Delete the messages still stored in the box
+        cascade: bool,
+    },
+    /// Move every box of the owner's chain to a new owner
+    ///
+    /// With `two_step` the new owner is only recorded on the root box and
+    /// must sign AcceptSolBoxOwnership to take over. Proposing the current
+    /// owner cancels a pending transfer. The boxes move to the new owner's
+    /// derived addresses from `index` on, which must be unused, and their
+    /// old accounts are closed. A new owner who already has an inbox gets
+    /// the boxes linked after its tail. Messages stay where they are.
+    TransferSolBoxOwnership {
+        /// Current owner of the chain, must sign
+        owner: Pubkey,
+        /// Who the chain is moved to
+        new_owner: Pubkey,
+        /// Wait for the new owner to accept
+        two_step: bool,
+        /// New owner's index the root moves to: 0 for a new owner without
+        /// an inbox, else one past the index of its tail. Unused with `two_step`
+        index: u32,
+    },
+    /// Take over a chain whose root names the signer as pending owner,
+    /// moving its boxes as TransferSolBoxOwnership does
+    AcceptSolBoxOwnership {
+        /// Pending owner of the chain, must sign
+        new_owner: Pubkey,
+        /// Index the root moves to, as in TransferSolBoxOwnership
+        index: u32,
+    },
+    /// Create an access list and link it from the owner's sol boxes
+    ///
+    /// Once linked, WriteMessage rejects senders the list does not allow.
+    /// Boxes that already have a list are rejected.
+    InitializeAccessList {
+        /// Owner of the sol boxes, must sign and pays for the list
+        owner: Pubkey,
+        /// Whether listed senders are allowed or blocked
+        mode: AccessListMode,
+        /// How many senders the list can hold
+        capacity: u32,
+    },
+    /// Add a sender to a sol box's access list
+    AddAccessListEntry {
+        /// Owner of the sol box, must sign
+        owner: Pubkey,
+        /// Sender to list
+        sender: Pubkey,
+    },
+    /// Remove a sender from a sol box's access list
+    RemoveAccessListEntry {
+        /// Owner of the sol box, must sign
+        owner: Pubkey,
+        /// Sender to unlist
+        sender: Pubkey,
+    },
+    /// Switch a sol box's access list between allowlist and blocklist
+    SetAccessListMode {
+        /// Owner of the sol box, must sign
+        owner: Pubkey,
+        /// New interpretation of the listed senders
+        mode: AccessListMode,
+    },
+    /// Set the lamports senders pay the owner for each message
+    SetPostage {
+        /// Owner of the sol boxes, must sign
+        owner: Pubkey,
+        /// Postage per message, 0 to accept mail for free
+        lamports: u64,
+    },
+    /// Require senders to pay the owner tokens of `mint` for each message
+    SetTokenPostage {
+        /// Owner of the sol boxes, must sign
+        owner: Pubkey,
+        /// Mint postage is paid in, `Pubkey::default()` to stop charging
+        mint: Pubkey,
+        /// Tokens per message, in the mint's base units
+        amount: u64,
+    },
+    /// Require messages to the sol boxes to be sealed to an x25519 key
+    SetEncryptionKey {
+        /// Owner of the sol boxes, must sign
+        owner: Pubkey,
+        /// x25519 public key, all zeros to accept plaintext again
+        encryption_key: [u8; 32],
+    },
+    /// Send email without recording who sent it
+    ///
+    /// Takes the same accounts as `WriteMessage`, but the message stores
+    /// `reply_token` in place of the sender and the fee payer is not written
+    /// anywhere in program state. Boxes with an access list refuse
+    /// anonymous mail, since there is no sender to check.
+    WriteAnonymousMessage {
+        /// Which address is the email for
+        recipient: Pubkey,
+        /// Address of message
+        message_pubkey: Pubkey,
+        /// Which box to send it to
+        sol_box_pubkey: Pubkey,
+        /// One-time key the sender holds to receive a reply
+        reply_token: Pubkey,
+        /// Body of the email, opaque bytes (an `envelope::Envelope` when sealed)
+        msg_data: Vec<u8>,
+    },
+    /// Answer an anonymous message through its reply token
+    ///
+    /// The reply is created at `find_reply_address(reply_token)`, so each
+    /// token can be answered once, and only the token holder can close it.
+    /// The message must be stored in a sol box of the signer, which may
+    /// have taken the box over since it was sent.
+    ReplyAnonymous {
+        /// Owner of the sol box holding the message, must sign and pays for the reply
+        owner: Pubkey,
+        /// Anonymous message being answered
+        message_pubkey: Pubkey,
+        /// Body of the reply
+        msg_data: Vec<u8>,
+    },
+    /// Close a reply & reclaim lamports, signed by its reply token
+    DeleteReply {
+        /// Reply token the reply was addressed to
+        reply_token: Pubkey,
+    },
+    /// Update the `MESSAGE_STATUS_*` flags of messages in one sol box
+    ///
+    /// Flags in `set_flags` are turned on, then those in `clear_flags` off.
+    /// If the box has read receipts on, each message marked read for the
+    /// first time logs a receipt.
+    MarkMessages {
+        /// Owner of the sol box, must sign
+        owner: Pubkey,
+        /// Flags to turn on
+        set_flags: u8,
+        /// Flags to turn off
+        clear_flags: u8,
+    },
+    /// Turn read receipts on or off for the sol boxes
+    SetReadReceipts {
+        /// Owner of the sol boxes, must sign
+        owner: Pubkey,
+        /// Log a receipt when a message is first marked read
+        enabled: bool,
+    },
+    /// Set when a message may be purged, signed by its sender or the box owner
+    ///
+    /// A sender may expire a message they sent, letting anyone purge it
+    /// from the recipient's box: an unsend, whether or not it was read.
+    /// Anonymous messages cannot expire, since nothing records who funded
+    /// them to refund.
+    SetMessageExpiry {
+        /// Sender of the message or owner of its sol box
+        authority: Pubkey,
+        /// New expiry, `MessageExpiry::Never` to keep the message
+        expiry: MessageExpiry,
+    },
+    /// Remove an expired message from its sol box and close it
+    ///
+    /// Anyone may call this. The caller earns `PURGE_BOUNTY_LAMPORTS` and
+    /// the rest of the rent goes back to the message's sender, who paid it.
+    PurgeExpired,
+    /// Rewrite a sol box, message or access list in the current layout
+    ///
+    /// Anyone may call this. The payer tops up rent if the account grows,
+    /// and accounts already at `state::ACCOUNT_VERSION` are left alone.
+    /// Version 0 messages also need the sol box holding them, to recover
+    /// their slot and take its owner as their recipient.
+    MigrateAccount,
 }
 impl Sol2SolInstruction {
     /// Unpack the given bytes into an email
-    pub fn unpack<'a>(input: &'a [u8]) -> Result<Self, ProgramError> {
-        let (tag, rest) = input.split_at(1);
-        Ok(match tag[0] {
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.first().is_some_and(|tag| *tag >= BORSH_INSTRUCTION_TAG_OFFSET) {
+            return Self::unpack_borsh(input);
+        }
+        let (tag, rest) = input.split_first().ok_or(Sol2SolError::InvalidInstructionData)?;
+        let (instruction, rest) = match tag {
             0 => {
                 let (owner, rest) = Self::unpack_pubkey(rest)?;
                 let (num_spots, rest) = Self::unpack_size(rest)?;
                 let (next_box, rest) = Self::unpack_pubkey(rest)?;
-                let (prev_box, _rest) = Self::unpack_pubkey(rest)?;
-                Self::InitializeSolBox {
+                let (prev_box, rest) = Self::unpack_pubkey(rest)?;
+                let (index, rest) = Self::unpack_size(rest)?;
+                (Self::InitializeSolBox {
                     owner,
                     num_spots,
                     next_box,
-                    prev_box
-                }
+                    prev_box,
+                    index,
+                }, rest)
             }
             1 => {
                 let (sender, rest) = Self::unpack_pubkey(rest)?;
@@ -83,34 +314,204 @@ impl Sol2SolInstruction {
                 let (message_pubkey, rest) = Self::unpack_pubkey(rest)?;
                 let (sol_box_pubkey, rest) = Self::unpack_pubkey(rest)?;
                 let (msg_size, rest) = Self::unpack_size(rest)?;
-                let (msg_string, _rest) = Self::unpack_msg(rest, msg_size as usize)?;
-                Self::WriteMessage {
+                let (msg_data, rest) = Self::unpack_msg(rest, msg_size as usize)?;
+                let (reply_to, thread_root, rest) = if rest.is_empty() {
+                    (Pubkey::default(), Pubkey::default(), rest)
+                } else {
+                    let (reply_to, rest) = Self::unpack_pubkey(rest)?;
+                    let (thread_root, rest) = Self::unpack_pubkey(rest)?;
+                    (reply_to, thread_root, rest)
+                };
+                (Self::WriteMessage {
                     sender,
                     recipient,
                     message_pubkey,
                     sol_box_pubkey,
-                    msg_size,
-                    msg_string
-                }
+                    msg_data,
+                    reply_to,
+                    thread_root,
+                }, rest)
             }
             2 => {
                 let (owner, rest) = Self::unpack_pubkey(rest)?;
                 let (message_id, rest) = Self::unpack_pubkey(rest)?;
-                let (sol_box_id, _rest) = Self::unpack_pubkey(rest)?;
-                Self::DeleteMessage {
+                let (sol_box_id, rest) = Self::unpack_pubkey(rest)?;
+                (Self::DeleteMessage {
                     owner,
                     message_id,
                     sol_box_id,
-                }
+                }, rest)
+            }
+            3 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (num_spots, rest) = Self::unpack_size(rest)?;
+                let (index, rest) = Self::unpack_size(rest)?;
+                (Self::AppendSolBox {
+                    owner,
+                    num_spots,
+                    index,
+                }, rest)
+            }
+            4 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (cascade, rest) = Self::unpack_bool(rest)?;
+                (Self::CloseSolBox {
+                    owner,
+                    cascade,
+                }, rest)
+            }
+            5 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (new_owner, rest) = Self::unpack_pubkey(rest)?;
+                let (two_step, rest) = Self::unpack_bool(rest)?;
+                let (index, rest) = Self::unpack_size(rest)?;
+                (Self::TransferSolBoxOwnership {
+                    owner,
+                    new_owner,
+                    two_step,
+                    index,
+                }, rest)
+            }
+            6 => {
+                let (new_owner, rest) = Self::unpack_pubkey(rest)?;
+                let (index, rest) = Self::unpack_size(rest)?;
+                (Self::AcceptSolBoxOwnership {
+                    new_owner,
+                    index,
+                }, rest)
+            }
+            7 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (mode, rest) = Self::unpack_access_list_mode(rest)?;
+                let (capacity, rest) = Self::unpack_size(rest)?;
+                (Self::InitializeAccessList {
+                    owner,
+                    mode,
+                    capacity,
+                }, rest)
+            }
+            8 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (sender, rest) = Self::unpack_pubkey(rest)?;
+                (Self::AddAccessListEntry {
+                    owner,
+                    sender,
+                }, rest)
+            }
+            9 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (sender, rest) = Self::unpack_pubkey(rest)?;
+                (Self::RemoveAccessListEntry {
+                    owner,
+                    sender,
+                }, rest)
+            }
+            10 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (mode, rest) = Self::unpack_access_list_mode(rest)?;
+                (Self::SetAccessListMode {
+                    owner,
+                    mode,
+                }, rest)
+            }
+            11 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (lamports, rest) = Self::unpack_u64(rest)?;
+                (Self::SetPostage {
+                    owner,
+                    lamports,
+                }, rest)
+            }
+            12 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (mint, rest) = Self::unpack_pubkey(rest)?;
+                let (amount, rest) = Self::unpack_u64(rest)?;
+                (Self::SetTokenPostage {
+                    owner,
+                    mint,
+                    amount,
+                }, rest)
+            }
+            13 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (encryption_key, rest) = Self::unpack_pubkey(rest)?;
+                (Self::SetEncryptionKey {
+                    owner,
+                    encryption_key: encryption_key.to_bytes(),
+                }, rest)
+            }
+            14 => {
+                let (recipient, rest) = Self::unpack_pubkey(rest)?;
+                let (message_pubkey, rest) = Self::unpack_pubkey(rest)?;
+                let (sol_box_pubkey, rest) = Self::unpack_pubkey(rest)?;
+                let (reply_token, rest) = Self::unpack_pubkey(rest)?;
+                let (msg_size, rest) = Self::unpack_size(rest)?;
+                let (msg_data, rest) = Self::unpack_msg(rest, msg_size as usize)?;
+                (Self::WriteAnonymousMessage {
+                    recipient,
+                    message_pubkey,
+                    sol_box_pubkey,
+                    reply_token,
+                    msg_data,
+                }, rest)
+            }
+            15 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (message_pubkey, rest) = Self::unpack_pubkey(rest)?;
+                let (msg_size, rest) = Self::unpack_size(rest)?;
+                let (msg_data, rest) = Self::unpack_msg(rest, msg_size as usize)?;
+                (Self::ReplyAnonymous {
+                    owner,
+                    message_pubkey,
+                    msg_data,
+                }, rest)
+            }
+            16 => {
+                let (reply_token, rest) = Self::unpack_pubkey(rest)?;
+                (Self::DeleteReply {
+                    reply_token,
+                }, rest)
+            }
+            17 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (set_flags, rest) = Self::unpack_u8(rest)?;
+                let (clear_flags, rest) = Self::unpack_u8(rest)?;
+                (Self::MarkMessages {
+                    owner,
+                    set_flags,
+                    clear_flags,
+                }, rest)
+            }
+            18 => {
+                let (owner, rest) = Self::unpack_pubkey(rest)?;
+                let (enabled, rest) = Self::unpack_bool(rest)?;
+                (Self::SetReadReceipts {
+                    owner,
+                    enabled,
+                }, rest)
             }
+            19 => {
+                let (authority, rest) = Self::unpack_pubkey(rest)?;
+                let (expiry, rest) = Self::unpack_expiry(rest)?;
+                (Self::SetMessageExpiry {
+                    authority,
+                    expiry,
+                }, rest)
+            }
+            20 => (Self::PurgeExpired, rest),
+            21 => (Self::MigrateAccount, rest),
             _ => return Err(Sol2SolError::InvalidInstructionData.into()),
-        })
+        };
+        if !rest.is_empty() {
+            return Err(Sol2SolError::InvalidInstructionData.into());
+        }
+        Ok(instruction)
     }
 
     fn unpack_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
         if input.len() >= 32 {
             let (key, rest) = input.split_at(32);
-            let pk = Pubkey::new(key);
+            let pk = Pubkey::try_from(key).map_err(|_| Sol2SolError::InvalidInstructionData)?;
             Ok((pk, rest))
         } else {
             Err(Sol2SolError::InvalidInstructionData.into())
@@ -128,22 +529,48 @@ impl Sol2SolInstruction {
         }
     }
     
-    fn unpack_msg<'a>(input: &'a [u8], msg_size: usize) -> Result<(String, &'a [u8]), ProgramError> {
+    fn unpack_u64(input: &[u8]) -> Result<(u64, &[u8]), ProgramError> {
+        if input.len() >= 8 {
+            let (bytes, rest) = input.split_at(8);
+            let value = u64::from_le_bytes(*array_ref![bytes, 0, 8]);
+            Ok((value, rest))
+        } else {
+            Err(Sol2SolError::InvalidInstructionData.into())
+        }
+    }
+
+    fn unpack_expiry(input: &[u8]) -> Result<(MessageExpiry, &[u8]), ProgramError> {
+        if input.len() < MESSAGE_EXPIRY_LEN {
+            return Err(Sol2SolError::InvalidInstructionData.into());
+        }
+        let (expiry, rest) = input.split_at(MESSAGE_EXPIRY_LEN);
+        let expiry = MessageExpiry::unpack(array_ref![expiry, 0, MESSAGE_EXPIRY_LEN])
+            .map_err(|_| Sol2SolError::InvalidInstructionData)?;
+        Ok((expiry, rest))
+    }
+
+    fn unpack_u8(input: &[u8]) -> Result<(u8, &[u8]), ProgramError> {
+        let (value, rest) = input.split_first().ok_or(Sol2SolError::InvalidInstructionData)?;
+        Ok((*value, rest))
+    }
+
+    fn unpack_bool(input: &[u8]) -> Result<(bool, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((0, rest)) => Ok((false, rest)),
+            Some((1, rest)) => Ok((true, rest)),
+            _ => Err(Sol2SolError::InvalidInstructionData.into()),
+        }
+    }
+
+    fn unpack_access_list_mode(input: &[u8]) -> Result<(AccessListMode, &[u8]), ProgramError> {
+        let (mode, rest) = input.split_first().ok_or(Sol2SolError::InvalidInstructionData)?;
+        Ok((AccessListMode::try_from(*mode)?, rest))
+    }
+
+    fn unpack_msg(input: &[u8], msg_size: usize) -> Result<(Vec<u8>, &[u8]), ProgramError> {
         if input.len() >= msg_size {
-            let mut utf8_bytes = Vec::new();
-            for i in 0..msg_size {
-                utf8_bytes.push(input[i]);
-            }
-            let msg_decoded = String::from_utf8(utf8_bytes);
-            match msg_decoded {
-                Ok(msg_data) => {
-                    let (_, rest) = input.split_at(msg_size);
-                    Ok((msg_data, rest))
-                },
-                Err(_) => {
-                    Err(Sol2SolError::InvalidAccountData.into())
-                }
-            }
+            let (msg_data, rest) = input.split_at(msg_size);
+            Ok((msg_data.to_vec(), rest))
         } else {
             Err(Sol2SolError::InvalidInstructionData.into())
         }
@@ -158,28 +585,35 @@ impl Sol2SolInstruction {
                 num_spots,
                 next_box,
                 prev_box,
+                index,
             } => {
                 buf.push(0);
                 buf.extend_from_slice(&owner.to_bytes());
                 buf.extend_from_slice(&num_spots.to_le_bytes());
                 buf.extend_from_slice(&next_box.to_bytes());
                 buf.extend_from_slice(&prev_box.to_bytes());
+                buf.extend_from_slice(&index.to_le_bytes());
             }
             Self::WriteMessage {
                 sender,
                 recipient,
                 message_pubkey,
                 sol_box_pubkey,
-                msg_size,
-                msg_string,
+                msg_data,
+                reply_to,
+                thread_root,
             } => {
                 buf.push(1);
                 buf.extend_from_slice(&sender.to_bytes());
                 buf.extend_from_slice(&recipient.to_bytes());
                 buf.extend_from_slice(&message_pubkey.to_bytes());
                 buf.extend_from_slice(&sol_box_pubkey.to_bytes());
-                buf.extend_from_slice(&msg_size.to_le_bytes());
-                Self::pack_msg(&msg_string, &mut buf);
+                buf.extend_from_slice(&(msg_data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(msg_data);
+                if *reply_to != Pubkey::default() || *thread_root != Pubkey::default() {
+                    buf.extend_from_slice(&reply_to.to_bytes());
+                    buf.extend_from_slice(&thread_root.to_bytes());
+                }
             }
             Self::DeleteMessage {
                 owner,
@@ -191,36 +625,229 @@ impl Sol2SolInstruction {
                 buf.extend_from_slice(&message_id.to_bytes());
                 buf.extend_from_slice(&sol_box_id.to_bytes());
             }
+            Self::AppendSolBox {
+                owner,
+                num_spots,
+                index,
+            } => {
+                buf.push(3);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(&num_spots.to_le_bytes());
+                buf.extend_from_slice(&index.to_le_bytes());
+            }
+            Self::CloseSolBox {
+                owner,
+                cascade,
+            } => {
+                buf.push(4);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.push(*cascade as u8);
+            }
+            Self::TransferSolBoxOwnership {
+                owner,
+                new_owner,
+                two_step,
+                index,
+            } => {
+                buf.push(5);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(&new_owner.to_bytes());
+                buf.push(*two_step as u8);
+                buf.extend_from_slice(&index.to_le_bytes());
+            }
+            Self::AcceptSolBoxOwnership {
+                new_owner,
+                index,
+            } => {
+                buf.push(6);
+                buf.extend_from_slice(&new_owner.to_bytes());
+                buf.extend_from_slice(&index.to_le_bytes());
+            }
+            Self::InitializeAccessList {
+                owner,
+                mode,
+                capacity,
+            } => {
+                buf.push(7);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.push(*mode as u8);
+                buf.extend_from_slice(&capacity.to_le_bytes());
+            }
+            Self::AddAccessListEntry {
+                owner,
+                sender,
+            } => {
+                buf.push(8);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(&sender.to_bytes());
+            }
+            Self::RemoveAccessListEntry {
+                owner,
+                sender,
+            } => {
+                buf.push(9);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(&sender.to_bytes());
+            }
+            Self::SetAccessListMode {
+                owner,
+                mode,
+            } => {
+                buf.push(10);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.push(*mode as u8);
+            }
+            Self::SetPostage {
+                owner,
+                lamports,
+            } => {
+                buf.push(11);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(&lamports.to_le_bytes());
+            }
+            Self::SetTokenPostage {
+                owner,
+                mint,
+                amount,
+            } => {
+                buf.push(12);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(&mint.to_bytes());
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::SetEncryptionKey {
+                owner,
+                encryption_key,
+            } => {
+                buf.push(13);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(encryption_key);
+            }
+            Self::WriteAnonymousMessage {
+                recipient,
+                message_pubkey,
+                sol_box_pubkey,
+                reply_token,
+                msg_data,
+            } => {
+                buf.push(14);
+                buf.extend_from_slice(&recipient.to_bytes());
+                buf.extend_from_slice(&message_pubkey.to_bytes());
+                buf.extend_from_slice(&sol_box_pubkey.to_bytes());
+                buf.extend_from_slice(&reply_token.to_bytes());
+                buf.extend_from_slice(&(msg_data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(msg_data);
+            }
+            Self::ReplyAnonymous {
+                owner,
+                message_pubkey,
+                msg_data,
+            } => {
+                buf.push(15);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.extend_from_slice(&message_pubkey.to_bytes());
+                buf.extend_from_slice(&(msg_data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(msg_data);
+            }
+            Self::DeleteReply {
+                reply_token,
+            } => {
+                buf.push(16);
+                buf.extend_from_slice(&reply_token.to_bytes());
+            }
+            Self::MarkMessages {
+                owner,
+                set_flags,
+                clear_flags,
+            } => {
+                buf.push(17);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.push(*set_flags);
+                buf.push(*clear_flags);
+            }
+            Self::SetReadReceipts {
+                owner,
+                enabled,
+            } => {
+                buf.push(18);
+                buf.extend_from_slice(&owner.to_bytes());
+                buf.push(*enabled as u8);
+            }
+            Self::SetMessageExpiry {
+                authority,
+                expiry,
+            } => {
+                buf.push(19);
+                buf.extend_from_slice(&authority.to_bytes());
+                buf.extend_from_slice(&expiry.pack());
+            }
+            Self::PurgeExpired => {
+                buf.push(20);
+            }
+            Self::MigrateAccount => {
+                buf.push(21);
+            }
         };
         buf
     }
 
-    fn pack_msg(msg: &String, buf: &mut Vec<u8>) {
-        let msg_str: &[u8] = msg.as_bytes();
-        // println!("\tMsg str is: {} and has size {}", msg, msg_str.len());
-        buf.extend_from_slice(msg_str);
+    /// Unpack an instruction in the Borsh encoding, rejecting trailing bytes
+    pub fn unpack_borsh(input: &[u8]) -> Result<Self, ProgramError> {
+        let mut data = input.to_vec();
+        match data.first_mut() {
+            Some(tag) if *tag >= BORSH_INSTRUCTION_TAG_OFFSET => *tag -= BORSH_INSTRUCTION_TAG_OFFSET,
+            _ => return Err(Sol2SolError::InvalidInstructionData.into()),
+        }
+        Self::try_from_slice(&data).map_err(|_| Sol2SolError::InvalidInstructionData.into())
+    }
+
+    /// Pack the instruction in the Borsh encoding
+    pub fn pack_borsh(&self) -> Vec<u8> {
+        let mut buf = self.try_to_vec().unwrap();
+        buf[0] += BORSH_INSTRUCTION_TAG_OFFSET;
+        buf
     }
 
 }
 
+/// Seed prefix for program-derived sol box addresses
+pub const SOL_BOX_SEED: &[u8] = b"solbox";
+
+/// Derives the address of `owner`'s sol box number `index`
+///
+/// Index 0 is the root of the owner's inbox, so anyone can find it from
+/// the owner's wallet alone.
+pub fn find_sol_box_address(program_id: &Pubkey, owner: &Pubkey, index: u32) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[SOL_BOX_SEED, owner.as_ref(), &index.to_le_bytes()],
+        program_id,
+    )
+}
+
 /// Creates an InitializeSolBox instruction
+///
+/// The program creates the sol box at `find_sol_box_address(payer, index)`
+/// and funds it rent-exempt from the payer.
 pub fn init_sol_box(
     program_id: &Pubkey,
     payer_pubkey: &Pubkey,
-    sol_box_pubkey: &Pubkey,
+    index: u32,
+    num_spots: u32,
 ) -> Result<Instruction, ProgramError> {
+    let (sol_box_pubkey, _) = find_sol_box_address(program_id, payer_pubkey, index);
     let data: Vec<u8> = Sol2SolInstruction::InitializeSolBox {
         owner: *payer_pubkey,
-        num_spots: 20 as u32,
-        next_box: *sol_box_pubkey,
-        prev_box: *sol_box_pubkey
+        num_spots,
+        next_box: sol_box_pubkey,
+        prev_box: sol_box_pubkey,
+        index,
     }.pack();
 
     let accounts = vec![
-        // AccountMeta::new(*program_id, false),
-        AccountMeta::new(*sol_box_pubkey, true),
+        AccountMeta::new(sol_box_pubkey, false),
         AccountMeta::new(*payer_pubkey, true),
         AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
     ];
 
     Ok(Instruction {
@@ -230,31 +857,115 @@ pub fn init_sol_box(
     })
 }
 
+/// Seed prefix for program-derived message addresses
+pub const MESSAGE_SEED: &[u8] = b"message";
+
+/// Derives the address of message number `message_seq` delivered to `sol_box`
+pub fn find_message_address(program_id: &Pubkey, sol_box: &Pubkey, message_seq: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[MESSAGE_SEED, sol_box.as_ref(), &message_seq.to_le_bytes()],
+        program_id,
+    )
+}
+
 /// Creates an WriteMessage instruction
+///
+/// `message_seq` must be the box's current `message_seq`; the payer funds
+/// the message account the program creates, plus any postage the box
+/// charges. `access_list_pubkey` must be the box's `access_list` when it
+/// has one. `token_postage_accounts` are the sender's and recipient's token
+/// accounts for the box's `postage_mint` when it charges token postage.
+#[allow(clippy::too_many_arguments)]
 pub fn write_message(
     program_id: &Pubkey,
     payer_pubkey: &Pubkey,
     recipient_pubkey: &Pubkey,
     sol_box_pubkey: &Pubkey,
-    message_pubkey: &Pubkey,
-    msg_size: u32,
-    msg_string: &String,
+    message_seq: u64,
+    msg_data: &[u8],
+    access_list_pubkey: Option<&Pubkey>,
+    token_postage_accounts: Option<(&Pubkey, &Pubkey)>,
+) -> Result<Instruction, ProgramError> {
+    write_message_instruction(
+        program_id,
+        payer_pubkey,
+        recipient_pubkey,
+        sol_box_pubkey,
+        message_seq,
+        msg_data,
+        None,
+        access_list_pubkey,
+        token_postage_accounts,
+    )
+}
+
+/// Creates a WriteMessage instruction answering `parent`, the message stored at `parent_pubkey`
+///
+/// Takes the same accounts as `write_message`; the reply joins the
+/// parent's thread.
+#[allow(clippy::too_many_arguments)]
+pub fn write_reply(
+    program_id: &Pubkey,
+    payer_pubkey: &Pubkey,
+    recipient_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    message_seq: u64,
+    msg_data: &[u8],
+    parent_pubkey: &Pubkey,
+    parent: &Message,
+    access_list_pubkey: Option<&Pubkey>,
+    token_postage_accounts: Option<(&Pubkey, &Pubkey)>,
+) -> Result<Instruction, ProgramError> {
+    write_message_instruction(
+        program_id,
+        payer_pubkey,
+        recipient_pubkey,
+        sol_box_pubkey,
+        message_seq,
+        msg_data,
+        Some((parent_pubkey, &parent.reply_thread_root(parent_pubkey))),
+        access_list_pubkey,
+        token_postage_accounts,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_message_instruction(
+    program_id: &Pubkey,
+    payer_pubkey: &Pubkey,
+    recipient_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    message_seq: u64,
+    msg_data: &[u8],
+    thread: Option<(&Pubkey, &Pubkey)>,
+    access_list_pubkey: Option<&Pubkey>,
+    token_postage_accounts: Option<(&Pubkey, &Pubkey)>,
 ) -> Result<Instruction, ProgramError> {
+    let (message_pubkey, _) = find_message_address(program_id, sol_box_pubkey, message_seq);
+    let (reply_to, thread_root) = thread
+        .map(|(reply_to, thread_root)| (*reply_to, *thread_root))
+        .unwrap_or_default();
     let data: Vec<u8> = Sol2SolInstruction::WriteMessage {
         sender: *payer_pubkey,
         recipient: *recipient_pubkey,
-        message_pubkey: *message_pubkey,
+        message_pubkey,
         sol_box_pubkey: *sol_box_pubkey,
-        msg_size,
-        msg_string: (*msg_string.to_owned()).to_string(),
+        msg_data: msg_data.to_vec(),
+        reply_to,
+        thread_root,
     }.pack();
 
-    let accounts = vec![
-        AccountMeta::new(*message_pubkey, false),
-        AccountMeta::new(*sol_box_pubkey, true),
-        AccountMeta::new(*payer_pubkey, true),
-        AccountMeta::new_readonly(sysvar::rent::id(), false),
-    ];
+    let mut accounts = write_message_accounts(
+        &message_pubkey,
+        sol_box_pubkey,
+        payer_pubkey,
+        recipient_pubkey,
+        access_list_pubkey,
+        token_postage_accounts,
+    );
+    if thread.is_some() {
+        accounts.push(AccountMeta::new_readonly(reply_to, false));
+    }
 
     Ok(Instruction {
         program_id: *program_id,
@@ -263,18 +974,694 @@ pub fn write_message(
     })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Accounts shared by WriteMessage and WriteAnonymousMessage
+fn write_message_accounts(
+    message_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    payer_pubkey: &Pubkey,
+    recipient_pubkey: &Pubkey,
+    access_list_pubkey: Option<&Pubkey>,
+    token_postage_accounts: Option<(&Pubkey, &Pubkey)>,
+) -> Vec<AccountMeta> {
+    let mut accounts = vec![
+        AccountMeta::new(*message_pubkey, false),
+        AccountMeta::new(*sol_box_pubkey, false),
+        AccountMeta::new(*payer_pubkey, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new(*recipient_pubkey, false),
+    ];
+    if let Some(access_list_pubkey) = access_list_pubkey {
+        accounts.push(AccountMeta::new_readonly(*access_list_pubkey, false));
+    }
+    if let Some((sender_token_pubkey, recipient_token_pubkey)) = token_postage_accounts {
+        accounts.push(AccountMeta::new(*sender_token_pubkey, false));
+        accounts.push(AccountMeta::new(*recipient_token_pubkey, false));
+        accounts.push(AccountMeta::new_readonly(spl_token::id(), false));
+    }
+    accounts
+}
 
-    #[test]
-    fn test_instruction_parsing() {
-        let msg_string: String = "hello world!".to_owned();     // 12
-        let init_msg_string = msg_string.clone();
-        let msg_size: u32 = msg_string.len() as u32;            // 4
-        let sender = Pubkey::new_unique();                      // 32
-        let recipient = Pubkey::new_unique();                   // 32
-        let message_pubkey = Pubkey::new_unique();                   // 32
+/// Creates a WriteAnonymousMessage instruction
+///
+/// `payer_pubkey` pays rent and postage but is not recorded; use a wallet
+/// that can't be tied to the sender. `reply_token` should be a fresh
+/// keypair whose secret the sender keeps to collect a reply.
+#[allow(clippy::too_many_arguments)]
+pub fn write_anonymous_message(
+    program_id: &Pubkey,
+    payer_pubkey: &Pubkey,
+    recipient_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    message_seq: u64,
+    reply_token: &Pubkey,
+    msg_data: &[u8],
+    token_postage_accounts: Option<(&Pubkey, &Pubkey)>,
+) -> Result<Instruction, ProgramError> {
+    let (message_pubkey, _) = find_message_address(program_id, sol_box_pubkey, message_seq);
+    let data: Vec<u8> = Sol2SolInstruction::WriteAnonymousMessage {
+        recipient: *recipient_pubkey,
+        message_pubkey,
+        sol_box_pubkey: *sol_box_pubkey,
+        reply_token: *reply_token,
+        msg_data: msg_data.to_vec(),
+    }.pack();
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts: write_message_accounts(
+            &message_pubkey,
+            sol_box_pubkey,
+            payer_pubkey,
+            recipient_pubkey,
+            None,
+            token_postage_accounts,
+        ),
+        data
+    })
+}
+
+/// Seed prefix for program-derived reply addresses
+pub const REPLY_SEED: &[u8] = b"reply";
+
+/// Derives the address of the reply sent to `reply_token`
+pub fn find_reply_address(program_id: &Pubkey, reply_token: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[REPLY_SEED, reply_token.as_ref()], program_id)
+}
+
+/// Creates a ReplyAnonymous instruction answering `message_pubkey`, stored in `sol_box_pubkey`
+pub fn reply_anonymous(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    message_pubkey: &Pubkey,
+    reply_token: &Pubkey,
+    msg_data: &[u8],
+) -> Result<Instruction, ProgramError> {
+    let (reply_pubkey, _) = find_reply_address(program_id, reply_token);
+    let data: Vec<u8> = Sol2SolInstruction::ReplyAnonymous {
+        owner: *owner_pubkey,
+        message_pubkey: *message_pubkey,
+        msg_data: msg_data.to_vec(),
+    }.pack();
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*message_pubkey, false),
+        AccountMeta::new_readonly(*sol_box_pubkey, false),
+        AccountMeta::new(*owner_pubkey, true),
+        AccountMeta::new(reply_pubkey, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a DeleteReply instruction, returning the reply's lamports to `destination_pubkey`
+pub fn delete_reply(
+    program_id: &Pubkey,
+    reply_token: &Pubkey,
+    destination_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (reply_pubkey, _) = find_reply_address(program_id, reply_token);
+    let data: Vec<u8> = Sol2SolInstruction::DeleteReply {
+        reply_token: *reply_token,
+    }.pack();
+
+    let accounts = vec![
+        AccountMeta::new(reply_pubkey, false),
+        AccountMeta::new_readonly(*reply_token, true),
+        AccountMeta::new(*destination_pubkey, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a DeleteMessage instruction
+pub fn delete_message(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    message_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::DeleteMessage {
+        owner: *owner_pubkey,
+        message_id: *message_pubkey,
+        sol_box_id: *sol_box_pubkey,
+    }.pack();
+
+    let accounts = vec![
+        AccountMeta::new(*message_pubkey, false),
+        AccountMeta::new(*sol_box_pubkey, false),
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new(*destination_pubkey, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates an AppendSolBox instruction
+///
+/// `index` is the index of the owner's tail box. The program creates the
+/// new box at `find_sol_box_address(owner, index + 1)` and funds it
+/// rent-exempt from the owner.
+pub fn append_sol_box(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    index: u32,
+    num_spots: u32,
+) -> Result<Instruction, ProgramError> {
+    let (tail_sol_box_pubkey, _) = find_sol_box_address(program_id, owner_pubkey, index);
+    let new_index = index.checked_add(1).ok_or(Sol2SolError::InvalidInstructionData)?;
+    let (new_sol_box_pubkey, _) = find_sol_box_address(program_id, owner_pubkey, new_index);
+    let data: Vec<u8> = Sol2SolInstruction::AppendSolBox {
+        owner: *owner_pubkey,
+        num_spots,
+        index,
+    }.pack();
+
+    let accounts = vec![
+        AccountMeta::new(new_sol_box_pubkey, false),
+        AccountMeta::new(tail_sol_box_pubkey, false),
+        AccountMeta::new(*owner_pubkey, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a CloseSolBox instruction
+///
+/// `prev_box` and `next_box` are the box's neighbors as stored in it (the
+/// box itself at either end of the chain). Passing `message_pubkeys`
+/// cascades the close to those messages, which must be every message the
+/// box stores.
+#[allow(clippy::too_many_arguments)]
+pub fn close_sol_box(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    prev_box_pubkey: &Pubkey,
+    next_box_pubkey: &Pubkey,
+    destination_pubkey: &Pubkey,
+    message_pubkeys: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::CloseSolBox {
+        owner: *owner_pubkey,
+        cascade: !message_pubkeys.is_empty(),
+    }.pack();
+
+    let mut accounts = vec![
+        AccountMeta::new(*sol_box_pubkey, false),
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new(*destination_pubkey, false),
+        AccountMeta::new(*prev_box_pubkey, false),
+        AccountMeta::new(*next_box_pubkey, false),
+    ];
+    for message_pubkey in message_pubkeys {
+        accounts.push(AccountMeta::new(*message_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a TransferSolBoxOwnership instruction
+///
+/// `chain` lists every box of the chain from root to tail. A two-step
+/// transfer only touches the root, otherwise the boxes move to the new
+/// owner's derived addresses. `new_owner_tail` is the tail of the new
+/// owner's inbox and its index, if it has one; the boxes then follow it,
+/// else they move to `find_sol_box_address(new_owner, i)`. The owner funds
+/// the new accounts and gets back the lamports of the old ones.
+pub fn transfer_sol_box_ownership(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    new_owner_pubkey: &Pubkey,
+    chain: &[Pubkey],
+    new_owner_tail: Option<(&Pubkey, u32)>,
+    two_step: bool,
+) -> Result<Instruction, ProgramError> {
+    let index = move_chain_index(new_owner_tail)?;
+    let data: Vec<u8> = Sol2SolInstruction::TransferSolBoxOwnership {
+        owner: *owner_pubkey,
+        new_owner: *new_owner_pubkey,
+        two_step,
+        index,
+    }.pack();
+
+    let accounts = if two_step {
+        vec![
+            AccountMeta::new_readonly(*owner_pubkey, true),
+            AccountMeta::new(*chain.first().ok_or(ProgramError::NotEnoughAccountKeys)?, false),
+        ]
+    } else {
+        move_chain_accounts(program_id, owner_pubkey, new_owner_pubkey, chain, new_owner_tail, index)
+    };
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates an AcceptSolBoxOwnership instruction
+///
+/// `chain` lists every box of the chain from root to tail, and
+/// `new_owner_tail` the tail of the new owner's inbox and its index, as in
+/// `transfer_sol_box_ownership`. The new owner funds the new accounts and
+/// gets back the lamports of the old ones.
+pub fn accept_sol_box_ownership(
+    program_id: &Pubkey,
+    new_owner_pubkey: &Pubkey,
+    chain: &[Pubkey],
+    new_owner_tail: Option<(&Pubkey, u32)>,
+) -> Result<Instruction, ProgramError> {
+    let index = move_chain_index(new_owner_tail)?;
+    let data: Vec<u8> = Sol2SolInstruction::AcceptSolBoxOwnership {
+        new_owner: *new_owner_pubkey,
+        index,
+    }.pack();
+
+    let accounts = move_chain_accounts(program_id, new_owner_pubkey, new_owner_pubkey, chain, new_owner_tail, index);
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Index a moved chain's root takes: right after the new owner's tail
+fn move_chain_index(new_owner_tail: Option<(&Pubkey, u32)>) -> Result<u32, ProgramError> {
+    new_owner_tail.map_or(Ok(0), |(_, tail_index)| {
+        tail_index.checked_add(1).ok_or_else(|| Sol2SolError::IncorrectSolBox.into())
+    })
+}
+
+/// Accounts moving `chain` to `new_owner` from `index` on, signed and paid
+/// for by `signer`
+fn move_chain_accounts(
+    program_id: &Pubkey,
+    signer_pubkey: &Pubkey,
+    new_owner_pubkey: &Pubkey,
+    chain: &[Pubkey],
+    new_owner_tail: Option<(&Pubkey, u32)>,
+    index: u32,
+) -> Vec<AccountMeta> {
+    let mut accounts = vec![
+        AccountMeta::new(*signer_pubkey, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some((tail_pubkey, _)) = new_owner_tail {
+        accounts.push(AccountMeta::new(*tail_pubkey, false));
+    }
+    for sol_box_pubkey in chain {
+        accounts.push(AccountMeta::new(*sol_box_pubkey, false));
+    }
+    for offset in 0..chain.len() as u32 {
+        let (new_sol_box_pubkey, _) = find_sol_box_address(program_id, new_owner_pubkey, index.saturating_add(offset));
+        accounts.push(AccountMeta::new(new_sol_box_pubkey, false));
+    }
+    accounts
+}
+
+/// Seed prefix for program-derived access list addresses
+pub const ACCESS_LIST_SEED: &[u8] = b"access";
+
+/// Derives the address of the access list created for `sol_box`
+pub fn find_access_list_address(program_id: &Pubkey, sol_box: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[ACCESS_LIST_SEED, sol_box.as_ref()], program_id)
+}
+
+/// Creates an InitializeAccessList instruction
+///
+/// The list is created at `find_access_list_address(sol_boxes[0])` and
+/// linked from every box in `sol_boxes`, normally the owner's whole chain.
+pub fn init_access_list(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_boxes: &[Pubkey],
+    mode: AccessListMode,
+    capacity: u32,
+) -> Result<Instruction, ProgramError> {
+    let first_box = sol_boxes.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+    let (access_list_pubkey, _) = find_access_list_address(program_id, first_box);
+    let data: Vec<u8> = Sol2SolInstruction::InitializeAccessList {
+        owner: *owner_pubkey,
+        mode,
+        capacity,
+    }.pack();
+
+    let mut accounts = vec![
+        AccountMeta::new(access_list_pubkey, false),
+        AccountMeta::new(*owner_pubkey, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for sol_box_pubkey in sol_boxes {
+        accounts.push(AccountMeta::new(*sol_box_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+fn access_list_instruction(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    access_list_pubkey: &Pubkey,
+    instruction: Sol2SolInstruction,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new_readonly(*sol_box_pubkey, false),
+        AccountMeta::new(*access_list_pubkey, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: instruction.pack(),
+    })
+}
+
+/// Creates an AddAccessListEntry instruction
+///
+/// `sol_box_pubkey` is any box linked to the list.
+pub fn add_access_list_entry(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    access_list_pubkey: &Pubkey,
+    sender_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    access_list_instruction(
+        program_id,
+        owner_pubkey,
+        sol_box_pubkey,
+        access_list_pubkey,
+        Sol2SolInstruction::AddAccessListEntry {
+            owner: *owner_pubkey,
+            sender: *sender_pubkey,
+        },
+    )
+}
+
+/// Creates a RemoveAccessListEntry instruction
+pub fn remove_access_list_entry(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    access_list_pubkey: &Pubkey,
+    sender_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    access_list_instruction(
+        program_id,
+        owner_pubkey,
+        sol_box_pubkey,
+        access_list_pubkey,
+        Sol2SolInstruction::RemoveAccessListEntry {
+            owner: *owner_pubkey,
+            sender: *sender_pubkey,
+        },
+    )
+}
+
+/// Creates a SetAccessListMode instruction
+pub fn set_access_list_mode(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    access_list_pubkey: &Pubkey,
+    mode: AccessListMode,
+) -> Result<Instruction, ProgramError> {
+    access_list_instruction(
+        program_id,
+        owner_pubkey,
+        sol_box_pubkey,
+        access_list_pubkey,
+        Sol2SolInstruction::SetAccessListMode {
+            owner: *owner_pubkey,
+            mode,
+        },
+    )
+}
+
+/// Creates a SetPostage instruction for every box in `sol_boxes`
+pub fn set_postage(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_boxes: &[Pubkey],
+    lamports: u64,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::SetPostage {
+        owner: *owner_pubkey,
+        lamports,
+    }.pack();
+
+    let mut accounts = vec![AccountMeta::new_readonly(*owner_pubkey, true)];
+    for sol_box_pubkey in sol_boxes {
+        accounts.push(AccountMeta::new(*sol_box_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a SetTokenPostage instruction for every box in `sol_boxes`
+pub fn set_token_postage(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_boxes: &[Pubkey],
+    mint_pubkey: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::SetTokenPostage {
+        owner: *owner_pubkey,
+        mint: *mint_pubkey,
+        amount,
+    }.pack();
+
+    let mut accounts = vec![AccountMeta::new_readonly(*owner_pubkey, true)];
+    for sol_box_pubkey in sol_boxes {
+        accounts.push(AccountMeta::new(*sol_box_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a SetEncryptionKey instruction for every box in `sol_boxes`
+pub fn set_encryption_key(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_boxes: &[Pubkey],
+    encryption_key: &[u8; 32],
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::SetEncryptionKey {
+        owner: *owner_pubkey,
+        encryption_key: *encryption_key,
+    }.pack();
+
+    let mut accounts = vec![AccountMeta::new_readonly(*owner_pubkey, true)];
+    for sol_box_pubkey in sol_boxes {
+        accounts.push(AccountMeta::new(*sol_box_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a MarkMessages instruction for `messages` stored in `sol_box_pubkey`
+pub fn mark_messages(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    messages: &[Pubkey],
+    set_flags: u8,
+    clear_flags: u8,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::MarkMessages {
+        owner: *owner_pubkey,
+        set_flags,
+        clear_flags,
+    }.pack();
+
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*owner_pubkey, true),
+        AccountMeta::new_readonly(*sol_box_pubkey, false),
+    ];
+    for message_pubkey in messages {
+        accounts.push(AccountMeta::new(*message_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a SetReadReceipts instruction for every box in `sol_boxes`
+pub fn set_read_receipts(
+    program_id: &Pubkey,
+    owner_pubkey: &Pubkey,
+    sol_boxes: &[Pubkey],
+    enabled: bool,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::SetReadReceipts {
+        owner: *owner_pubkey,
+        enabled,
+    }.pack();
+
+    let mut accounts = vec![AccountMeta::new_readonly(*owner_pubkey, true)];
+    for sol_box_pubkey in sol_boxes {
+        accounts.push(AccountMeta::new(*sol_box_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a SetMessageExpiry instruction for `message_pubkey` stored in `sol_box_pubkey`
+pub fn set_message_expiry(
+    program_id: &Pubkey,
+    authority_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    message_pubkey: &Pubkey,
+    expiry: MessageExpiry,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::SetMessageExpiry {
+        authority: *authority_pubkey,
+        expiry,
+    }.pack();
+
+    let accounts = vec![
+        AccountMeta::new_readonly(*authority_pubkey, true),
+        AccountMeta::new_readonly(*sol_box_pubkey, false),
+        AccountMeta::new(*message_pubkey, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Lamports the caller of PurgeExpired earns for each message purged
+pub const PURGE_BOUNTY_LAMPORTS: u64 = 10_000;
+
+/// Creates a PurgeExpired instruction
+///
+/// `sender_pubkey` must be the message's sender, and `caller_pubkey`
+/// receives the bounty.
+pub fn purge_expired(
+    program_id: &Pubkey,
+    caller_pubkey: &Pubkey,
+    sol_box_pubkey: &Pubkey,
+    message_pubkey: &Pubkey,
+    sender_pubkey: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::PurgeExpired.pack();
+
+    let accounts = vec![
+        AccountMeta::new(*message_pubkey, false),
+        AccountMeta::new(*sol_box_pubkey, false),
+        AccountMeta::new(*caller_pubkey, false),
+        AccountMeta::new(*sender_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+/// Creates a MigrateAccount instruction
+///
+/// `account_pubkey` may be a sol box, message or access list, and
+/// `payer_pubkey` covers any extra rent. `sol_box_pubkey` is the box
+/// holding `account_pubkey` when it is a version 0 message.
+pub fn migrate_account(
+    program_id: &Pubkey,
+    payer_pubkey: &Pubkey,
+    account_pubkey: &Pubkey,
+    sol_box_pubkey: Option<&Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let data: Vec<u8> = Sol2SolInstruction::MigrateAccount.pack();
+
+    let mut accounts = vec![
+        AccountMeta::new(*account_pubkey, false),
+        AccountMeta::new(*payer_pubkey, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    if let Some(sol_box_pubkey) = sol_box_pubkey {
+        accounts.push(AccountMeta::new_readonly(*sol_box_pubkey, false));
+    }
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_parsing() {
+        let msg_data: Vec<u8> = b"hello world!".to_vec();       // 12
+        let init_msg_data = msg_data.clone();
+        let msg_size = msg_data.len();                          // 4
+        let sender = Pubkey::new_unique();                      // 32
+        let recipient = Pubkey::new_unique();                   // 32
+        let message_pubkey = Pubkey::new_unique();                   // 32
         let sol_box_pubkey = Pubkey::new_unique();                  // 32
         // 12 + 4 + 32 + 32 + 1 (tag) = 113
         let instruction = Sol2SolInstruction::WriteMessage {
@@ -282,24 +1669,326 @@ mod tests {
             recipient,
             message_pubkey,
             sol_box_pubkey,
-            msg_size,
-            msg_string,
+            msg_data,
+            reply_to: Pubkey::default(),
+            thread_root: Pubkey::default(),
         };
         let packed_vec = instruction.pack();
-        assert_eq!(133 + msg_size as usize, packed_vec.len());
+        assert_eq!(133 + msg_size, packed_vec.len());
         assert_eq!(145, packed_vec.len());
         
         let recreated = Sol2SolInstruction::unpack(&packed_vec[..]).unwrap();
         assert_eq!(instruction, recreated);
         match instruction {
-            Sol2SolInstruction::WriteMessage{ msg_string, .. } => {
-                assert_eq!(init_msg_string, msg_string);
+            Sol2SolInstruction::WriteMessage{ msg_data, .. } => {
+                assert_eq!(init_msg_data, msg_data);
             }
             _ => {
                 // Lol manually fail test
                 assert_eq!(0, 1);
             }
         }
+
+        // Replies carry their thread after the body
+        let instruction = Sol2SolInstruction::WriteMessage {
+            sender,
+            recipient,
+            message_pubkey,
+            sol_box_pubkey,
+            msg_data: init_msg_data,
+            reply_to: Pubkey::new_unique(),
+            thread_root: Pubkey::new_unique(),
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(197 + msg_size, packed_vec.len());
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..packed_vec.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_append_sol_box_parsing() {
+        let instruction = Sol2SolInstruction::AppendSolBox {
+            owner: Pubkey::new_unique(),
+            num_spots: 7,
+            index: 2,
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(1 + 32 + 4 + 4, packed_vec.len());
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+    }
+
+    #[test]
+    fn test_init_sol_box_parsing() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let instruction = init_sol_box(&program_id, &owner, 3, 7).unwrap();
+        let (sol_box, _) = find_sol_box_address(&program_id, &owner, 3);
+        assert_eq!(sol_box, instruction.accounts[0].pubkey);
+        assert_ne!(sol_box, find_sol_box_address(&program_id, &owner, 4).0);
+        assert_eq!(
+            Sol2SolInstruction::InitializeSolBox {
+                owner,
+                num_spots: 7,
+                next_box: sol_box,
+                prev_box: sol_box,
+                index: 3,
+            },
+            Sol2SolInstruction::unpack(&instruction.data).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_close_sol_box_parsing() {
+        for cascade in [false, true] {
+            let instruction = Sol2SolInstruction::CloseSolBox {
+                owner: Pubkey::new_unique(),
+                cascade,
+            };
+            let packed_vec = instruction.pack();
+            assert_eq!(1 + 32 + 1, packed_vec.len());
+            assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        }
+        let mut packed_vec = Sol2SolInstruction::CloseSolBox {
+            owner: Pubkey::new_unique(),
+            cascade: true,
+        }.pack();
+        packed_vec[33] = 2;
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..]).is_err());
+    }
+
+    #[test]
+    fn test_transfer_ownership_parsing() {
+        for instruction in [
+            Sol2SolInstruction::TransferSolBoxOwnership {
+                owner: Pubkey::new_unique(),
+                new_owner: Pubkey::new_unique(),
+                two_step: true,
+                index: 0,
+            },
+            Sol2SolInstruction::AcceptSolBoxOwnership {
+                new_owner: Pubkey::new_unique(),
+                index: 3,
+            },
+        ] {
+            let packed_vec = instruction.pack();
+            assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_access_list_parsing() {
+        let owner = Pubkey::new_unique();
+        let sender = Pubkey::new_unique();
+        for instruction in [
+            Sol2SolInstruction::InitializeAccessList {
+                owner,
+                mode: AccessListMode::Blocklist,
+                capacity: 10,
+            },
+            Sol2SolInstruction::AddAccessListEntry { owner, sender },
+            Sol2SolInstruction::RemoveAccessListEntry { owner, sender },
+            Sol2SolInstruction::SetAccessListMode {
+                owner,
+                mode: AccessListMode::Allowlist,
+            },
+        ] {
+            let packed_vec = instruction.pack();
+            assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        }
+
+        let mut packed_vec = Sol2SolInstruction::SetAccessListMode {
+            owner,
+            mode: AccessListMode::Allowlist,
+        }.pack();
+        packed_vec[33] = 2;
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..]).is_err());
+    }
+
+    #[test]
+    fn test_set_postage_parsing() {
+        let instruction = Sol2SolInstruction::SetPostage {
+            owner: Pubkey::new_unique(),
+            lamports: u64::MAX - 1,
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(1 + 32 + 8, packed_vec.len());
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..40]).is_err());
+
+        let instruction = Sol2SolInstruction::SetTokenPostage {
+            owner: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            amount: 42,
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+
+        let instruction = Sol2SolInstruction::SetEncryptionKey {
+            owner: Pubkey::new_unique(),
+            encryption_key: [3; 32],
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(65, packed_vec.len());
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..64]).is_err());
+
+        let instruction = Sol2SolInstruction::WriteAnonymousMessage {
+            recipient: Pubkey::new_unique(),
+            message_pubkey: Pubkey::new_unique(),
+            sol_box_pubkey: Pubkey::new_unique(),
+            reply_token: Pubkey::new_unique(),
+            msg_data: b"guess!".to_vec(),
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(1 + 4 * 32 + 4 + 6, packed_vec.len());
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..packed_vec.len() - 1]).is_err());
+
+        let instruction = Sol2SolInstruction::ReplyAnonymous {
+            owner: Pubkey::new_unique(),
+            message_pubkey: Pubkey::new_unique(),
+            msg_data: b"you".to_vec(),
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+
+        let instruction = Sol2SolInstruction::DeleteReply {
+            reply_token: Pubkey::new_unique(),
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+
+        let instruction = Sol2SolInstruction::MarkMessages {
+            owner: Pubkey::new_unique(),
+            set_flags: 1,
+            clear_flags: 6,
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(35, packed_vec.len());
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..34]).is_err());
+
+        let instruction = Sol2SolInstruction::SetReadReceipts {
+            owner: Pubkey::new_unique(),
+            enabled: true,
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+
+        let instruction = Sol2SolInstruction::SetMessageExpiry {
+            authority: Pubkey::new_unique(),
+            expiry: MessageExpiry::Slot(1234),
+        };
+        let packed_vec = instruction.pack();
+        assert_eq!(42, packed_vec.len());
+        assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+        assert!(Sol2SolInstruction::unpack(&packed_vec[..41]).is_err());
+
+        let packed_vec = Sol2SolInstruction::PurgeExpired.pack();
+        assert_eq!(vec![20], packed_vec);
+        assert_eq!(Sol2SolInstruction::PurgeExpired, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+
+        let packed_vec = Sol2SolInstruction::MigrateAccount.pack();
+        assert_eq!(vec![21], packed_vec);
+        assert_eq!(Sol2SolInstruction::MigrateAccount, Sol2SolInstruction::unpack(&packed_vec[..]).unwrap());
+    }
+
+    /// One of every instruction, in tag order
+    fn sample_instructions() -> Vec<Sol2SolInstruction> {
+        let key = Pubkey::new_unique;
+        let msg_data = b"hello world!".to_vec();
+        vec![
+            Sol2SolInstruction::InitializeSolBox {
+                owner: key(),
+                num_spots: 20,
+                next_box: key(),
+                prev_box: key(),
+                index: 1,
+            },
+            Sol2SolInstruction::WriteMessage {
+                sender: key(),
+                recipient: key(),
+                message_pubkey: key(),
+                sol_box_pubkey: key(),
+                        msg_data: msg_data.clone(),
+                reply_to: key(),
+                thread_root: key(),
+            },
+            Sol2SolInstruction::DeleteMessage { owner: key(), message_id: key(), sol_box_id: key() },
+            Sol2SolInstruction::AppendSolBox { owner: key(), num_spots: 5, index: 0 },
+            Sol2SolInstruction::CloseSolBox { owner: key(), cascade: true },
+            Sol2SolInstruction::TransferSolBoxOwnership { owner: key(), new_owner: key(), two_step: false, index: 2 },
+            Sol2SolInstruction::AcceptSolBoxOwnership { new_owner: key(), index: 1 },
+            Sol2SolInstruction::InitializeAccessList { owner: key(), mode: AccessListMode::Blocklist, capacity: 3 },
+            Sol2SolInstruction::AddAccessListEntry { owner: key(), sender: key() },
+            Sol2SolInstruction::RemoveAccessListEntry { owner: key(), sender: key() },
+            Sol2SolInstruction::SetAccessListMode { owner: key(), mode: AccessListMode::Allowlist },
+            Sol2SolInstruction::SetPostage { owner: key(), lamports: 5000 },
+            Sol2SolInstruction::SetTokenPostage { owner: key(), mint: key(), amount: 7 },
+            Sol2SolInstruction::SetEncryptionKey { owner: key(), encryption_key: [9; 32] },
+            Sol2SolInstruction::WriteAnonymousMessage {
+                recipient: key(),
+                message_pubkey: key(),
+                sol_box_pubkey: key(),
+                reply_token: key(),
+                        msg_data: msg_data.clone(),
+            },
+            Sol2SolInstruction::ReplyAnonymous {
+                owner: key(),
+                message_pubkey: key(),
+                        msg_data,
+            },
+            Sol2SolInstruction::DeleteReply { reply_token: key() },
+            Sol2SolInstruction::MarkMessages { owner: key(), set_flags: 1, clear_flags: 2 },
+            Sol2SolInstruction::SetReadReceipts { owner: key(), enabled: true },
+            Sol2SolInstruction::SetMessageExpiry { authority: key(), expiry: MessageExpiry::UnixTimestamp(-1) },
+            Sol2SolInstruction::PurgeExpired,
+            Sol2SolInstruction::MigrateAccount,
+        ]
+    }
+
+    #[test]
+    fn test_borsh_instruction_parsing() {
+        for (tag, instruction) in sample_instructions().into_iter().enumerate() {
+            let mut packed_vec = instruction.pack();
+            assert_eq!(tag as u8, packed_vec[0]);
+            assert_eq!(instruction, Sol2SolInstruction::unpack(&packed_vec).unwrap());
+            packed_vec.push(0);
+            assert!(Sol2SolInstruction::unpack(&packed_vec).is_err());
+            packed_vec.pop();
+
+            let mut borsh_vec = instruction.pack_borsh();
+            assert_eq!(tag as u8 + BORSH_INSTRUCTION_TAG_OFFSET, borsh_vec[0]);
+            assert_eq!(instruction, Sol2SolInstruction::unpack(&borsh_vec).unwrap());
+            assert_eq!(instruction, Sol2SolInstruction::unpack_borsh(&borsh_vec).unwrap());
+            assert!(Sol2SolInstruction::unpack_borsh(&packed_vec).is_err());
+
+            borsh_vec.push(0);
+            assert!(Sol2SolInstruction::unpack(&borsh_vec).is_err());
+            borsh_vec.pop();
+            if borsh_vec.len() > 1 {
+                assert!(Sol2SolInstruction::unpack(&borsh_vec[..borsh_vec.len() - 1]).is_err());
+            }
+        }
+        assert!(Sol2SolInstruction::unpack(&[BORSH_INSTRUCTION_TAG_OFFSET + 23]).is_err());
+        assert!(Sol2SolInstruction::unpack(&[]).is_err());
+
+        // Both encodings size the body from `msg_data`, so a hand-packed
+        // size that runs past the data is rejected
+        let instruction = Sol2SolInstruction::WriteAnonymousMessage {
+            recipient: Pubkey::new_unique(),
+            message_pubkey: Pubkey::new_unique(),
+            sol_box_pubkey: Pubkey::new_unique(),
+            reply_token: Pubkey::new_unique(),
+            msg_data: b"hello".to_vec(),
+        };
+        let mut packed_vec = instruction.pack();
+        assert_eq!(5u32.to_le_bytes(), packed_vec[129..133]);
+        packed_vec[129] = 6;
+        assert_eq!(
+            Sol2SolInstruction::unpack(&packed_vec),
+            Err(Sol2SolError::InvalidInstructionData.into()),
+        );
     }
 
     #[test]
@@ -309,10 +1998,10 @@ mod tests {
         ];
         let recreated = Sol2SolInstruction::unpack(&manual_bytes[..]).unwrap();
         match recreated {
-            Sol2SolInstruction::WriteMessage{ msg_string, .. } => {
+            Sol2SolInstruction::WriteMessage{ msg_data, .. } => {
                 // should say "hello world!"
-                println!("Recreated string: [{}]", msg_string);
-                assert_eq!(msg_string, "hello world!");
+                println!("Recreated string: [{}]", String::from_utf8_lossy(&msg_data));
+                assert_eq!(msg_data, b"hello world!");
             }
             _ => {
                 // Lol manually fail test
@@ -328,7 +2017,9 @@ mod tests {
         let manual_bytes = [
             0x00, 0xe7, 0x38, 0xcc, 0x07, 0x5d, 0xd3, 0xe1, 0xaf, 0x7f, 0x14, 0x4b, 0xcd, 0x39, 0x35, 0x21, 0x3c, 0xe1, 0x3f, 0x0a, 0x1e, 0x12, 0x22, 0x79, 0x87, 0x70, 0x0e, 0x95, 0xf6, 0xc9, 0x8a, 0x8f, 0x38, 0x73, 0xf4, 0xef, 0x5f, 0xe3, 0xdb, 0x6f, 0x25, 0x40, 0x2d, 0xc2, 0xce, 0x13, 0x71, 0x62, 0xbc, 0x74, 0x74, 0x4f, 0xde, 0x84, 0xac, 0xae, 0x6b, 0x6e, 0x04, 0xfe, 0x34, 0xa3, 0xf3, 0x83, 0xc4, 0x73, 0xf4, 0xef, 0x5f, 0xe3, 0xdb, 0x6f, 0x25, 0x40, 0x2d, 0xc2, 0xce, 0x13, 0x71, 0x62, 0xbc, 0x74, 0x74, 0x4f, 0xde, 0x84, 0xac, 0xae, 0x6b, 0x6e, 0x04, 0xfe, 0x34, 0xa3, 0xf3, 0x83, 0xc4, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9
         ];
-        let recreated = Sol2SolInstruction::unpack(&manual_bytes[..]).unwrap();
+        // Trailing bytes are rejected, the instruction itself is the first 105
+        assert!(Sol2SolInstruction::unpack(&manual_bytes[..]).is_err());
+        let recreated = Sol2SolInstruction::unpack(&manual_bytes[..105]).unwrap();
         match recreated {
             Sol2SolInstruction::InitializeSolBox{ owner, .. } => {
                 // should say "hello world!"
@@ -7,4 +7,14 @@ mod entrypoint;
 pub mod processor;
 pub mod error;
 pub mod state;
-pub mod instruction;
\ No newline at end of file
+pub mod instruction;
+#[cfg(feature = "offchain")]
+pub mod chain;
+pub mod envelope;
+pub mod event;
+#[cfg(feature = "offchain")]
+pub mod thread;
+#[cfg(feature = "indexer")]
+pub mod indexer;
+#[cfg(feature = "client")]
+pub mod client;
\ No newline at end of file
@@ -2,22 +2,24 @@
 use::{
     // arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs},
     solana_program::{  
-        entrypoint::{ProgramResult},
+        entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
         program_error::{ProgramError},
         account_info::{
             next_account_info, 
             AccountInfo,
         },
         pubkey::Pubkey,
-        program_pack::{Pack},
         rent::Rent,
+        clock::Clock,
         // msg,
-        // system_instruction,
-        // program::{invoke, invoke_signed},
-        // system_program,
+        system_instruction,
+        program::{invoke, invoke_signed},
+        system_program,
         sysvar::{Sysvar},
+        program_pack::Pack,
         msg,
     },
+    spl_token::state::Account as TokenAccount,
     // std::{
     //     str::from_utf8,
     //     vec::Vec,
@@ -25,14 +27,37 @@ use::{
 };
 use crate::{
     // error::Sol2SolError,
-    instruction::Sol2SolInstruction,
+    instruction::{
+        Sol2SolInstruction,
+        find_sol_box_address,
+        find_message_address,
+        find_access_list_address,
+        find_reply_address,
+        PURGE_BOUNTY_LAMPORTS,
+        SOL_BOX_SEED,
+        MESSAGE_SEED,
+        ACCESS_LIST_SEED,
+        REPLY_SEED,
+    },
     state::{
+        ACCESS_LIST_TAG,
+        ACCOUNT_VERSION,
+        MESSAGE_SLOT_UNKNOWN,
+        MESSAGE_STATUS_ALL,
+        MESSAGE_TAG,
+        SOL_BOX_TAG,
+        MESSAGE_STATUS_READ,
+        AccessList,
+        AccessListMode,
+        MessageExpiry,
         SolBox, 
-        // Message,
-        pack_message_into,
-        SOL_BOX_NUM_SPOTS
+        SolBoxView,
+        unpack_account_prefix,
+        Message,
     },
+    envelope::Envelope,
     error::Sol2SolError,
+    event::Sol2SolEvent,
 };
 
 /// Directs how message instructions will be handled
@@ -52,6 +77,7 @@ impl Processor {
                 num_spots,
                 next_box,
                 prev_box,
+                index,
             } => {
                 Self::process_init_sol_box(
                     program_id, 
@@ -60,6 +86,7 @@ impl Processor {
                     num_spots,
                     &next_box,
                     &prev_box,
+                    index,
                 )
             },
             Sol2SolInstruction::WriteMessage {
@@ -67,20 +94,88 @@ impl Processor {
                 recipient,
                 message_pubkey,
                 sol_box_pubkey,
-                msg_size,
-                msg_string,
+                msg_data,
+                reply_to,
+                thread_root,
             } => {
                 Self::process_write_message(
                     program_id, 
                     accounts,
                     &sender,
+                    false,
                     &recipient, 
                     &message_pubkey,
                     &sol_box_pubkey, 
-                    msg_size,
-                    &msg_string,
+                    &msg_data,
+                    &reply_to,
+                    &thread_root,
+                )
+            },
+            Sol2SolInstruction::WriteAnonymousMessage {
+                recipient,
+                message_pubkey,
+                sol_box_pubkey,
+                reply_token,
+                msg_data,
+            } => {
+                Self::process_write_message(
+                    program_id,
+                    accounts,
+                    &reply_token,
+                    true,
+                    &recipient,
+                    &message_pubkey,
+                    &sol_box_pubkey,
+                    &msg_data,
+                    &Pubkey::default(),
+                    &Pubkey::default(),
+                )
+            },
+            Sol2SolInstruction::ReplyAnonymous {
+                owner,
+                message_pubkey,
+                msg_data,
+            } => {
+                Self::process_reply_anonymous(
+                    program_id,
+                    accounts,
+                    &owner,
+                    &message_pubkey,
+                    &msg_data,
                 )
             },
+            Sol2SolInstruction::DeleteReply {
+                reply_token,
+            } => {
+                Self::process_delete_reply(program_id, accounts, &reply_token)
+            },
+            Sol2SolInstruction::MarkMessages {
+                owner,
+                set_flags,
+                clear_flags,
+            } => {
+                Self::process_mark_messages(program_id, accounts, &owner, set_flags, clear_flags)
+            },
+            Sol2SolInstruction::SetReadReceipts {
+                owner,
+                enabled,
+            } => {
+                Self::process_update_sol_boxes(program_id, accounts, &owner, |sol_box| {
+                    sol_box.read_receipts = enabled;
+                }, |sol_box| Sol2SolEvent::ReadReceiptsSet { sol_box, enabled })
+            },
+            Sol2SolInstruction::SetMessageExpiry {
+                authority,
+                expiry,
+            } => {
+                Self::process_set_message_expiry(program_id, accounts, &authority, expiry)
+            },
+            Sol2SolInstruction::PurgeExpired => {
+                Self::process_purge_expired(program_id, accounts)
+            },
+            Sol2SolInstruction::MigrateAccount => {
+                Self::process_migrate_account(program_id, accounts)
+            },
             Sol2SolInstruction::DeleteMessage {
                 owner,
                 message_id,
@@ -93,10 +188,125 @@ impl Processor {
                     &message_id, 
                     &sol_box_id,
                 )
+            },
+            Sol2SolInstruction::AppendSolBox {
+                owner,
+                num_spots,
+                index,
+            } => {
+                Self::process_append_sol_box(
+                    program_id,
+                    accounts,
+                    &owner,
+                    num_spots,
+                    index,
+                )
+            }
+            Sol2SolInstruction::CloseSolBox {
+                owner,
+                cascade,
+            } => {
+                Self::process_close_sol_box(
+                    program_id,
+                    accounts,
+                    &owner,
+                    cascade,
+                )
+            }
+            Sol2SolInstruction::TransferSolBoxOwnership {
+                owner,
+                new_owner,
+                two_step,
+                index,
+            } => {
+                Self::process_transfer_sol_box_ownership(
+                    program_id,
+                    accounts,
+                    &owner,
+                    &new_owner,
+                    two_step,
+                    index,
+                )
+            }
+            Sol2SolInstruction::AcceptSolBoxOwnership {
+                new_owner,
+                index,
+            } => {
+                Self::process_accept_sol_box_ownership(
+                    program_id,
+                    accounts,
+                    &new_owner,
+                    index,
+                )
+            }
+            Sol2SolInstruction::InitializeAccessList {
+                owner,
+                mode,
+                capacity,
+            } => {
+                Self::process_init_access_list(
+                    program_id,
+                    accounts,
+                    &owner,
+                    mode,
+                    capacity,
+                )
+            }
+            Sol2SolInstruction::AddAccessListEntry {
+                owner,
+                sender,
+            } => {
+                Self::process_update_access_list(program_id, accounts, &owner, |access_list| {
+                    access_list.add_entry(&sender)
+                }, |access_list| Sol2SolEvent::AccessListEntryAdded { access_list, sender })
+            }
+            Sol2SolInstruction::RemoveAccessListEntry {
+                owner,
+                sender,
+            } => {
+                Self::process_update_access_list(program_id, accounts, &owner, |access_list| {
+                    access_list.remove_entry(&sender)
+                }, |access_list| Sol2SolEvent::AccessListEntryRemoved { access_list, sender })
+            }
+            Sol2SolInstruction::SetAccessListMode {
+                owner,
+                mode,
+            } => {
+                Self::process_update_access_list(program_id, accounts, &owner, |access_list| {
+                    access_list.mode = mode;
+                    Ok(())
+                }, |access_list| Sol2SolEvent::AccessListModeSet { access_list, mode })
+            }
+            Sol2SolInstruction::SetPostage {
+                owner,
+                lamports,
+            } => {
+                Self::process_update_sol_boxes(program_id, accounts, &owner, |sol_box| {
+                    sol_box.postage_lamports = lamports;
+                }, |sol_box| Sol2SolEvent::PostageSet { sol_box, lamports })
+            }
+            Sol2SolInstruction::SetTokenPostage {
+                owner,
+                mint,
+                amount,
+            } => {
+                Self::process_update_sol_boxes(program_id, accounts, &owner, |sol_box| {
+                    sol_box.postage_mint = mint;
+                    sol_box.postage_token_amount = amount;
+                }, |sol_box| Sol2SolEvent::TokenPostageSet { sol_box, mint, amount })
+            }
+            Sol2SolInstruction::SetEncryptionKey {
+                owner,
+                encryption_key,
+            } => {
+                Self::process_update_sol_boxes(program_id, accounts, &owner, |sol_box| {
+                    sol_box.encryption_key = encryption_key;
+                }, |sol_box| Sol2SolEvent::EncryptionKeySet { sol_box, encryption_key })
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn process_init_sol_box<'a>(
         program_id: &'a Pubkey,
         accounts: &'a [AccountInfo],
@@ -104,6 +314,7 @@ impl Processor {
         num_spots: u32,
         next_box: &'a Pubkey,
         prev_box: &'a Pubkey,
+        index: u32,
     ) -> ProgramResult {
         msg!("[process]initializing sol box");
 
@@ -112,28 +323,8 @@ impl Processor {
         let sol_box_info = next_account_info(account_info_iter)?;
         let payer_info = next_account_info(account_info_iter)?;
         let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_info = next_account_info(account_info_iter)?;
 
-        let sol_box_data_len = sol_box_info.data_len();
-
-        // Check that this was created properly
-        msg!("Checking system owner");
-        if sol_box_info.owner != program_id {
-            msg!(&format!("Sol box owner: {}", &sol_box_info.owner.to_string()));
-            msg!(&format!("Program id: {}", &program_id.to_string()));
-            msg!("erroring on sol box mismatch");
-            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
-        }
-
-        if !sol_box_info.is_writable {
-            msg!("Sol box info is not writable, exiting!");
-            return Err(Sol2SolError::InvalidAccountData.into());
-        }
-        // Check that account data is zero'd
-        // msg!("Checking data is uninitialized");
-        // let sol_box = SolBox::unpack_unchecked(&sol_box_info.data.borrow())?;
-        // if sol_box.is_initialized {
-        //     return Err(Sol2SolError::SolBoxAlreadyInUse.into());
-        // }
         // Check that payer will be user-space owner
         msg!("Checking user space owner");
         if owner != payer_info.key {
@@ -142,44 +333,206 @@ impl Processor {
             msg!("erroring on owner mismatch");
             return Err(Sol2SolError::OwnerMismatch.into());
         }
-        // Check that the solbox is rent-exempt
-        msg!("Checking that sol box is rent exempt");
-        if !rent.is_exempt(sol_box_info.lamports(), sol_box_data_len) {
-            return Err(Sol2SolError::InsufficientFunds.into());
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !system_program::check_id(system_program_info.key) {
+            return Err(Sol2SolError::IncorrectSystemProgramAddress.into());
+        }
+        // Check that the sol box lives at the owner's derived address
+        msg!("Checking sol box address");
+        let (sol_box_address, bump_seed) = find_sol_box_address(program_id, owner, index);
+        if sol_box_address != *sol_box_info.key {
+            return Err(Sol2SolError::IncorrectSolBox.into());
+        }
+        if !sol_box_info.is_writable {
+            msg!("Sol box info is not writable, exiting!");
+            return Err(Sol2SolError::InvalidAccountData.into());
         }
+        if sol_box_info.owner != &system_program::id() || sol_box_info.data_len() != 0 {
+            return Err(Sol2SolError::SolBoxAlreadyInUse.into());
+        }
+        // A new box is a chain of its own; only AppendSolBox links boxes
+        if next_box != sol_box_info.key || prev_box != sol_box_info.key {
+            return Err(Sol2SolError::InvalidSolBoxLink.into());
+        }
+        msg!("Checking sol box capacity");
+        SolBox::check_num_spots(num_spots)?;
         // -------End Account Check----->
 
+        // <--------Create Sol Box-------
+        msg!("Creating sol box account");
+        let sol_box_data_len = SolBox::get_packed_len(num_spots);
+        let index_bytes = index.to_le_bytes();
+        let sol_box_signer_seeds: &[&[u8]] = &[
+            SOL_BOX_SEED,
+            owner.as_ref(),
+            &index_bytes,
+            &[bump_seed],
+        ];
+        Self::create_pda_account(
+            payer_info,
+            sol_box_info,
+            system_program_info,
+            rent.minimum_balance(sol_box_data_len),
+            sol_box_data_len,
+            program_id,
+            sol_box_signer_seeds,
+        )?;
+        // -------End Create Sol Box----->
+
         // <---------Init Sol Box-------
         msg!("initializing sol box");
-        let message_slots: [Pubkey; SOL_BOX_NUM_SPOTS] = SolBox::get_empty_message_slots();
+        let message_slots: Vec<Option<Pubkey>> = SolBox::get_empty_message_slots(num_spots);
         let sol_box = SolBox {
             owner: *owner,
-            next_box: *next_box,
-            prev_box: *prev_box,
+            next_box: *sol_box_info.key,
+            prev_box: *sol_box_info.key,
             num_spots,
+            occupied: SolBox::get_empty_occupancy(num_spots),
             message_slots,
             is_initialized: true,
             num_in_use: 0,
+            message_seq: 0,
+            pending_owner: Pubkey::default(),
+            access_list: Pubkey::default(),
+            postage_lamports: 0,
+            postage_mint: Pubkey::default(),
+            postage_token_amount: 0,
+            encryption_key: [0; 32],
+            read_receipts: false,
         };
 
         msg!("Packing sol box");
 
         SolBox::pack(sol_box, &mut sol_box_info.data.borrow_mut())?;
+        Sol2SolEvent::BoxInitialized {
+            sol_box: *sol_box_info.key,
+            owner: *owner,
+            num_spots,
+            index,
+        }.emit();
         msg!("Done!");
         // -------End Init Sol Box------>
 
         Ok(())
     }
 
+    fn process_append_sol_box<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        num_spots: u32,
+        index: u32,
+    ) -> ProgramResult {
+        msg!("[process]appending sol box");
+        let account_info_iter = &mut accounts.iter();
+        let new_box_info = next_account_info(account_info_iter)?;
+        let tail_box_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !system_program::check_id(system_program_info.key) {
+            return Err(Sol2SolError::IncorrectSystemProgramAddress.into());
+        }
+        msg!("Checking both boxes live at the owner's derived addresses");
+        let (tail_box_address, _) = find_sol_box_address(program_id, owner, index);
+        let new_index = index.checked_add(1).ok_or(Sol2SolError::IncorrectSolBox)?;
+        let (new_box_address, bump_seed) = find_sol_box_address(program_id, owner, new_index);
+        if tail_box_address != *tail_box_info.key || new_box_address != *new_box_info.key {
+            return Err(Sol2SolError::IncorrectSolBox.into());
+        }
+        if tail_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        if new_box_info.owner != &system_program::id() || new_box_info.data_len() != 0 {
+            return Err(Sol2SolError::SolBoxAlreadyInUse.into());
+        }
+        SolBox::check_num_spots(num_spots)?;
+        msg!("Checking tail box belongs to owner and ends its chain");
+        let mut tail_box = SolBox::unpack(&tail_box_info.data.borrow())?;
+        if tail_box.owner != *owner {
+            return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+        }
+        if tail_box.next_box != *tail_box_info.key {
+            return Err(Sol2SolError::SolBoxNotChainTail.into());
+        }
+
+        msg!("Creating new box account");
+        let sol_box_data_len = SolBox::get_packed_len(num_spots);
+        let index_bytes = new_index.to_le_bytes();
+        let sol_box_signer_seeds: &[&[u8]] = &[
+            SOL_BOX_SEED,
+            owner.as_ref(),
+            &index_bytes,
+            &[bump_seed],
+        ];
+        Self::create_pda_account(
+            owner_info,
+            new_box_info,
+            system_program_info,
+            rent.minimum_balance(sol_box_data_len),
+            sol_box_data_len,
+            program_id,
+            sol_box_signer_seeds,
+        )?;
+
+        msg!("Linking new box after tail");
+        let new_box = SolBox {
+            owner: *owner,
+            next_box: *new_box_info.key,
+            prev_box: *tail_box_info.key,
+            num_spots,
+            num_in_use: 0,
+            is_initialized: true,
+            message_seq: 0,
+            pending_owner: Pubkey::default(),
+            access_list: tail_box.access_list,
+            postage_lamports: tail_box.postage_lamports,
+            postage_mint: tail_box.postage_mint,
+            postage_token_amount: tail_box.postage_token_amount,
+            encryption_key: tail_box.encryption_key,
+            read_receipts: tail_box.read_receipts,
+            occupied: SolBox::get_empty_occupancy(num_spots),
+            message_slots: SolBox::get_empty_message_slots(num_spots),
+        };
+        SolBox::pack(new_box, &mut new_box_info.data.borrow_mut())?;
+        tail_box.next_box = *new_box_info.key;
+        SolBox::pack(tail_box, &mut tail_box_info.data.borrow_mut())?;
+        Sol2SolEvent::BoxAppended {
+            sol_box: *new_box_info.key,
+            prev_box: *tail_box_info.key,
+            owner: *owner,
+            num_spots,
+            index: new_index,
+        }.emit();
+
+        msg!("Appending sol box succeeded!");
+        Ok(())
+    }
+
+    /// Delivers a message; when `is_anonymous`, `sender` is the reply token
+    /// stored in its place and the payer goes unrecorded
+    #[allow(clippy::too_many_arguments)]
     fn process_write_message<'a>(
         program_id: &'a Pubkey,
         accounts: &'a [AccountInfo],
         sender: &'a Pubkey,
+        is_anonymous: bool,
         recipient: &'a Pubkey,
         message_pubkey: &'a Pubkey,
         sol_box_pubkey: &'a Pubkey,
-        msg_size: u32,
-        msg_string: &String,
+        msg_data: &[u8],
+        reply_to: &'a Pubkey,
+        thread_root: &'a Pubkey,
     ) -> ProgramResult {
         msg!("[processs]writing message");
         let account_info_iter = &mut accounts.iter();
@@ -187,15 +540,24 @@ impl Processor {
         let sol_box_info = next_account_info(account_info_iter)?;
         let payer_info = next_account_info(account_info_iter)?;
         let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let recipient_info = next_account_info(account_info_iter)?;
 
-        msg!("Checking owner of message field matches program id");
-        if message_account_info.owner != program_id {
-            return Err(Sol2SolError::OwnerMismatch.into());
+        msg!("Checking that sender signed and is paying");
+        if is_anonymous {
+            if *sender == Pubkey::default() {
+                return Err(Sol2SolError::InvalidInstructionData.into());
+            }
+        } else if payer_info.key != sender {
+            return Err(Sol2SolError::SenderMismatch.into());
         }
-        msg!("Checking message account id matches");
-        if message_account_info.key != message_pubkey {
-            return Err(ProgramError::InvalidInstructionData);
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !system_program::check_id(system_program_info.key) {
+            return Err(Sol2SolError::IncorrectSystemProgramAddress.into());
         }
+        let msg_size = msg_data.len() as u32;
         msg!("Checking that sol box pubkey matches instruction pubkey");
         if sol_box_info.key != sol_box_pubkey {
             return Err(Sol2SolError::IncorrectSolBox.into())
@@ -204,32 +566,1228 @@ impl Processor {
         if sol_box_info.owner != program_id {
             return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
         }
-        msg!("Checking owner of sol box field matches payer");
-        let mut sol_box = SolBox::unpack_unchecked(&sol_box_info.data.borrow())?;
-        if sol_box.owner != *payer_info.key {
+        msg!("Checking owner of sol box field matches recipient");
+        let sol_box = SolBoxView::from_account_info(sol_box_info)?;
+        if sol_box.owner() != *recipient || recipient_info.key != recipient {
             return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
         }
+        if sol_box.encryption_key() != [0; 32] {
+            msg!("Checking message is sealed to the sol box encryption key");
+            Envelope::check(msg_data)?;
+        }
+        if sol_box.access_list() != Pubkey::default() {
+            msg!("Checking sender against the sol box access list");
+            if is_anonymous {
+                return Err(Sol2SolError::SenderNotAllowed.into());
+            }
+            let access_list_info = next_account_info(account_info_iter)?;
+            if *access_list_info.key != sol_box.access_list() || access_list_info.owner != program_id {
+                return Err(Sol2SolError::IncorrectAccessList.into());
+            }
+            if !AccessList::unpack(&access_list_info.data.borrow())?.allows(sender) {
+                return Err(Sol2SolError::SenderNotAllowed.into());
+            }
+        }
+        msg!("Checking message account is the box's next message address");
+        let (message_address, bump_seed) =
+            find_message_address(program_id, sol_box_info.key, sol_box.message_seq());
+        if message_account_info.key != message_pubkey || *message_pubkey != message_address {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        if message_account_info.owner != &system_program::id() || message_account_info.data_len() != 0 {
+            return Err(Sol2SolError::MessageAccountInUse.into());
+        }
 
-        msg!("Writing to message state");
-        pack_message_into(recipient, sender, msg_size, msg_string, &mut message_account_info.data.borrow_mut());
+        let charges_postage = payer_info.key != recipient;
+        let charges_token_postage = charges_postage && sol_box.postage_mint() != Pubkey::default();
+        let token_postage_infos = if charges_token_postage {
+            msg!("Checking token postage accounts");
+            let sender_token_info = next_account_info(account_info_iter)?;
+            let recipient_token_info = next_account_info(account_info_iter)?;
+            let token_program_info = next_account_info(account_info_iter)?;
+            if !spl_token::check_id(token_program_info.key)
+                || sender_token_info.owner != token_program_info.key
+                || recipient_token_info.owner != token_program_info.key
+            {
+                return Err(Sol2SolError::InvalidAccountData.into());
+            }
+            let sender_token = TokenAccount::unpack(&sender_token_info.data.borrow())?;
+            let recipient_token = TokenAccount::unpack(&recipient_token_info.data.borrow())?;
+            if sender_token.mint != sol_box.postage_mint() || recipient_token.mint != sol_box.postage_mint() {
+                return Err(Sol2SolError::PostageMintMismatch.into());
+            }
+            if recipient_token.owner != *recipient {
+                return Err(Sol2SolError::OwnerMismatch.into());
+            }
+            if sender_token.amount < sol_box.postage_token_amount() {
+                return Err(Sol2SolError::InsufficientFunds.into());
+            }
+            Some((sender_token_info, recipient_token_info, token_program_info, sol_box.postage_token_amount()))
+        } else {
+            None
+        };
+        if *reply_to == Pubkey::default() {
+            if *thread_root != Pubkey::default() {
+                return Err(Sol2SolError::InvalidThreadReference.into());
+            }
+        } else {
+            msg!("Checking reply points to an existing message in its thread");
+            let parent_info = next_account_info(account_info_iter)?;
+            if parent_info.key != reply_to || parent_info.owner != program_id {
+                return Err(Sol2SolError::InvalidThreadReference.into());
+            }
+            let parent = Message::unpack(&parent_info.data.borrow())
+                .map_err(|_| Sol2SolError::InvalidThreadReference)?;
+            if parent.reply_thread_root(reply_to) != *thread_root {
+                return Err(Sol2SolError::InvalidThreadReference.into());
+            }
+        }
+        let postage_lamports = if charges_postage { sol_box.postage_lamports() } else { 0 };
+        let message_len = Message::get_packed_len(msg_size);
+        let message_lamports = rent.minimum_balance(message_len);
+        msg!("Checking sender can pay postage and rent");
+        if payer_info.lamports() < postage_lamports.saturating_add(message_lamports) {
+            return Err(Sol2SolError::InsufficientFunds.into());
+        }
 
         msg!("Writing to sol box");
-        SolBox::add_message_to_sol_box(&mut sol_box.message_slots, &message_account_info.key)?;
-        SolBox::pack(sol_box, &mut sol_box_info.data.borrow_mut())?;
+        let message_seq = sol_box.message_seq();
+        drop(sol_box);
+        let mut sol_box = SolBoxView::from_account_info_mut(sol_box_info)?;
+        let slot = sol_box.add_message(message_account_info.key)?;
+        sol_box.set_message_seq(message_seq
+            .checked_add(1)
+            .ok_or(Sol2SolError::SolBoxNoSpaceLeft)?);
+        // Release the box before calling other programs
+        drop(sol_box);
+        let message_seq_bytes = message_seq.to_le_bytes();
+
+        if postage_lamports > 0 {
+            msg!("Paying postage to sol box owner");
+            invoke(
+                &system_instruction::transfer(payer_info.key, recipient_info.key, postage_lamports),
+                &[payer_info.clone(), recipient_info.clone(), system_program_info.clone()],
+            )?;
+        }
+
+        if let Some((sender_token_info, recipient_token_info, token_program_info, token_amount)) = token_postage_infos {
+            msg!("Paying token postage to sol box owner");
+            invoke(
+                &spl_token::instruction::transfer(
+                    token_program_info.key,
+                    sender_token_info.key,
+                    recipient_token_info.key,
+                    payer_info.key,
+                    &[],
+                    token_amount,
+                )?,
+                &[
+                    sender_token_info.clone(),
+                    recipient_token_info.clone(),
+                    payer_info.clone(),
+                    token_program_info.clone(),
+                ],
+            )?;
+        }
+
+        msg!("Creating message account");
+        let message_signer_seeds: &[&[u8]] = &[
+            MESSAGE_SEED,
+            sol_box_info.key.as_ref(),
+            &message_seq_bytes,
+            &[bump_seed],
+        ];
+        Self::create_pda_account(
+            payer_info,
+            message_account_info,
+            system_program_info,
+            message_lamports,
+            message_len,
+            program_id,
+            message_signer_seeds,
+        )?;
+
+        msg!("Writing to message state");
+        let message = if is_anonymous {
+            Message::new_anonymous(*recipient, *sender, msg_data.to_vec())
+        } else {
+            Message::new_reply(*recipient, *sender, msg_data.to_vec(), *reply_to, *thread_root)
+        };
+        Message::pack(Message { slot, ..message }, &mut message_account_info.data.borrow_mut())?;
+        Sol2SolEvent::MessageWritten {
+            sol_box: *sol_box_info.key,
+            message: *message_account_info.key,
+            recipient: *recipient,
+            sender: *sender,
+            slot,
+            message_seq,
+            msg_size,
+            is_anonymous,
+            reply_to: *reply_to,
+            thread_root: *thread_root,
+        }.emit();
 
         msg!("Writing message succeeded!");
         Ok(())
     }
 
+    fn process_reply_anonymous<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        message_pubkey: &'a Pubkey,
+        msg_data: &[u8],
+    ) -> ProgramResult {
+        msg!("[process]replying to anonymous message");
+        let account_info_iter = &mut accounts.iter();
+        let message_account_info = next_account_info(account_info_iter)?;
+        let sol_box_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let reply_account_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that recipient signed and is paying");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !system_program::check_id(system_program_info.key) {
+            return Err(Sol2SolError::IncorrectSystemProgramAddress.into());
+        }
+        let msg_size = msg_data.len() as u32;
+        msg!("Checking message was sent anonymously to a box of the signer");
+        if message_account_info.key != message_pubkey || message_account_info.owner != program_id {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let message = Message::unpack(&message_account_info.data.borrow())?;
+        if !message.is_anonymous {
+            return Err(Sol2SolError::MessageNotAnonymous.into());
+        }
+        // The box owner, not `message.recipient`, since boxes change hands
+        if sol_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        let sol_box = SolBoxView::from_account_info(sol_box_info)?;
+        if !sol_box.holds(message.slot, message_pubkey) {
+            return Err(Sol2SolError::MessageNotInSolBox.into());
+        }
+        if sol_box.owner() != *owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        drop(sol_box);
+        msg!("Checking reply account is the reply token's reply address");
+        let (reply_address, bump_seed) = find_reply_address(program_id, &message.sender);
+        if *reply_account_info.key != reply_address {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        if reply_account_info.owner != &system_program::id() || reply_account_info.data_len() != 0 {
+            return Err(Sol2SolError::MessageAccountInUse.into());
+        }
+
+        msg!("Creating reply account");
+        let reply_len = Message::get_packed_len(msg_size);
+        let reply_signer_seeds: &[&[u8]] = &[
+            REPLY_SEED,
+            message.sender.as_ref(),
+            &[bump_seed],
+        ];
+        Self::create_pda_account(
+            owner_info,
+            reply_account_info,
+            system_program_info,
+            rent.minimum_balance(reply_len),
+            reply_len,
+            program_id,
+            reply_signer_seeds,
+        )?;
+        let reply = Message::new(message.sender, *owner, msg_data.to_vec());
+        Message::pack(reply, &mut reply_account_info.data.borrow_mut())?;
+        Sol2SolEvent::ReplyWritten {
+            reply: *reply_account_info.key,
+            message: *message_pubkey,
+            reply_token: message.sender,
+        }.emit();
+
+        msg!("Replying succeeded!");
+        Ok(())
+    }
+
+    fn process_delete_reply<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        reply_token: &'a Pubkey,
+    ) -> ProgramResult {
+        msg!("[process]deleting reply");
+        let account_info_iter = &mut accounts.iter();
+        let reply_account_info = next_account_info(account_info_iter)?;
+        let reply_token_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that the reply token signed");
+        if reply_token_info.key != reply_token {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !reply_token_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        msg!("Checking reply account belongs to the reply token");
+        let (reply_address, _) = find_reply_address(program_id, reply_token);
+        if *reply_account_info.key != reply_address || reply_account_info.owner != program_id {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        if Message::unpack(&reply_account_info.data.borrow())?.recipient != *reply_token {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+
+        Self::close_account(reply_account_info, destination_info)?;
+        Sol2SolEvent::ReplyDeleted {
+            reply: *reply_account_info.key,
+            reply_token: *reply_token,
+        }.emit();
+
+        msg!("Deleting reply succeeded!");
+        Ok(())
+    }
+
     fn process_delete_message<'a>(
         program_id: &'a Pubkey,
         accounts: &'a [AccountInfo],
         owner: &'a Pubkey,
-        recipient: &'a Pubkey,
+        message_id: &'a Pubkey,
         sol_box_id: &'a Pubkey,
     ) -> ProgramResult {
         msg!("[process]deleting message");
+        let account_info_iter = &mut accounts.iter();
+        let message_account_info = next_account_info(account_info_iter)?;
+        let sol_box_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking message account id matches");
+        if message_account_info.key != message_id {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        msg!("Checking owner of message field matches program id");
+        if message_account_info.owner != program_id {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        msg!("Checking that sol box pubkey matches instruction pubkey");
+        if sol_box_info.key != sol_box_id {
+            return Err(Sol2SolError::IncorrectSolBox.into())
+        }
+        msg!("Checking owner of sol box field matches program id");
+        if sol_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let mut sol_box = SolBoxView::from_account_info_mut(sol_box_info)?;
+        if sol_box.owner() != *owner_info.key {
+            return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+        }
+        if destination_info.key == message_account_info.key || sol_box_info.key == message_account_info.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        msg!("Removing message from sol box");
+        let message = Message::unpack(&message_account_info.data.borrow())?;
+        sol_box.remove_message(message.slot, message_account_info.key)?;
+        drop(sol_box);
+
+        msg!("Zeroing message state & reclaiming lamports");
+        Self::close_account(message_account_info, destination_info)?;
+        Sol2SolEvent::MessageDeleted {
+            sol_box: *sol_box_info.key,
+            message: *message_account_info.key,
+        }.emit();
+
+        msg!("Deleting message succeeded!");
         Ok(())
     }
+
+    fn process_close_sol_box<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        cascade: bool,
+    ) -> ProgramResult {
+        msg!("[process]closing sol box");
+        let account_info_iter = &mut accounts.iter();
+        let sol_box_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let destination_info = next_account_info(account_info_iter)?;
+        let prev_box_info = next_account_info(account_info_iter)?;
+        let next_box_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        msg!("Checking owner of sol box field matches program id");
+        if sol_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        let sol_box = SolBox::unpack(&sol_box_info.data.borrow())?;
+        if sol_box.owner != *owner {
+            return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+        }
+        msg!("Checking neighbors match the sol box links");
+        if *prev_box_info.key != sol_box.prev_box || *next_box_info.key != sol_box.next_box {
+            return Err(Sol2SolError::IncorrectSolBox.into());
+        }
+        let is_root = sol_box.prev_box == *sol_box_info.key;
+        let is_tail = sol_box.next_box == *sol_box_info.key;
+        if is_root && !is_tail {
+            return Err(Sol2SolError::SolBoxIsChainRoot.into());
+        }
+        if destination_info.key == sol_box_info.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let prev_box = if is_root {
+            None
+        } else {
+            Some(Self::unpack_neighbor(program_id, prev_box_info, owner, |prev_box| prev_box.next_box == *sol_box_info.key)?)
+        };
+        let next_box = if is_tail {
+            None
+        } else {
+            Some(Self::unpack_neighbor(program_id, next_box_info, owner, |next_box| next_box.prev_box == *sol_box_info.key)?)
+        };
+
+        msg!("Checking sol box messages");
+        let stored_messages = sol_box.stored_messages();
+        if !cascade && !stored_messages.is_empty() {
+            return Err(Sol2SolError::SolBoxNotEmpty.into());
+        }
+        let message_infos: Vec<&AccountInfo> = account_info_iter.collect();
+        if message_infos.len() != stored_messages.len() {
+            return Err(Sol2SolError::SolBoxNotEmpty.into());
+        }
+        for (i, message_account_info) in message_infos.iter().enumerate() {
+            if !stored_messages.contains(message_account_info.key) {
+                return Err(Sol2SolError::MessageNotInSolBox.into());
+            }
+            if message_infos[..i].iter().any(|other| other.key == message_account_info.key) {
+                return Err(Sol2SolError::SolBoxNotEmpty.into());
+            }
+            if message_account_info.owner != program_id {
+                return Err(Sol2SolError::OwnerMismatch.into());
+            }
+            if message_account_info.key == destination_info.key {
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+
+        msg!("Unlinking sol box from its chain");
+        if let Some(mut prev_box) = prev_box {
+            prev_box.next_box = if is_tail { *prev_box_info.key } else { *next_box_info.key };
+            SolBox::pack(prev_box, &mut prev_box_info.data.borrow_mut())?;
+        }
+        if let Some(mut next_box) = next_box {
+            next_box.prev_box = *prev_box_info.key;
+            SolBox::pack(next_box, &mut next_box_info.data.borrow_mut())?;
+        }
+
+        msg!("Deleting stored messages");
+        for message_account_info in message_infos {
+            Self::close_account(message_account_info, destination_info)?;
+            Sol2SolEvent::MessageDeleted {
+                sol_box: *sol_box_info.key,
+                message: *message_account_info.key,
+            }.emit();
+        }
+
+        msg!("Zeroing sol box state & reclaiming lamports");
+        Self::close_account(sol_box_info, destination_info)?;
+        Sol2SolEvent::BoxClosed {
+            sol_box: *sol_box_info.key,
+            owner: *owner,
+        }.emit();
+
+        msg!("Closing sol box succeeded!");
+        Ok(())
+    }
+
+    /// Unpacks a neighbor of a box being unlinked, checking it is a box of
+    /// `owner` whose link back passes `links_back`
+    fn unpack_neighbor<F: FnOnce(&SolBox) -> bool>(
+        program_id: &Pubkey,
+        neighbor_info: &AccountInfo,
+        owner: &Pubkey,
+        links_back: F,
+    ) -> Result<SolBox, ProgramError> {
+        if neighbor_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        let neighbor = SolBox::unpack(&neighbor_info.data.borrow())?;
+        if neighbor.owner != *owner {
+            return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+        }
+        if !links_back(&neighbor) {
+            return Err(Sol2SolError::InvalidSolBoxLink.into());
+        }
+        Ok(neighbor)
+    }
+
+    fn process_transfer_sol_box_ownership<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        new_owner: &'a Pubkey,
+        two_step: bool,
+        index: u32,
+    ) -> ProgramResult {
+        msg!("[process]transferring sol box ownership");
+        let account_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if *new_owner == Pubkey::default() {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if two_step {
+            msg!("Recording pending owner on the root box");
+            let root_info = next_account_info(account_info_iter)?;
+            if root_info.owner != program_id {
+                return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+            }
+            let mut root = SolBox::unpack(&root_info.data.borrow())?;
+            if root.owner != *owner {
+                return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+            }
+            if root.prev_box != *root_info.key {
+                return Err(Sol2SolError::IncorrectSolBox.into());
+            }
+            root.pending_owner = if new_owner == owner { Pubkey::default() } else { *new_owner };
+            let pending_owner = root.pending_owner;
+            SolBox::pack(root, &mut root_info.data.borrow_mut())?;
+            Sol2SolEvent::OwnershipTransferPending {
+                sol_box: *root_info.key,
+                owner: *owner,
+                pending_owner,
+            }.emit();
+        } else {
+            msg!("Moving chain to new owner");
+            let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+            let system_program_info = next_account_info(account_info_iter)?;
+            let new_owner_tail_info = if index > 0 { Some(next_account_info(account_info_iter)?) } else { None };
+            let box_infos: Vec<&AccountInfo> = account_info_iter.collect();
+            Self::move_chain(
+                program_id,
+                owner_info,
+                system_program_info,
+                rent,
+                new_owner_tail_info,
+                &box_infos,
+                index,
+                owner,
+                new_owner,
+            )?;
+        }
+
+        msg!("Transferring sol box ownership succeeded!");
+        Ok(())
+    }
+
+    fn process_accept_sol_box_ownership<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        new_owner: &'a Pubkey,
+        index: u32,
+    ) -> ProgramResult {
+        msg!("[process]accepting sol box ownership");
+        let account_info_iter = &mut accounts.iter();
+        let new_owner_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that pending owner signed");
+        if new_owner_info.key != new_owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !new_owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let new_owner_tail_info = if index > 0 { Some(next_account_info(account_info_iter)?) } else { None };
+        let box_infos: Vec<&AccountInfo> = account_info_iter.collect();
+        let root_info = box_infos.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+        if root_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        let root = SolBox::unpack(&root_info.data.borrow())?;
+        if root.pending_owner != *new_owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+
+        msg!("Moving chain to new owner");
+        Self::move_chain(
+            program_id,
+            new_owner_info,
+            system_program_info,
+            rent,
+            new_owner_tail_info,
+            &box_infos,
+            index,
+            &root.owner,
+            new_owner,
+        )?;
+
+        msg!("Accepting sol box ownership succeeded!");
+        Ok(())
+    }
+
+    /// Moves a complete chain to `new_owner`'s derived addresses
+    ///
+    /// `box_infos` lists the chain root to tail, then the new owner's boxes
+    /// from `index` on, one per box of the chain. A new owner who already
+    /// has an inbox passes its tail as `new_owner_tail_info`, and the chain
+    /// is linked after it. `payer_info` funds the new accounts and gets the
+    /// lamports of the old ones, which are closed.
+    #[allow(clippy::too_many_arguments)]
+    fn move_chain<'a>(
+        program_id: &Pubkey,
+        payer_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        rent: &Rent,
+        new_owner_tail_info: Option<&AccountInfo<'a>>,
+        box_infos: &[&AccountInfo<'a>],
+        index: u32,
+        owner: &Pubkey,
+        new_owner: &Pubkey,
+    ) -> ProgramResult {
+        if !system_program::check_id(system_program_info.key) {
+            return Err(Sol2SolError::IncorrectSystemProgramAddress.into());
+        }
+        if new_owner == owner {
+            return Err(ProgramError::InvalidArgument);
+        }
+        let (chain_infos, new_infos) = box_infos.split_at(box_infos.len() / 2);
+        if chain_infos.is_empty() || chain_infos.len() != new_infos.len() {
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+        let new_owner_tail = match new_owner_tail_info {
+            Some(tail_info) => {
+                msg!("Checking the new owner's tail box");
+                if tail_info.owner != program_id {
+                    return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+                }
+                let tail = SolBox::unpack(&tail_info.data.borrow())?;
+                if tail.owner != *new_owner {
+                    return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+                }
+                if tail.next_box != *tail_info.key {
+                    return Err(Sol2SolError::SolBoxNotChainTail.into());
+                }
+                Some(tail)
+            }
+            None => None,
+        };
+        let new_root_prev = new_owner_tail_info.map_or(new_infos[0].key, |tail_info| tail_info.key);
+        let mut sol_boxes = Vec::with_capacity(chain_infos.len());
+        for (i, sol_box_info) in chain_infos.iter().enumerate() {
+            if sol_box_info.owner != program_id {
+                return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+            }
+            let mut sol_box = SolBox::unpack(&sol_box_info.data.borrow())?;
+            if sol_box.owner != *owner {
+                return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+            }
+            let expected_prev = if i == 0 { sol_box_info.key } else { chain_infos[i - 1].key };
+            if sol_box.prev_box != *expected_prev {
+                return Err(Sol2SolError::IncorrectSolBox.into());
+            }
+            let expected_next = chain_infos.get(i + 1).map_or(sol_box_info.key, |info| info.key);
+            if sol_box.next_box != *expected_next {
+                return Err(Sol2SolError::SolBoxNotChainTail.into());
+            }
+            sol_box.owner = *new_owner;
+            sol_box.pending_owner = Pubkey::default();
+            sol_box.prev_box = if i == 0 { *new_root_prev } else { *new_infos[i - 1].key };
+            sol_box.next_box = *new_infos.get(i + 1).unwrap_or(&new_infos[i]).key;
+            sol_boxes.push(sol_box);
+        }
+
+        for (i, (new_box_info, sol_box)) in new_infos.iter().zip(sol_boxes.iter()).enumerate() {
+            msg!("Checking new box lives at the new owner's derived address");
+            let index = index.checked_add(i as u32).ok_or(Sol2SolError::IncorrectSolBox)?;
+            let (new_box_address, bump_seed) = find_sol_box_address(program_id, new_owner, index);
+            if new_box_address != *new_box_info.key {
+                return Err(Sol2SolError::IncorrectSolBox.into());
+            }
+            if new_box_info.owner != &system_program::id() || new_box_info.data_len() != 0 {
+                return Err(Sol2SolError::SolBoxAlreadyInUse.into());
+            }
+
+            msg!("Creating box at its new address");
+            let sol_box_data_len = SolBox::get_packed_len(sol_box.num_spots);
+            let index_bytes = index.to_le_bytes();
+            let sol_box_signer_seeds: &[&[u8]] = &[
+                SOL_BOX_SEED,
+                new_owner.as_ref(),
+                &index_bytes,
+                &[bump_seed],
+            ];
+            Self::create_pda_account(
+                payer_info,
+                new_box_info,
+                system_program_info,
+                rent.minimum_balance(sol_box_data_len),
+                sol_box_data_len,
+                program_id,
+                sol_box_signer_seeds,
+            )?;
+        }
+
+        // Lamports only move once every account has been created
+        msg!("Moving boxes & closing their old accounts");
+        let moves = chain_infos.iter().zip(new_infos).zip(sol_boxes);
+        for (index, ((sol_box_info, new_box_info), sol_box)) in (index..).zip(moves) {
+            let prev_box = sol_box.prev_box;
+            SolBox::pack(sol_box, &mut new_box_info.data.borrow_mut())?;
+            Self::close_account(sol_box_info, payer_info)?;
+            Sol2SolEvent::BoxMoved {
+                sol_box: *sol_box_info.key,
+                new_sol_box: *new_box_info.key,
+                prev_box,
+                index,
+            }.emit();
+        }
+        if let (Some(tail_info), Some(mut tail)) = (new_owner_tail_info, new_owner_tail) {
+            msg!("Linking moved boxes after the new owner's tail");
+            tail.next_box = *new_infos[0].key;
+            SolBox::pack(tail, &mut tail_info.data.borrow_mut())?;
+        }
+        Sol2SolEvent::OwnershipTransferred {
+            sol_box: *new_infos[0].key,
+            owner: *owner,
+            new_owner: *new_owner,
+        }.emit();
+        Ok(())
+    }
+
+    fn process_init_access_list<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        mode: AccessListMode,
+        capacity: u32,
+    ) -> ProgramResult {
+        msg!("[process]initializing access list");
+        let account_info_iter = &mut accounts.iter();
+        let access_list_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let sol_box_infos: Vec<&AccountInfo> = account_info_iter.collect();
+
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !system_program::check_id(system_program_info.key) {
+            return Err(Sol2SolError::IncorrectSystemProgramAddress.into());
+        }
+        msg!("Checking access list address");
+        let first_box_info = sol_box_infos.first().ok_or(ProgramError::NotEnoughAccountKeys)?;
+        let (access_list_address, bump_seed) = find_access_list_address(program_id, first_box_info.key);
+        if access_list_address != *access_list_info.key {
+            return Err(Sol2SolError::IncorrectAccessList.into());
+        }
+        if access_list_info.owner != &system_program::id() || access_list_info.data_len() != 0 {
+            return Err(Sol2SolError::IncorrectAccessList.into());
+        }
+
+        msg!("Linking access list from sol boxes");
+        for sol_box_info in sol_box_infos.iter() {
+            if sol_box_info.owner != program_id {
+                return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+            }
+            let mut sol_box = SolBox::unpack(&sol_box_info.data.borrow())?;
+            if sol_box.owner != *owner {
+                return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+            }
+            if sol_box.access_list != Pubkey::default() {
+                return Err(Sol2SolError::AccessListAlreadyLinked.into());
+            }
+            sol_box.access_list = *access_list_info.key;
+            SolBox::pack(sol_box, &mut sol_box_info.data.borrow_mut())?;
+        }
+
+        msg!("Creating access list account");
+        let access_list_len = AccessList::get_packed_len(capacity);
+        let access_list_signer_seeds: &[&[u8]] = &[
+            ACCESS_LIST_SEED,
+            first_box_info.key.as_ref(),
+            &[bump_seed],
+        ];
+        Self::create_pda_account(
+            owner_info,
+            access_list_info,
+            system_program_info,
+            rent.minimum_balance(access_list_len),
+            access_list_len,
+            program_id,
+            access_list_signer_seeds,
+        )?;
+        let access_list = AccessList {
+            sol_box: *first_box_info.key,
+            mode,
+            is_initialized: true,
+            capacity,
+            entries: vec![],
+        };
+        AccessList::pack(access_list, &mut access_list_info.data.borrow_mut())?;
+        Sol2SolEvent::AccessListInitialized {
+            access_list: *access_list_info.key,
+            sol_box: *first_box_info.key,
+            owner: *owner,
+            mode,
+            capacity,
+        }.emit();
+        for sol_box_info in &sol_box_infos[1..] {
+            Sol2SolEvent::AccessListLinked {
+                access_list: *access_list_info.key,
+                sol_box: *sol_box_info.key,
+            }.emit();
+        }
+
+        msg!("Initializing access list succeeded!");
+        Ok(())
+    }
+
+    /// Applies `update` to the access list of a box signed for by its owner,
+    /// then emits the event `event` builds from the list's address
+    fn process_update_access_list<'a, F, E>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        update: F,
+        event: E,
+    ) -> ProgramResult
+    where
+        F: FnOnce(&mut AccessList) -> ProgramResult,
+        E: FnOnce(Pubkey) -> Sol2SolEvent,
+    {
+        msg!("[process]updating access list");
+        let account_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(account_info_iter)?;
+        let sol_box_info = next_account_info(account_info_iter)?;
+        let access_list_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if sol_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        let sol_box = SolBox::unpack(&sol_box_info.data.borrow())?;
+        if sol_box.owner != *owner {
+            return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+        }
+        msg!("Checking access list is linked from the sol box");
+        if sol_box.access_list != *access_list_info.key || access_list_info.owner != program_id {
+            return Err(Sol2SolError::IncorrectAccessList.into());
+        }
+
+        let mut access_list = AccessList::unpack(&access_list_info.data.borrow())?;
+        update(&mut access_list)?;
+        AccessList::pack(access_list, &mut access_list_info.data.borrow_mut())?;
+        event(*access_list_info.key).emit();
+
+        msg!("Updating access list succeeded!");
+        Ok(())
+    }
+
+    fn process_mark_messages<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        set_flags: u8,
+        clear_flags: u8,
+    ) -> ProgramResult {
+        msg!("[process]marking messages");
+        let account_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(account_info_iter)?;
+        let sol_box_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking flags");
+        if (set_flags | clear_flags) & !MESSAGE_STATUS_ALL != 0 || set_flags & clear_flags != 0 {
+            return Err(Sol2SolError::InvalidInstructionData.into());
+        }
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if sol_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        let sol_box = SolBoxView::from_account_info(sol_box_info)?;
+        if sol_box.owner() != *owner {
+            return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+        }
+
+        for message_account_info in account_info_iter {
+            if message_account_info.owner != program_id {
+                return Err(Sol2SolError::MessageNotInSolBox.into());
+            }
+            let mut message = Message::unpack(&message_account_info.data.borrow())
+                .map_err(|_| Sol2SolError::MessageNotInSolBox)?;
+            if !sol_box.holds(message.slot, message_account_info.key) {
+                return Err(Sol2SolError::MessageNotInSolBox.into());
+            }
+            let first_read = message.status & MESSAGE_STATUS_READ == 0 && set_flags & MESSAGE_STATUS_READ != 0;
+            message.status = (message.status | set_flags) & !clear_flags;
+            if first_read && sol_box.read_receipts() {
+                Sol2SolEvent::ReadReceipt {
+                    message: *message_account_info.key,
+                    sender: message.sender,
+                    reader: *owner,
+                }.emit();
+            }
+            let status = message.status;
+            Message::pack(message, &mut message_account_info.data.borrow_mut())?;
+            Sol2SolEvent::MessageStatusChanged {
+                sol_box: *sol_box_info.key,
+                message: *message_account_info.key,
+                status,
+            }.emit();
+        }
+
+        msg!("Marking messages succeeded!");
+        Ok(())
+    }
+
+    fn process_set_message_expiry<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        authority: &'a Pubkey,
+        expiry: MessageExpiry,
+    ) -> ProgramResult {
+        msg!("[process]setting message expiry");
+        let account_info_iter = &mut accounts.iter();
+        let authority_info = next_account_info(account_info_iter)?;
+        let sol_box_info = next_account_info(account_info_iter)?;
+        let message_account_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that the authority signed");
+        if authority_info.key != authority {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !authority_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        msg!("Checking message is stored in the sol box");
+        if sol_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        let sol_box = SolBoxView::from_account_info(sol_box_info)?;
+        if message_account_info.owner != program_id {
+            return Err(Sol2SolError::MessageNotInSolBox.into());
+        }
+        let mut message = Message::unpack(&message_account_info.data.borrow())
+            .map_err(|_| Sol2SolError::MessageNotInSolBox)?;
+        if !sol_box.holds(message.slot, message_account_info.key) {
+            return Err(Sol2SolError::MessageNotInSolBox.into());
+        }
+        msg!("Checking authority is the sender or the sol box owner");
+        if message.sender != *authority && sol_box.owner() != *authority {
+            return Err(Sol2SolError::SenderMismatch.into());
+        }
+        if message.is_anonymous {
+            return Err(Sol2SolError::MessageIsAnonymous.into());
+        }
+
+        message.expiry = expiry;
+        Message::pack(message, &mut message_account_info.data.borrow_mut())?;
+        Sol2SolEvent::MessageExpirySet {
+            sol_box: *sol_box_info.key,
+            message: *message_account_info.key,
+            expiry,
+        }.emit();
+
+        msg!("Setting message expiry succeeded!");
+        Ok(())
+    }
+
+    fn process_purge_expired<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+    ) -> ProgramResult {
+        msg!("[process]purging expired message");
+        let account_info_iter = &mut accounts.iter();
+        let message_account_info = next_account_info(account_info_iter)?;
+        let sol_box_info = next_account_info(account_info_iter)?;
+        let caller_info = next_account_info(account_info_iter)?;
+        let rent_payer_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+
+        msg!("Checking message is stored in the sol box");
+        if sol_box_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        if message_account_info.owner != program_id {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        let message = Message::unpack(&message_account_info.data.borrow())?;
+        let mut sol_box = SolBoxView::from_account_info_mut(sol_box_info)?;
+        msg!("Checking message has expired");
+        if !message.expiry.has_passed(clock) {
+            return Err(Sol2SolError::MessageNotExpired.into());
+        }
+        msg!("Checking rent goes back to the sender, who paid it");
+        if message.is_anonymous {
+            return Err(Sol2SolError::MessageIsAnonymous.into());
+        }
+        if *rent_payer_info.key != message.sender {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        if caller_info.key == message_account_info.key || rent_payer_info.key == message_account_info.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        msg!("Removing message from sol box");
+        sol_box.remove_message(message.slot, message_account_info.key)?;
+        drop(sol_box);
+
+        msg!("Paying bounty & returning rent");
+        let bounty = message_account_info.lamports().min(PURGE_BOUNTY_LAMPORTS);
+        **message_account_info.lamports.borrow_mut() -= bounty;
+        let caller_starting_lamports = caller_info.lamports();
+        **caller_info.lamports.borrow_mut() = caller_starting_lamports
+            .checked_add(bounty)
+            .ok_or(ProgramError::InvalidArgument)?;
+        Self::close_account(message_account_info, rent_payer_info)?;
+        Sol2SolEvent::MessagePurged {
+            sol_box: *sol_box_info.key,
+            message: *message_account_info.key,
+            caller: *caller_info.key,
+        }.emit();
+
+        msg!("Purging expired message succeeded!");
+        Ok(())
+    }
+
+    fn process_migrate_account<'a>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+    ) -> ProgramResult {
+        msg!("[process]migrating account");
+        let account_info_iter = &mut accounts.iter();
+        let account_info = next_account_info(account_info_iter)?;
+        let payer_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking account is owned by the program");
+        if account_info.owner != program_id {
+            return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+        }
+        if !payer_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        if !system_program::check_id(system_program_info.key) {
+            return Err(Sol2SolError::IncorrectSystemProgramAddress.into());
+        }
+
+        let (tag, version) = unpack_account_prefix(&account_info.data.borrow())?;
+        if version == ACCOUNT_VERSION {
+            msg!("Account already uses the current layout");
+            return Ok(());
+        }
+        match tag {
+            SOL_BOX_TAG => {
+                let sol_box = SolBox::unpack(&account_info.data.borrow())?;
+                let len = SolBox::get_packed_len(sol_box.num_spots);
+                Self::resize_account(account_info, payer_info, system_program_info, rent, len)?;
+                sol_box.pack_into_slice(&mut account_info.data.borrow_mut());
+            },
+            MESSAGE_TAG => {
+                let mut message = Message::unpack(&account_info.data.borrow())?;
+                if message.slot == MESSAGE_SLOT_UNKNOWN {
+                    // Version 0 messages didn't record their slot, so it is
+                    // looked up in the box holding the message. Its owner is
+                    // the recipient now, even if the box changed hands
+                    let sol_box_info = next_account_info(account_info_iter)?;
+                    if sol_box_info.owner != program_id {
+                        return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+                    }
+                    let sol_box = SolBox::unpack(&sol_box_info.data.borrow())?;
+                    message.recipient = sol_box.owner;
+                    message.slot = sol_box
+                        .message_slots
+                        .iter()
+                        .position(|message_slot| *message_slot == Some(*account_info.key))
+                        .ok_or(Sol2SolError::MessageNotInSolBox)? as u32;
+                }
+                let len = Message::get_packed_len(message.msg_size);
+                Self::resize_account(account_info, payer_info, system_program_info, rent, len)?;
+                message.pack_into_slice(&mut account_info.data.borrow_mut());
+            },
+            ACCESS_LIST_TAG => {
+                let access_list = AccessList::unpack(&account_info.data.borrow())?;
+                let len = AccessList::get_packed_len(access_list.capacity);
+                Self::resize_account(account_info, payer_info, system_program_info, rent, len)?;
+                access_list.pack_into_slice(&mut account_info.data.borrow_mut());
+            },
+            _ => return Err(ProgramError::InvalidAccountData),
+        }
+        Sol2SolEvent::AccountMigrated {
+            account: *account_info.key,
+            tag,
+            from_version: version,
+        }.emit();
+
+        msg!("Migrating account succeeded!");
+        Ok(())
+    }
+
+    /// Resizes a program account to `len`, topping up rent from `payer_info`.
+    /// Growth past what one realloc allows is rejected rather than chunked
+    fn resize_account<'a>(
+        account_info: &AccountInfo<'a>,
+        payer_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        rent: &Rent,
+        len: usize,
+    ) -> ProgramResult {
+        if len.saturating_sub(account_info.data_len()) > MAX_PERMITTED_DATA_INCREASE {
+            return Err(Sol2SolError::AccountResizeTooLarge.into());
+        }
+        let top_up = rent.minimum_balance(len).saturating_sub(account_info.lamports());
+        if top_up > 0 {
+            invoke(
+                &system_instruction::transfer(payer_info.key, account_info.key, top_up),
+                &[payer_info.clone(), account_info.clone(), system_program_info.clone()],
+            )?;
+        }
+        account_info.realloc(len, false)
+    }
+
+    /// Applies `update` to every listed box signed for by its owner, emitting
+    /// the event `event` builds from each box's address
+    fn process_update_sol_boxes<'a, F, E>(
+        program_id: &'a Pubkey,
+        accounts: &'a [AccountInfo],
+        owner: &'a Pubkey,
+        update: F,
+        event: E,
+    ) -> ProgramResult
+    where
+        F: Fn(&mut SolBox),
+        E: Fn(Pubkey) -> Sol2SolEvent,
+    {
+        msg!("[process]updating sol box settings");
+        let account_info_iter = &mut accounts.iter();
+        let owner_info = next_account_info(account_info_iter)?;
+
+        msg!("Checking that sol box owner signed");
+        if owner_info.key != owner {
+            return Err(Sol2SolError::OwnerMismatch.into());
+        }
+        if !owner_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        for sol_box_info in account_info_iter {
+            if sol_box_info.owner != program_id {
+                return Err(Sol2SolError::SolBoxSystemOwnerMismatch.into());
+            }
+            let mut sol_box = SolBox::unpack(&sol_box_info.data.borrow())?;
+            if sol_box.owner != *owner {
+                return Err(Sol2SolError::SolBoxUserOwnerMismatch.into());
+            }
+            update(&mut sol_box);
+            SolBox::pack(sol_box, &mut sol_box_info.data.borrow_mut())?;
+            event(*sol_box_info.key).emit();
+        }
+
+        msg!("Updating sol box settings succeeded!");
+        Ok(())
+    }
+
+    /// Zeroes a program account's data and drains its lamports to `destination_info`
+    fn close_account(account_info: &AccountInfo, destination_info: &AccountInfo) -> ProgramResult {
+        account_info.data.borrow_mut().fill(0);
+        let destination_starting_lamports = destination_info.lamports();
+        **destination_info.lamports.borrow_mut() = destination_starting_lamports
+            .checked_add(account_info.lamports())
+            .ok_or(ProgramError::InvalidArgument)?;
+        **account_info.lamports.borrow_mut() = 0;
+        Ok(())
+    }
+
+    /// Creates a program-owned account at a PDA, paid for by `payer_info`
+    ///
+    /// Lamports already sent to the address are kept, so nobody can block
+    /// creation by funding the account first.
+    fn create_pda_account<'a>(
+        payer_info: &AccountInfo<'a>,
+        new_account_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        required_lamports: u64,
+        space: usize,
+        owner: &Pubkey,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let current_lamports = new_account_info.lamports();
+        if current_lamports == 0 {
+            return invoke_signed(
+                &system_instruction::create_account(
+                    payer_info.key,
+                    new_account_info.key,
+                    required_lamports,
+                    space as u64,
+                    owner,
+                ),
+                &[payer_info.clone(), new_account_info.clone(), system_program_info.clone()],
+                &[signer_seeds],
+            );
+        }
+
+        let top_up = required_lamports.saturating_sub(current_lamports);
+        if top_up > 0 {
+            invoke(
+                &system_instruction::transfer(payer_info.key, new_account_info.key, top_up),
+                &[payer_info.clone(), new_account_info.clone(), system_program_info.clone()],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(new_account_info.key, space as u64),
+            &[new_account_info.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(new_account_info.key, owner),
+            &[new_account_info.clone(), system_program_info.clone()],
+            &[signer_seeds],
+        )
+    }
 }
 
@@ -1,25 +1,99 @@
 //! State for messaging
-//! Todo(ngundotra): design a whitelist system + transferring lamports
 
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use borsh::{BorshDeserialize, BorshSerialize};
 // use num_enum::TryFromPrimitive;
 use solana_program::{
+    account_info::AccountInfo,
+    clock::Clock,
     program_error::ProgramError,
-    program_pack::{IsInitialized, Pack, Sealed},
+    program_pack::IsInitialized,
     pubkey::Pubkey,
-    msg,
+    entrypoint::MAX_PERMITTED_DATA_INCREASE,
+};
+use std::{
+    cell::{Ref, RefMut},
+    convert::TryFrom,
+    ops::{Deref, DerefMut},
 };
-use std::{convert::TryFrom, str::FromStr};
 use crate::error::Sol2SolError;
 
-const NULL_PUBKEY_STR: &'static str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
-/// Hard coded until post-hackathon
-pub const SOL_BOX_NUM_SPOTS: usize = 20;
+/// Set in the tag byte of accounts whose next byte is their layout version
+pub const ACCOUNT_VERSIONED_FLAG: u8 = 0x80;
+/// Layout version written for every account type. Version 0 accounts are
+/// the original layouts, with a bare tag and no version byte: a 20-slot
+/// SolBox without occupancy bitmap or settings, and a Message of just
+/// recipient, sender, size and body. They are read-only until migrated
+pub const ACCOUNT_VERSION: u8 = 1;
+/// Tag of SolBox accounts
+pub const SOL_BOX_TAG: u8 = 0;
+/// Tag of initialized message accounts
+pub const MESSAGE_TAG: u8 = 1;
+/// Tag of access list accounts
+pub const ACCESS_LIST_TAG: u8 = 2;
+
+/// Bytes the tag and version of a versioned account take
+const ACCOUNT_PREFIX_LEN: usize = 2;
+
+/// Reads the tag and layout version of a packed account, erroring on
+/// versions newer than `ACCOUNT_VERSION`
+pub fn unpack_account_prefix(src: &[u8]) -> Result<(u8, u8), ProgramError> {
+    let tag = *src.first().ok_or(Sol2SolError::InvalidAccountData)?;
+    if tag & ACCOUNT_VERSIONED_FLAG == 0 {
+        return Ok((tag, 0));
+    }
+    match src.get(1) {
+        Some(version @ 1..=ACCOUNT_VERSION) => Ok((tag & !ACCOUNT_VERSIONED_FLAG, *version)),
+        _ => Err(Sol2SolError::InvalidAccountData.into()),
+    }
+}
+
+fn pack_account_prefix(tag: u8, dst: &mut [u8]) {
+    dst[0] = tag | ACCOUNT_VERSIONED_FLAG;
+    dst[1] = ACCOUNT_VERSION;
+}
+
+/// What the empty message slots of version 0 boxes hold: the SPL Token program id
+pub const LEGACY_EMPTY_SLOT: Pubkey = spl_token::ID;
+/// Message slots in a version 0 box, which all had the same capacity
+pub const SOL_BOX_V0_NUM_SPOTS: u32 = 20;
+/// Bytes of a version 0 box (tag + owner + next + prev + num_spots + num_in_use + is_initialized + slots)
+pub const SOL_BOX_V0_LEN: usize = 106 + SOL_BOX_V0_NUM_SPOTS as usize * 32;
+/// Capacity used by clients that do not ask for a specific size
+pub const DEFAULT_SOL_BOX_NUM_SPOTS: u32 = 20;
+/// Smallest box worth paying rent for
+pub const MIN_SOL_BOX_NUM_SPOTS: u32 = 5;
+/// Largest box the program can create in one instruction; a CPI
+/// create_account allocates at most 10KB, and each slot costs 32 bytes
+/// plus one bit of occupancy bitmap
+pub const MAX_SOL_BOX_NUM_SPOTS: u32 =
+    ((8 * (MAX_PERMITTED_DATA_INCREASE - SOL_BOX_HEADER_LEN) - 7) / 257) as u32;
+/// Bytes before the occupancy bitmap (tag + version + owner + next + prev + num_spots + num_in_use + is_initialized + message_seq + pending_owner + access_list + postage_lamports + postage_mint + postage_token_amount + encryption_key + read_receipts)
+pub const SOL_BOX_HEADER_LEN: usize = 2 + SOL_BOX_FIELDS_LEN;
+/// Header bytes after the tag and version
+const SOL_BOX_FIELDS_LEN: usize = 258;
+
+// Where `SolBoxView` finds each header field, counted from after the version
+const SOL_BOX_OWNER_OFFSET: usize = 0;
+const SOL_BOX_NUM_SPOTS_OFFSET: usize = 96;
+const SOL_BOX_NUM_IN_USE_OFFSET: usize = 100;
+const SOL_BOX_IS_INITIALIZED_OFFSET: usize = 104;
+const SOL_BOX_MESSAGE_SEQ_OFFSET: usize = 105;
+const SOL_BOX_ACCESS_LIST_OFFSET: usize = 145;
+const SOL_BOX_POSTAGE_LAMPORTS_OFFSET: usize = 177;
+const SOL_BOX_POSTAGE_MINT_OFFSET: usize = 185;
+const SOL_BOX_POSTAGE_TOKEN_AMOUNT_OFFSET: usize = 217;
+const SOL_BOX_ENCRYPTION_KEY_OFFSET: usize = 225;
+const SOL_BOX_READ_RECEIPTS_OFFSET: usize = 257;
 
-// Todo(ngundotra): remove assumption that box size is 20
 /// SolBox
-#[repr(C)]
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
+///
+/// The number of message slots is not fixed: it is recorded in `num_spots`
+/// and must agree with the length of the account holding the box. The fixed
+/// header is followed by a bitmap with one bit per slot, set while the slot
+/// holds a message, and then by the slots themselves. Empty slots are
+/// stored as all-zero bytes.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct SolBox {
     /// Who owns this SolBox
     pub owner: Pubkey,
@@ -29,90 +103,250 @@ pub struct SolBox {
     pub prev_box: Pubkey,
     /// How many messages this box stores
     pub num_spots: u32,
-    /// How many messages have been used
+    /// How many slots hold a message, always the number of bits set in `occupied`
     pub num_in_use: u32,
     /// Has been initialized?
     pub is_initialized: bool,
-    /// The message pubkeys (const # only 40)
-    pub message_slots: [Pubkey; SOL_BOX_NUM_SPOTS],
+    /// Sequence number used to derive the next message delivered here
+    pub message_seq: u64,
+    /// Owner proposed by a two-step transfer, `Pubkey::default()` if none
+    pub pending_owner: Pubkey,
+    /// Access list senders are checked against, `Pubkey::default()` if none
+    pub access_list: Pubkey,
+    /// Lamports a sender pays the owner to deliver a message, 0 for free
+    pub postage_lamports: u64,
+    /// Mint of the token postage, `Pubkey::default()` if none
+    pub postage_mint: Pubkey,
+    /// Tokens of `postage_mint` a sender pays the owner per message
+    pub postage_token_amount: u64,
+    /// x25519 public key messages must be sealed to, all zeros if none
+    pub encryption_key: [u8; 32],
+    /// Log a read receipt when a message is first marked read
+    pub read_receipts: bool,
+    /// Occupancy bitmap, bit `i % 8` of byte `i / 8` set while slot `i` is in use
+    pub occupied: Vec<u8>,
+    /// The message pubkeys, `num_spots` of them, `None` where a slot is empty
+    pub message_slots: Vec<Option<Pubkey>>,
 }
 impl SolBox {
+    /// Account size needed to hold a box with `num_spots` message slots
+    pub fn get_packed_len(num_spots: u32) -> usize {
+        SOL_BOX_HEADER_LEN + Self::get_occupancy_len(num_spots) + num_spots as usize * 32
+    }
+
+    /// Bytes of occupancy bitmap for a box with `num_spots` message slots
+    pub fn get_occupancy_len(num_spots: u32) -> usize {
+        (num_spots as usize).div_ceil(8)
+    }
+
+    /// Number of message slots that fit in an account of `data_len` bytes
+    pub fn num_spots_for_len(data_len: usize) -> Result<u32, ProgramError> {
+        if data_len < SOL_BOX_HEADER_LEN {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        // Every slot takes 257 bits, rounded up to a byte across the bitmap
+        let num_spots = (8 * (data_len - SOL_BOX_HEADER_LEN) / 257) as u32;
+        if Self::get_packed_len(num_spots) != data_len {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        Self::check_num_spots(num_spots)?;
+        Ok(num_spots)
+    }
+
+    /// Errors unless `num_spots` is a supported box capacity
+    pub fn check_num_spots(num_spots: u32) -> Result<(), ProgramError> {
+        if !(MIN_SOL_BOX_NUM_SPOTS..=MAX_SOL_BOX_NUM_SPOTS).contains(&num_spots) {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        Ok(())
+    }
+
     /// Convenience function to initialize the message slots
-    pub fn get_empty_message_slots() -> [Pubkey; SOL_BOX_NUM_SPOTS] {
-        let null_pubkey: Pubkey = Pubkey::from_str(NULL_PUBKEY_STR).unwrap();
-        return [null_pubkey; SOL_BOX_NUM_SPOTS];
-    }
-
-    fn pack_keys_into_ref(message_slots: &[Pubkey; SOL_BOX_NUM_SPOTS], message_slots_dst: &mut [u8]) {
-        // Pack the keys into an array
-        let key_bytes: &mut [u8; SOL_BOX_NUM_SPOTS*32] = &mut [0; SOL_BOX_NUM_SPOTS*32];
-        for i in 0..SOL_BOX_NUM_SPOTS {
-            let bytes = message_slots[i].to_bytes();
-            for j in 0..32 {
-                key_bytes[i*32+j] = bytes[j];
+    pub fn get_empty_message_slots(num_spots: u32) -> Vec<Option<Pubkey>> {
+        vec![None; num_spots as usize]
+    }
+
+    fn pack_keys_into_ref(message_slots: &[Option<Pubkey>], message_slots_dst: &mut [u8]) {
+        for (message_slot, slot_dst) in message_slots.iter().zip(message_slots_dst.chunks_mut(32)) {
+            match message_slot {
+                Some(message_key) => slot_dst.copy_from_slice(message_key.as_ref()),
+                None => slot_dst.fill(0),
             }
         }
-        message_slots_dst.copy_from_slice(key_bytes.as_ref());
     }
 
-    fn get_null_key() -> Pubkey {
-        Pubkey::from_str(NULL_PUBKEY_STR).unwrap()
+    /// Convenience function to initialize the occupancy bitmap
+    pub fn get_empty_occupancy(num_spots: u32) -> Vec<u8> {
+        vec![0; Self::get_occupancy_len(num_spots)]
     }
 
-    /// Adds message to Sol Box if there's enough space
-    pub fn add_message_to_sol_box(message_slots: &mut [Pubkey; SOL_BOX_NUM_SPOTS], message_key: &Pubkey) -> Result<(), ProgramError> {
-        let null_key: Pubkey = Self::get_null_key();
-        for i in 0..SOL_BOX_NUM_SPOTS {
-            let curr_key = message_slots[i];
-            if curr_key == null_key {
-                message_slots[i] = *message_key;
-                return Ok(());
-            }
+    /// Whether any message slot is free
+    pub fn has_space(&self) -> bool {
+        self.num_in_use < self.num_spots
+    }
+
+    /// Whether slot `slot` holds a message
+    pub fn is_occupied(&self, slot: u32) -> bool {
+        slot < self.num_spots && self.occupied[slot as usize / 8] & (1 << (slot % 8)) != 0
+    }
+
+    /// Whether slot `slot` holds `message_key`
+    pub fn holds(&self, slot: u32, message_key: &Pubkey) -> bool {
+        self.is_occupied(slot) && self.message_slots[slot as usize] == Some(*message_key)
+    }
+
+    /// Keys of the messages currently stored in the box
+    pub fn stored_messages(&self) -> Vec<Pubkey> {
+        self.message_slots.iter().flatten().copied().collect()
+    }
+
+    /// Stores `message_key` in the first free slot, returning its index
+    ///
+    /// Only the bitmap is searched, a byte at a time, so the slots
+    /// themselves are never read.
+    pub fn add_message(&mut self, message_key: &Pubkey) -> Result<u32, ProgramError> {
+        if !self.has_space() {
+            return Err(Sol2SolError::SolBoxNoSpaceLeft.into());
+        }
+        let (byte, bits) = self
+            .occupied
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != u8::MAX)
+            .ok_or(Sol2SolError::SolBoxNoSpaceLeft)?;
+        let slot = (byte * 8) as u32 + bits.trailing_ones();
+        if slot >= self.num_spots {
+            return Err(Sol2SolError::SolBoxNoSpaceLeft.into());
         }
-        return Err(Sol2SolError::SolBoxNoSpaceLeft.into());
+        *bits |= 1 << (slot % 8);
+        self.message_slots[slot as usize] = Some(*message_key);
+        self.num_in_use += 1;
+        Ok(slot)
     }
-}
-impl Sealed for SolBox {}
-impl IsInitialized for SolBox {
-    fn is_initialized(&self) -> bool {
-        self.is_initialized
+
+    /// Frees slot `slot`, erroring unless it holds `message_key`
+    pub fn remove_message(&mut self, slot: u32, message_key: &Pubkey) -> Result<(), ProgramError> {
+        if !self.holds(slot, message_key) {
+            return Err(Sol2SolError::MessageNotInSolBox.into());
+        }
+        self.occupied[slot as usize / 8] &= !(1 << (slot % 8));
+        self.message_slots[slot as usize] = None;
+        self.num_in_use -= 1;
+        Ok(())
     }
-}
-impl Pack for SolBox {
-    const LEN: usize = 746; //20*32+32+32+32+4+4+1+1;
-
-    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
-        let src = array_ref![src, 0, SolBox::LEN];
-        let (tag, owner, next_box, prev_box, num_spots, num_in_use, is_initialized, message_slots_src) =
-            array_refs![src, 1, 32, 32, 32, 4, 4, 1, SOL_BOX_NUM_SPOTS*32];
-        let tag = u8::from_le_bytes(*tag);
-        if tag != 0 {
+
+    /// Unpacks an initialized box, sized from the account data
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        let sol_box = Self::unpack_unchecked(src)?;
+        if !sol_box.is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(sol_box)
+    }
+
+    /// Unpacks a box without checking that it has been initialized
+    pub fn unpack_unchecked(src: &[u8]) -> Result<Self, ProgramError> {
+        Self::unpack_from_slice(src)
+    }
+
+    /// Packs `src` into `dst`, which must be exactly as long as the box
+    ///
+    /// Version 0 accounts can't hold the current fields and are rejected,
+    /// they only change layout through `MigrateAccount`.
+    pub fn pack(src: Self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if src.message_slots.len() != src.num_spots as usize
+            || src.occupied.len() != Self::get_occupancy_len(src.num_spots)
+        {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        if dst.len() == SOL_BOX_V0_LEN {
+            return Err(Sol2SolError::AccountNotMigrated.into());
+        }
+        if dst.len() != Self::get_packed_len(src.num_spots) {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        src.pack_into_slice(dst);
+        Ok(())
+    }
+
+    /// Decodes a box of any layout version, whose capacity is given by
+    /// `num_spots` and the length of `src`
+    pub fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let (tag, version) = unpack_account_prefix(src)?;
+        if tag != SOL_BOX_TAG {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        if version == 0 {
+            return Self::unpack_v0(src);
+        }
+        let src = &src[ACCOUNT_PREFIX_LEN..];
+        if src.len() < SOL_BOX_FIELDS_LEN {
             return Err(Sol2SolError::InvalidAccountData.into());
         }
-        let owner = Pubkey::new(owner);
-        let next_box = Pubkey::new(next_box);
-        let prev_box = Pubkey::new(prev_box);
+        let (header_src, message_slots_src) = src.split_at(SOL_BOX_FIELDS_LEN);
+        let header_src = array_ref![header_src, 0, SOL_BOX_FIELDS_LEN];
+        let (
+            owner,
+            next_box,
+            prev_box,
+            num_spots,
+            num_in_use,
+            is_initialized,
+            message_seq,
+            pending_owner,
+            access_list,
+            postage_lamports,
+            postage_mint,
+            postage_token_amount,
+            encryption_key,
+            read_receipts,
+        ) = array_refs![header_src, 32, 32, 32, 4, 4, 1, 8, 32, 32, 8, 32, 8, 32, 1];
+        let owner = Pubkey::new_from_array(*owner);
+        let next_box = Pubkey::new_from_array(*next_box);
+        let prev_box = Pubkey::new_from_array(*prev_box);
 
         let num_spots = u32::from_le_bytes(*num_spots);
-        if usize::try_from(num_spots).unwrap() != SOL_BOX_NUM_SPOTS {
-            return Err(ProgramError::InvalidAccountData)
+        if num_spots != Self::num_spots_for_len(src.len() + ACCOUNT_PREFIX_LEN)? {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
         }
 
+        let (occupied_src, message_slots_src) = message_slots_src.split_at(Self::get_occupancy_len(num_spots));
         let num_in_use = u32::from_le_bytes(*num_in_use);
+        let padding_bits = if num_spots % 8 == 0 { 0 } else { u8::MAX << (num_spots % 8) };
+        if occupied_src.last().is_some_and(|bits| bits & padding_bits != 0)
+            || occupied_src.iter().map(|bits| bits.count_ones()).sum::<u32>() != num_in_use
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
 
         let is_initialized = match is_initialized {
             [0] => false,
             [1] => true,
             _ => return Err(ProgramError::InvalidAccountData)
         };
+        let message_seq = u64::from_le_bytes(*message_seq);
+        let pending_owner = Pubkey::new_from_array(*pending_owner);
+        let access_list = Pubkey::new_from_array(*access_list);
+        let postage_lamports = u64::from_le_bytes(*postage_lamports);
+        let postage_mint = Pubkey::new_from_array(*postage_mint);
+        let postage_token_amount = u64::from_le_bytes(*postage_token_amount);
+        let read_receipts = match read_receipts {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
 
-        let null_pubkey = Self::get_null_key();
-        let message_slots: &mut [Pubkey; SOL_BOX_NUM_SPOTS] = &mut [null_pubkey; SOL_BOX_NUM_SPOTS];
-        let mut i = 0;
-        for chunk in message_slots_src.chunks(32) {
-            let message_pubkey = Pubkey::new(chunk);
-            message_slots[i] = message_pubkey;
-            i += 1;
+        let mut message_slots: Vec<Option<Pubkey>> = Vec::with_capacity(num_spots as usize);
+        for (slot, chunk) in message_slots_src.chunks(32).enumerate() {
+            if occupied_src[slot / 8] & (1 << (slot % 8)) == 0 {
+                message_slots.push(None);
+                continue;
+            }
+            let message_pubkey = Pubkey::try_from(chunk).map_err(|_| ProgramError::InvalidAccountData)?;
+            if message_pubkey == Pubkey::default() {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            message_slots.push(Some(message_pubkey));
         }
 
         Ok(Self {
@@ -122,84 +356,780 @@ impl Pack for SolBox {
             num_spots,
             num_in_use,
             is_initialized,
-            message_slots: *message_slots
+            message_seq,
+            pending_owner,
+            access_list,
+            postage_lamports,
+            postage_mint,
+            postage_token_amount,
+            encryption_key: *encryption_key,
+            read_receipts,
+            occupied: occupied_src.to_vec(),
+            message_slots,
         })
     }
 
-    fn pack_into_slice(&self, dst: &mut [u8]) {
-        let dst = array_mut_ref![dst, 0, SolBox::LEN];
+    /// Decodes the fixed 20-slot layout of version 0. It has no bitmap:
+    /// empty slots hold `LEGACY_EMPTY_SLOT`, and `num_in_use` was never
+    /// kept up to date, so both are rebuilt from the slots
+    fn unpack_v0(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() != SOL_BOX_V0_LEN {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        let src = array_ref![src, 0, SOL_BOX_V0_LEN];
+        let (_tag, owner, next_box, prev_box, num_spots, _num_in_use, is_initialized, message_slots_src) =
+            array_refs![src, 1, 32, 32, 32, 4, 4, 1, SOL_BOX_V0_NUM_SPOTS as usize * 32];
+        if u32::from_le_bytes(*num_spots) != SOL_BOX_V0_NUM_SPOTS {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        let mut sol_box = Self {
+            owner: Pubkey::new_from_array(*owner),
+            next_box: Pubkey::new_from_array(*next_box),
+            prev_box: Pubkey::new_from_array(*prev_box),
+            num_spots: SOL_BOX_V0_NUM_SPOTS,
+            is_initialized,
+            occupied: Self::get_empty_occupancy(SOL_BOX_V0_NUM_SPOTS),
+            message_slots: Self::get_empty_message_slots(SOL_BOX_V0_NUM_SPOTS),
+            ..Self::default()
+        };
+        for (slot, chunk) in message_slots_src.chunks(32).enumerate() {
+            let message_pubkey = Pubkey::try_from(chunk).map_err(|_| ProgramError::InvalidAccountData)?;
+            if message_pubkey == LEGACY_EMPTY_SLOT || message_pubkey == Pubkey::default() {
+                continue;
+            }
+            sol_box.occupied[slot / 8] |= 1 << (slot % 8);
+            sol_box.message_slots[slot] = Some(message_pubkey);
+            sol_box.num_in_use += 1;
+        }
+        Ok(sol_box)
+    }
+
+    /// Encodes the box; `dst` must be `get_packed_len(self.num_spots)` long
+    pub fn pack_into_slice(&self, dst: &mut [u8]) {
+        pack_account_prefix(SOL_BOX_TAG, dst);
+        let dst = &mut dst[ACCOUNT_PREFIX_LEN..];
+        let (header_dst, message_slots_dst) = dst.split_at_mut(SOL_BOX_FIELDS_LEN);
+        let header_dst = array_mut_ref![header_dst, 0, SOL_BOX_FIELDS_LEN];
         let (
-            tag_dst,
             owner_dst,
             next_box_dst,
             prev_box_dst,
             num_spots_dst,
             num_in_use_dst,
             is_initialized_dst,
-            message_slots_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 4, 4, 1, (SOL_BOX_NUM_SPOTS as usize)*32];
-        let &SolBox {
+            message_seq_dst,
+            pending_owner_dst,
+            access_list_dst,
+            postage_lamports_dst,
+            postage_mint_dst,
+            postage_token_amount_dst,
+            encryption_key_dst,
+            read_receipts_dst,
+        ) = mut_array_refs![header_dst, 32, 32, 32, 4, 4, 1, 8, 32, 32, 8, 32, 8, 32, 1];
+        let SolBox {
             ref owner,
             ref next_box,
             ref prev_box,
             num_spots,
             num_in_use,
             is_initialized,
+            message_seq,
+            ref pending_owner,
+            ref access_list,
+            postage_lamports,
+            ref postage_mint,
+            postage_token_amount,
+            ref encryption_key,
+            read_receipts,
+            ref occupied,
             ref message_slots,
-        } = self;
-        let tag: u8 = 0;
-        tag_dst.copy_from_slice(&tag.to_le_bytes());
+        } = *self;
         owner_dst.copy_from_slice(owner.as_ref());
         next_box_dst.copy_from_slice(next_box.as_ref());
         prev_box_dst.copy_from_slice(prev_box.as_ref());
         *num_spots_dst = num_spots.to_le_bytes();
         *num_in_use_dst = num_in_use.to_le_bytes();
         is_initialized_dst[0] = is_initialized as u8;
+        *message_seq_dst = message_seq.to_le_bytes();
+        pending_owner_dst.copy_from_slice(pending_owner.as_ref());
+        access_list_dst.copy_from_slice(access_list.as_ref());
+        *postage_lamports_dst = postage_lamports.to_le_bytes();
+        postage_mint_dst.copy_from_slice(postage_mint.as_ref());
+        *postage_token_amount_dst = postage_token_amount.to_le_bytes();
+        encryption_key_dst.copy_from_slice(encryption_key);
+        read_receipts_dst[0] = read_receipts as u8;
+
+        let (occupied_dst, message_slots_dst) = message_slots_dst.split_at_mut(occupied.len());
+        occupied_dst.copy_from_slice(occupied);
+        Self::pack_keys_into_ref(message_slots, message_slots_dst);
+    }
+}
+impl IsInitialized for SolBox {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+/// Zero-copy access to a packed, initialized SolBox
+///
+/// Reads and writes single header fields and message slots in place, so
+/// instructions that touch one slot don't decode and re-encode the whole
+/// box. `D` is usually the `Ref` or `RefMut` of the account's data.
+pub struct SolBoxView<D> {
+    data: D,
+    /// Where the header fields start, after the tag and version
+    fields_start: usize,
+    num_spots: u32,
+}
+impl<'a> SolBoxView<Ref<'a, [u8]>> {
+    /// Borrows the data of `sol_box_info` for reading
+    pub fn from_account_info(sol_box_info: &'a AccountInfo) -> Result<Self, ProgramError> {
+        Self::new(Ref::map(sol_box_info.data.borrow(), |data| &**data))
+    }
+}
+impl<'a> SolBoxView<RefMut<'a, [u8]>> {
+    /// Borrows the data of `sol_box_info` for reading and writing
+    pub fn from_account_info_mut(sol_box_info: &'a AccountInfo) -> Result<Self, ProgramError> {
+        Self::new(RefMut::map(sol_box_info.data.borrow_mut(), |data| &mut **data))
+    }
+}
+impl<D: Deref<Target = [u8]>> SolBoxView<D> {
+    /// Checks the header of the packed box in `data` without decoding the
+    /// slots. Version 0 boxes must be migrated first
+    pub fn new(data: D) -> Result<Self, ProgramError> {
+        let (tag, version) = unpack_account_prefix(&data)?;
+        let fields_start = ACCOUNT_PREFIX_LEN;
+        if tag != SOL_BOX_TAG {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        if version == 0 {
+            return Err(Sol2SolError::AccountNotMigrated.into());
+        }
+        if data.len() < SOL_BOX_HEADER_LEN {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let num_spots = u32::from_le_bytes(*array_ref![data, fields_start + SOL_BOX_NUM_SPOTS_OFFSET, 4]);
+        if num_spots != SolBox::num_spots_for_len(data.len())? {
+            return Err(Sol2SolError::SolBoxInvalidNumSpots.into());
+        }
+        match data[fields_start + SOL_BOX_IS_INITIALIZED_OFFSET] {
+            0 => return Err(ProgramError::UninitializedAccount),
+            1 => {}
+            _ => return Err(ProgramError::InvalidAccountData),
+        }
+        let view = Self { data, fields_start, num_spots };
+        if view.num_in_use() > num_spots {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(view)
+    }
+
+    fn pubkey_at(&self, offset: usize) -> Pubkey {
+        Pubkey::new_from_array(*array_ref![self.data, offset, 32])
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(*array_ref![self.data, self.fields_start + offset, 8])
+    }
+
+    fn occupancy_start(&self) -> usize {
+        self.fields_start + SOL_BOX_FIELDS_LEN
+    }
+
+    fn slot_offset(&self, slot: u32) -> usize {
+        self.occupancy_start() + SolBox::get_occupancy_len(self.num_spots) + slot as usize * 32
+    }
+
+    /// Who owns this SolBox
+    pub fn owner(&self) -> Pubkey {
+        self.pubkey_at(self.fields_start + SOL_BOX_OWNER_OFFSET)
+    }
+
+    /// How many messages this box stores
+    pub fn num_spots(&self) -> u32 {
+        self.num_spots
+    }
+
+    /// How many slots hold a message
+    pub fn num_in_use(&self) -> u32 {
+        u32::from_le_bytes(*array_ref![self.data, self.fields_start + SOL_BOX_NUM_IN_USE_OFFSET, 4])
+    }
+
+    /// Sequence number used to derive the next message delivered here
+    pub fn message_seq(&self) -> u64 {
+        self.u64_at(SOL_BOX_MESSAGE_SEQ_OFFSET)
+    }
+
+    /// Access list senders are checked against, `Pubkey::default()` if none
+    pub fn access_list(&self) -> Pubkey {
+        self.pubkey_at(self.fields_start + SOL_BOX_ACCESS_LIST_OFFSET)
+    }
+
+    /// Lamports a sender pays the owner to deliver a message
+    pub fn postage_lamports(&self) -> u64 {
+        self.u64_at(SOL_BOX_POSTAGE_LAMPORTS_OFFSET)
+    }
+
+    /// Mint of the token postage, `Pubkey::default()` if none
+    pub fn postage_mint(&self) -> Pubkey {
+        self.pubkey_at(self.fields_start + SOL_BOX_POSTAGE_MINT_OFFSET)
+    }
+
+    /// Tokens of `postage_mint` a sender pays the owner per message
+    pub fn postage_token_amount(&self) -> u64 {
+        self.u64_at(SOL_BOX_POSTAGE_TOKEN_AMOUNT_OFFSET)
+    }
+
+    /// x25519 public key messages must be sealed to, all zeros if none
+    pub fn encryption_key(&self) -> [u8; 32] {
+        *array_ref![self.data, self.fields_start + SOL_BOX_ENCRYPTION_KEY_OFFSET, 32]
+    }
+
+    /// Log a read receipt when a message is first marked read
+    pub fn read_receipts(&self) -> bool {
+        self.data[self.fields_start + SOL_BOX_READ_RECEIPTS_OFFSET] != 0
+    }
+
+    /// Whether any message slot is free
+    pub fn has_space(&self) -> bool {
+        self.num_in_use() < self.num_spots
+    }
+
+    /// Whether slot `slot` holds a message
+    pub fn is_occupied(&self, slot: u32) -> bool {
+        slot < self.num_spots && self.data[self.occupancy_start() + slot as usize / 8] & (1 << (slot % 8)) != 0
+    }
+
+    /// The message in slot `slot`, `None` if it is empty
+    pub fn message_slot(&self, slot: u32) -> Option<Pubkey> {
+        if self.is_occupied(slot) {
+            Some(self.pubkey_at(self.slot_offset(slot)))
+        } else {
+            None
+        }
+    }
+
+    /// Whether slot `slot` holds `message_key`
+    pub fn holds(&self, slot: u32, message_key: &Pubkey) -> bool {
+        self.message_slot(slot) == Some(*message_key)
+    }
+}
+impl<D: DerefMut<Target = [u8]>> SolBoxView<D> {
+    fn set_num_in_use(&mut self, num_in_use: u32) {
+        let offset = self.fields_start + SOL_BOX_NUM_IN_USE_OFFSET;
+        *array_mut_ref![self.data, offset, 4] = num_in_use.to_le_bytes();
+    }
+
+    /// Sets the sequence number of the next message delivered here
+    pub fn set_message_seq(&mut self, message_seq: u64) {
+        let offset = self.fields_start + SOL_BOX_MESSAGE_SEQ_OFFSET;
+        *array_mut_ref![self.data, offset, 8] = message_seq.to_le_bytes();
+    }
+
+    /// Stores `message_key` in the first free slot, returning its index
+    pub fn add_message(&mut self, message_key: &Pubkey) -> Result<u32, ProgramError> {
+        if !self.has_space() {
+            return Err(Sol2SolError::SolBoxNoSpaceLeft.into());
+        }
+        let occupancy_start = self.occupancy_start();
+        let occupied_len = SolBox::get_occupancy_len(self.num_spots);
+        let byte = self.data[occupancy_start..occupancy_start + occupied_len]
+            .iter()
+            .position(|bits| *bits != u8::MAX)
+            .ok_or(Sol2SolError::SolBoxNoSpaceLeft)?;
+        let slot = (byte * 8) as u32 + self.data[occupancy_start + byte].trailing_ones();
+        if slot >= self.num_spots {
+            return Err(Sol2SolError::SolBoxNoSpaceLeft.into());
+        }
+        self.data[occupancy_start + byte] |= 1 << (slot % 8);
+        let offset = self.slot_offset(slot);
+        array_mut_ref![self.data, offset, 32].copy_from_slice(message_key.as_ref());
+        self.set_num_in_use(self.num_in_use() + 1);
+        Ok(slot)
+    }
+
+    /// Frees slot `slot`, erroring unless it holds `message_key`
+    pub fn remove_message(&mut self, slot: u32, message_key: &Pubkey) -> Result<(), ProgramError> {
+        if !self.holds(slot, message_key) {
+            return Err(Sol2SolError::MessageNotInSolBox.into());
+        }
+        let occupancy_start = self.occupancy_start();
+        self.data[occupancy_start + slot as usize / 8] &= !(1 << (slot % 8));
+        let offset = self.slot_offset(slot);
+        array_mut_ref![self.data, offset, 32].fill(0);
+        self.set_num_in_use(self.num_in_use() - 1);
+        Ok(())
+    }
+}
+
+// Begin Message State
+/// Bytes in a message account before the body (tag + version + recipient + sender + size + slot + is_anonymous + reply_to + thread_root + status + expiry)
+pub const FIXED_MSG_SIZE: usize = 2 + MESSAGE_FIELDS_LEN;
+/// Fixed message bytes after the tag and version
+const MESSAGE_FIELDS_LEN: usize = 147;
+/// Bytes in a version 0 message account before the body (tag + recipient + sender + size)
+pub const FIXED_MSG_SIZE_V0: usize = 69;
+/// `Message::slot` of version 0 messages, which did not record their slot
+pub const MESSAGE_SLOT_UNKNOWN: u32 = u32::MAX;
+/// `Message::status` flag: the recipient has read the message
+pub const MESSAGE_STATUS_READ: u8 = 1;
+/// `Message::status` flag: the recipient starred the message
+pub const MESSAGE_STATUS_STARRED: u8 = 1 << 1;
+/// `Message::status` flag: the recipient archived the message
+pub const MESSAGE_STATUS_ARCHIVED: u8 = 1 << 2;
+/// Every `Message::status` flag currently defined
+pub const MESSAGE_STATUS_ALL: u8 = MESSAGE_STATUS_READ | MESSAGE_STATUS_STARRED | MESSAGE_STATUS_ARCHIVED;
+/// Bytes an encoded `MessageExpiry` takes (kind + value)
+pub const MESSAGE_EXPIRY_LEN: usize = 9;
+
+/// When a message may be purged by anyone
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum MessageExpiry {
+    /// Kept until deleted
+    #[default]
+    Never,
+    /// Expires once the cluster reaches this slot
+    Slot(u64),
+    /// Expires once the cluster clock reaches this unix timestamp
+    UnixTimestamp(i64),
+}
+impl MessageExpiry {
+    /// Whether the expiry is in the past according to `clock`
+    pub fn has_passed(&self, clock: &Clock) -> bool {
+        match *self {
+            Self::Never => false,
+            Self::Slot(slot) => clock.slot >= slot,
+            Self::UnixTimestamp(unix_timestamp) => clock.unix_timestamp >= unix_timestamp,
+        }
+    }
+
+    /// Decodes an expiry from its kind byte and little-endian value
+    pub fn unpack(src: &[u8; MESSAGE_EXPIRY_LEN]) -> Result<Self, ProgramError> {
+        let (kind, value) = array_refs![src, 1, 8];
+        match kind[0] {
+            0 if *value == [0; 8] => Ok(Self::Never),
+            1 => Ok(Self::Slot(u64::from_le_bytes(*value))),
+            2 => Ok(Self::UnixTimestamp(i64::from_le_bytes(*value))),
+            _ => Err(Sol2SolError::InvalidAccountData.into()),
+        }
+    }
+
+    /// Encodes the expiry as its kind byte and little-endian value
+    pub fn pack(&self) -> [u8; MESSAGE_EXPIRY_LEN] {
+        let mut dst = [0; MESSAGE_EXPIRY_LEN];
+        let (kind, value) = mut_array_refs![&mut dst, 1, 8];
+        match *self {
+            Self::Never => {}
+            Self::Slot(slot) => {
+                kind[0] = 1;
+                *value = slot.to_le_bytes();
+            }
+            Self::UnixTimestamp(unix_timestamp) => {
+                kind[0] = 2;
+                *value = unix_timestamp.to_le_bytes();
+            }
+        }
+        dst
+    }
+}
+
+/// Message
+///
+/// A message account is `FIXED_MSG_SIZE` header bytes followed by exactly
+/// `msg_size` bytes of body. The body is opaque to the program: plaintext
+/// utf-8, or an `envelope::Envelope` for boxes with an `encryption_key`.
+/// A zeroed account is an uninitialized message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Message {
+    /// Owner of the sol box the message was delivered to
+    pub recipient: Pubkey,
+    /// Who wrote the message, or the one-time reply token if `is_anonymous`
+    pub sender: Pubkey,
+    /// Length in bytes of `msg_data`
+    pub msg_size: u32,
+    /// Index of the slot holding the message in its sol box, or
+    /// `MESSAGE_SLOT_UNKNOWN` until a version 0 message is migrated
+    pub slot: u32,
+    /// The body of the message
+    pub msg_data: Vec<u8>,
+    /// Was written without recording the sender
+    pub is_anonymous: bool,
+    /// Message this one answers, `Pubkey::default()` if it starts a thread
+    pub reply_to: Pubkey,
+    /// First message of the thread, `Pubkey::default()` if it starts a thread
+    pub thread_root: Pubkey,
+    /// `MESSAGE_STATUS_*` flags set by the recipient
+    pub status: u8,
+    /// When anyone may purge the message
+    pub expiry: MessageExpiry,
+    /// Has been initialized? Stored as the account tag
+    pub is_initialized: bool,
+}
+impl Message {
+    /// Account size needed to hold a message with a `msg_size` byte body
+    pub fn get_packed_len(msg_size: u32) -> usize {
+        FIXED_MSG_SIZE + msg_size as usize
+    }
+
+    /// Builds an initialized message, sizing it from `msg_data`
+    pub fn new(recipient: Pubkey, sender: Pubkey, msg_data: Vec<u8>) -> Self {
+        Self {
+            recipient,
+            sender,
+            msg_size: msg_data.len() as u32,
+            slot: 0,
+            msg_data,
+            is_anonymous: false,
+            reply_to: Pubkey::default(),
+            thread_root: Pubkey::default(),
+            status: 0,
+            expiry: MessageExpiry::Never,
+            is_initialized: true,
+        }
+    }
+
+    /// Builds an initialized message answering `reply_to` in `thread_root`
+    pub fn new_reply(recipient: Pubkey, sender: Pubkey, msg_data: Vec<u8>, reply_to: Pubkey, thread_root: Pubkey) -> Self {
+        Self {
+            reply_to,
+            thread_root,
+            ..Self::new(recipient, sender, msg_data)
+        }
+    }
+
+    /// The `thread_root` a reply to this message, stored at `message_pubkey`, must carry
+    pub fn reply_thread_root(&self, message_pubkey: &Pubkey) -> Pubkey {
+        if self.thread_root == Pubkey::default() {
+            *message_pubkey
+        } else {
+            self.thread_root
+        }
+    }
+
+    /// Builds an initialized anonymous message that can be answered through `reply_token`
+    pub fn new_anonymous(recipient: Pubkey, reply_token: Pubkey, msg_data: Vec<u8>) -> Self {
+        Self {
+            is_anonymous: true,
+            ..Self::new(recipient, reply_token, msg_data)
+        }
+    }
+
+    /// The body as text, for messages that were not sealed
+    pub fn msg_str(&self) -> Result<&str, ProgramError> {
+        std::str::from_utf8(&self.msg_data).map_err(|_| Sol2SolError::InvalidMessageEncoding.into())
+    }
+
+    /// Unpacks an initialized message, sized from the account data
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        let message = Self::unpack_unchecked(src)?;
+        if !message.is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(message)
+    }
+
+    /// Unpacks a message without checking that it has been initialized
+    pub fn unpack_unchecked(src: &[u8]) -> Result<Self, ProgramError> {
+        Self::unpack_from_slice(src)
+    }
+
+    /// Packs `src` into `dst`, which must be exactly as long as the message
+    ///
+    /// Version 0 accounts can't hold the current fields and are rejected,
+    /// they only change layout through `MigrateAccount`.
+    pub fn pack(src: Self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if src.msg_size as usize != src.msg_data.len() {
+            return Err(Sol2SolError::MessageSizeMismatch.into());
+        }
+        if dst.len() == FIXED_MSG_SIZE_V0 + src.msg_size as usize {
+            return Err(Sol2SolError::AccountNotMigrated.into());
+        }
+        if dst.len() != Self::get_packed_len(src.msg_size) {
+            return Err(Sol2SolError::MessageSizeMismatch.into());
+        }
+        src.pack_into_slice(dst);
+        Ok(())
+    }
+
+    /// Decodes a message of any layout version, checking `msg_size` against
+    /// the length of `src`
+    pub fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let (tag, version) = unpack_account_prefix(src)?;
+        match tag {
+            0 if src.iter().all(|byte| *byte == 0) => return Ok(Self::default()),
+            MESSAGE_TAG => {}
+            _ => return Err(Sol2SolError::InvalidAccountData.into()),
+        }
+        if version == 0 {
+            return Self::unpack_v0(src);
+        }
+        let src = &src[ACCOUNT_PREFIX_LEN..];
+        if src.len() < MESSAGE_FIELDS_LEN {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let (fixed_src, msg_data_src) = src.split_at(MESSAGE_FIELDS_LEN);
+        let fixed_src = array_ref![fixed_src, 0, MESSAGE_FIELDS_LEN];
+        let (recipient_src, sender_src, msg_size_src, slot_src, is_anonymous_src, reply_to_src, thread_root_src, status_src, expiry_src) =
+            array_refs![fixed_src, 32, 32, 4, 4, 1, 32, 32, 1, MESSAGE_EXPIRY_LEN];
+        let recipient = Pubkey::new_from_array(*recipient_src);
+        let sender = Pubkey::new_from_array(*sender_src);
+        let msg_size = u32::from_le_bytes(*msg_size_src);
+        if msg_size as usize != msg_data_src.len() {
+            return Err(Sol2SolError::MessageSizeMismatch.into());
+        }
+        let is_anonymous = match is_anonymous_src {
+            [0] => false,
+            [1] => true,
+            _ => return Err(Sol2SolError::InvalidAccountData.into()),
+        };
+        let status = status_src[0];
+        if status & !MESSAGE_STATUS_ALL != 0 {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        Ok(Self {
+            recipient,
+            sender,
+            msg_size,
+            slot: u32::from_le_bytes(*slot_src),
+            msg_data: msg_data_src.to_vec(),
+            is_anonymous,
+            reply_to: Pubkey::new_from_array(*reply_to_src),
+            thread_root: Pubkey::new_from_array(*thread_root_src),
+            status,
+            expiry: MessageExpiry::unpack(expiry_src)?,
+            is_initialized: true,
+        })
+    }
+
+    /// Decodes the version 0 layout: recipient, sender, size and body
+    fn unpack_v0(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < FIXED_MSG_SIZE_V0 {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let (fixed_src, msg_data_src) = src.split_at(FIXED_MSG_SIZE_V0);
+        let fixed_src = array_ref![fixed_src, 0, FIXED_MSG_SIZE_V0];
+        let (_tag, recipient_src, sender_src, msg_size_src) = array_refs![fixed_src, 1, 32, 32, 4];
+        let msg_size = u32::from_le_bytes(*msg_size_src);
+        if msg_size as usize != msg_data_src.len() {
+            return Err(Sol2SolError::MessageSizeMismatch.into());
+        }
+        Ok(Self {
+            slot: MESSAGE_SLOT_UNKNOWN,
+            ..Self::new(
+                Pubkey::new_from_array(*recipient_src),
+                Pubkey::new_from_array(*sender_src),
+                msg_data_src.to_vec(),
+            )
+        })
+    }
+
+    /// Encodes the message; `dst` must be `get_packed_len(self.msg_size)` long
+    pub fn pack_into_slice(&self, dst: &mut [u8]) {
+        if self.is_initialized {
+            pack_account_prefix(MESSAGE_TAG, dst);
+        } else {
+            dst[..ACCOUNT_PREFIX_LEN].fill(0);
+        }
+        let dst = &mut dst[ACCOUNT_PREFIX_LEN..];
+        let (fixed_dst, data_dst) = dst.split_at_mut(MESSAGE_FIELDS_LEN);
+        let fixed_dst = array_mut_ref![fixed_dst, 0, MESSAGE_FIELDS_LEN];
+        let (recipient_dst, sender_dst, size_dst, slot_dst, is_anonymous_dst, reply_to_dst, thread_root_dst, status_dst, expiry_dst) =
+            mut_array_refs![fixed_dst, 32, 32, 4, 4, 1, 32, 32, 1, MESSAGE_EXPIRY_LEN];
+        recipient_dst.copy_from_slice(self.recipient.as_ref());
+        sender_dst.copy_from_slice(self.sender.as_ref());
+        size_dst.copy_from_slice(&self.msg_size.to_le_bytes());
+        *slot_dst = self.slot.to_le_bytes();
+        is_anonymous_dst[0] = self.is_anonymous as u8;
+        reply_to_dst.copy_from_slice(self.reply_to.as_ref());
+        thread_root_dst.copy_from_slice(self.thread_root.as_ref());
+        status_dst[0] = self.status;
+        *expiry_dst = self.expiry.pack();
+        data_dst.copy_from_slice(&self.msg_data);
+    }
+}
+impl IsInitialized for Message {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
 
-        Self::pack_keys_into_ref(&message_slots, message_slots_dst);
+
+// Begin Access List State
+/// Bytes before the access list entries (tag + version + sol_box + mode + is_initialized + num_entries)
+pub const ACCESS_LIST_HEADER_LEN: usize = 2 + ACCESS_LIST_FIELDS_LEN;
+/// Header bytes after the tag and version
+const ACCESS_LIST_FIELDS_LEN: usize = 38;
+
+/// Whether the senders in an access list are the only ones let in, or kept out
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum AccessListMode {
+    /// Only listed senders may write
+    #[default]
+    Allowlist = 0,
+    /// Everyone but listed senders may write
+    Blocklist = 1,
+}
+impl TryFrom<u8> for AccessListMode {
+    type Error = ProgramError;
+
+    fn try_from(mode: u8) -> Result<Self, Self::Error> {
+        match mode {
+            0 => Ok(Self::Allowlist),
+            1 => Ok(Self::Blocklist),
+            _ => Err(Sol2SolError::InvalidInstructionData.into()),
+        }
     }
 }
 
-/// Begin Message State
-const FIXED_MSG_SIZE: usize = 69;
-
-/// Packs the Message state into data
-pub fn pack_message_into(recipient: &Pubkey, sender: &Pubkey, msg_size: u32, msg_string: &String, dst: &mut [u8]) {
-    let fixed_dst = array_mut_ref![dst, 0, FIXED_MSG_SIZE];
-    let (tag_dst, recipient_dst, sender_dst, size_dst) =
-        mut_array_refs![fixed_dst, 1, 32, 32, 4];
-    let tag: u8 = 1;
-    tag_dst.copy_from_slice(&tag.to_le_bytes());
-    recipient_dst.copy_from_slice(recipient.as_ref());
-    sender_dst.copy_from_slice(sender.as_ref());
-    size_dst.copy_from_slice(&msg_size.to_le_bytes());
-    let (_, string_dst) = dst.split_at_mut(FIXED_MSG_SIZE);
-    string_dst.copy_from_slice(msg_string.as_bytes());
+/// AccessList
+///
+/// Linked from a sol box through `SolBox::access_list`. Management is
+/// authorized by the owner of a linked box, so the list follows ownership
+/// transfers. Entries are stored densely; capacity comes from the account length.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccessList {
+    /// Sol box the list was created for
+    pub sol_box: Pubkey,
+    /// How entries are interpreted
+    pub mode: AccessListMode,
+    /// Has been initialized?
+    pub is_initialized: bool,
+    /// How many senders the account can hold
+    pub capacity: u32,
+    /// Listed senders
+    pub entries: Vec<Pubkey>,
+}
+impl AccessList {
+    /// Account size needed to hold `capacity` entries
+    pub fn get_packed_len(capacity: u32) -> usize {
+        ACCESS_LIST_HEADER_LEN + capacity as usize * 32
+    }
+
+    /// Whether `sender` may write to boxes linked to this list
+    pub fn allows(&self, sender: &Pubkey) -> bool {
+        let listed = self.entries.contains(sender);
+        match self.mode {
+            AccessListMode::Allowlist => listed,
+            AccessListMode::Blocklist => !listed,
+        }
+    }
+
+    /// Lists `sender`, doing nothing if it already is
+    pub fn add_entry(&mut self, sender: &Pubkey) -> Result<(), ProgramError> {
+        if self.entries.contains(sender) {
+            return Ok(());
+        }
+        if self.entries.len() >= self.capacity as usize {
+            return Err(Sol2SolError::AccessListFull.into());
+        }
+        self.entries.push(*sender);
+        Ok(())
+    }
+
+    /// Unlists `sender`, erroring if it is not listed
+    pub fn remove_entry(&mut self, sender: &Pubkey) -> Result<(), ProgramError> {
+        let position = self.entries
+            .iter()
+            .position(|entry| entry == sender)
+            .ok_or(Sol2SolError::SenderNotInAccessList)?;
+        self.entries.swap_remove(position);
+        Ok(())
+    }
+
+    /// Unpacks an initialized access list, sized from the account data
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        let access_list = Self::unpack_from_slice(src)?;
+        if !access_list.is_initialized {
+            return Err(ProgramError::UninitializedAccount);
+        }
+        Ok(access_list)
+    }
+
+    /// Packs `src` into `dst`, which must be exactly as long as the list
+    pub fn pack(src: Self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if src.entries.len() > src.capacity as usize || dst.len() != Self::get_packed_len(src.capacity) {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        src.pack_into_slice(dst);
+        Ok(())
+    }
+
+    /// Decodes an access list, whose capacity is given by the length of
+    /// `src`. Access lists postdate versioning, so there is no version 0
+    pub fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        let (tag, version) = unpack_account_prefix(src)?;
+        if tag != ACCESS_LIST_TAG || version == 0 {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let src = &src[ACCOUNT_PREFIX_LEN..];
+        if src.len() < ACCESS_LIST_FIELDS_LEN || !(src.len() - ACCESS_LIST_FIELDS_LEN).is_multiple_of(32) {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let (header_src, entries_src) = src.split_at(ACCESS_LIST_FIELDS_LEN);
+        let header_src = array_ref![header_src, 0, ACCESS_LIST_FIELDS_LEN];
+        let (sol_box, mode, is_initialized, num_entries) =
+            array_refs![header_src, 32, 1, 1, 4];
+        let sol_box = Pubkey::new_from_array(*sol_box);
+        let mode = AccessListMode::try_from(mode[0]).map_err(|_| ProgramError::InvalidAccountData)?;
+        let is_initialized = match is_initialized {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData)
+        };
+        let capacity = (entries_src.len() / 32) as u32;
+        let num_entries = u32::from_le_bytes(*num_entries);
+        if num_entries > capacity {
+            return Err(Sol2SolError::InvalidAccountData.into());
+        }
+        let entries = entries_src
+            .chunks(32)
+            .take(num_entries as usize)
+            .map(|chunk| Pubkey::try_from(chunk).map_err(|_| ProgramError::InvalidAccountData))
+            .collect::<Result<Vec<Pubkey>, ProgramError>>()?;
+        Ok(Self {
+            sol_box,
+            mode,
+            is_initialized,
+            capacity,
+            entries,
+        })
+    }
+
+    /// Encodes the list; `dst` must be `get_packed_len(self.capacity)` long
+    pub fn pack_into_slice(&self, dst: &mut [u8]) {
+        pack_account_prefix(ACCESS_LIST_TAG, dst);
+        let dst = &mut dst[ACCOUNT_PREFIX_LEN..];
+        let (header_dst, entries_dst) = dst.split_at_mut(ACCESS_LIST_FIELDS_LEN);
+        let header_dst = array_mut_ref![header_dst, 0, ACCESS_LIST_FIELDS_LEN];
+        let (sol_box_dst, mode_dst, is_initialized_dst, num_entries_dst) =
+            mut_array_refs![header_dst, 32, 1, 1, 4];
+        sol_box_dst.copy_from_slice(self.sol_box.as_ref());
+        mode_dst[0] = self.mode as u8;
+        is_initialized_dst[0] = self.is_initialized as u8;
+        *num_entries_dst = (self.entries.len() as u32).to_le_bytes();
+        entries_dst.fill(0);
+        for (entry, entry_dst) in self.entries.iter().zip(entries_dst.chunks_mut(32)) {
+            entry_dst.copy_from_slice(entry.as_ref());
+        }
+    }
 }
-/// Unpacks the Message state from data
-pub fn unpack_message_from(src: &mut [u8]) -> Result<(Pubkey, Pubkey, u32, String), ProgramError> {
-    // let (msg_size_src, msg_string_src)
-    // dst.copy_from_slice(msg_size.to_le_bytes()));
-    // dst.copy_from_slice(msg_string.as_bytes());
-    let fixed_src = array_ref![src, 0, FIXED_MSG_SIZE];
-    let (tag_src, recipient_src, sender_src, msg_size_src) = array_refs![fixed_src, 1, 32, 32, 4];
-    let tag = u8::from_le_bytes(*tag_src);
-    msg!("Checking that message state has right tag");
-    if tag != 1 {
-        return Err(Sol2SolError::InvalidAccountData.into());
-    }
-    let recipient = Pubkey::new(recipient_src);
-    let sender = Pubkey::new(sender_src);
-    let msg_size = u32::from_le_bytes(*msg_size_src);
-    let (_, msg_string_src) = src.split_at(FIXED_MSG_SIZE);
-    let msg_string = String::from_utf8(msg_string_src[..].to_vec()).unwrap();
-    Ok((recipient, sender, msg_size, msg_string))
+impl IsInitialized for AccessList {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
 }
 
 
 #[cfg(test)]
-pub mod tests {
+mod tests {
     use super::*;
+    use std::str::FromStr;
 
     #[test]
     fn manual_sol_box_deserialize() {
@@ -210,11 +1140,17 @@ pub mod tests {
         
         let owner = Pubkey::from_str("GZbQmKYYzwjP3nbdqRWPLn98ipAni9w5eXMGp7bmZbGB").unwrap();
         assert_eq!(recreated_box.owner, owner);
+
+        // A version 0 box, whose empty slots hold `LEGACY_EMPTY_SLOT`
+        assert_eq!(Ok((SOL_BOX_TAG, 0)), unpack_account_prefix(&manual_bytes));
+        assert_eq!(SOL_BOX_V0_NUM_SPOTS, recreated_box.num_spots);
+        assert_eq!(0, recreated_box.num_in_use);
+        assert_eq!(SolBox::get_empty_message_slots(SOL_BOX_V0_NUM_SPOTS), recreated_box.message_slots);
+        assert_eq!(SolBox::get_empty_occupancy(SOL_BOX_V0_NUM_SPOTS), recreated_box.occupied);
     }
 
     #[test]
     fn test_sol_box_state() {
-        println!("Penis!");
         let owner = Pubkey::new_unique();
         let num_spots = 20;
         let num_in_use = 3;
@@ -224,13 +1160,13 @@ pub mod tests {
         let address1 = Pubkey::new_unique();
         let address2 = Pubkey::new_unique();
         let address3 = Pubkey::new_unique();
-        let null_pubkey = Pubkey::from_str(NULL_PUBKEY_STR).unwrap();
-        let message_slots = [
-            address1, address2, address3, null_pubkey, null_pubkey,
-            null_pubkey, null_pubkey, null_pubkey, null_pubkey, null_pubkey,
-            null_pubkey, null_pubkey, null_pubkey, null_pubkey, null_pubkey,
-            null_pubkey, null_pubkey, null_pubkey, null_pubkey, null_pubkey,
+        let message_slots = vec![
+            Some(address1), Some(address2), Some(address3), None, None,
+            None, None, None, None, None,
+            None, None, None, None, None,
+            None, None, None, None, None,
         ];
+        let occupied = vec![0b0000_0111, 0, 0];
         let init_box = SolBox {
             owner,
             next_box,
@@ -238,29 +1174,440 @@ pub mod tests {
             num_in_use,
             num_spots,
             is_initialized,
+            message_seq: 0,
+            pending_owner: Pubkey::default(),
+            access_list: Pubkey::default(),
+            postage_lamports: 0,
+            postage_mint: Pubkey::default(),
+            postage_token_amount: 0,
+            encryption_key: [0; 32],
+            read_receipts: false,
+            occupied,
             message_slots
         };
         
-        let dst: &mut [u8; SolBox::LEN] = &mut [0; SolBox::LEN];
-        init_box.pack_into_slice(dst);
+        let dst: &mut [u8] = &mut vec![0; SolBox::get_packed_len(num_spots)];
+        assert_eq!(903, dst.len());
+        SolBox::pack(init_box.clone(), dst).unwrap();
 
-        let recreated_box = SolBox::unpack_from_slice(dst).unwrap();
+        let recreated_box = SolBox::unpack(dst).unwrap();
         assert_eq!(init_box, recreated_box);
     }
 
+    #[test]
+    fn test_variable_capacity_sol_box() {
+        for num_spots in [MIN_SOL_BOX_NUM_SPOTS, 7, 100] {
+            let mut init_box = SolBox {
+                owner: Pubkey::new_unique(),
+                next_box: Pubkey::new_unique(),
+                prev_box: Pubkey::new_unique(),
+                num_spots,
+                num_in_use: 0,
+                is_initialized: true,
+                message_seq: 0,
+                pending_owner: Pubkey::default(),
+                access_list: Pubkey::default(),
+                postage_lamports: 0,
+                postage_mint: Pubkey::default(),
+                postage_token_amount: 0,
+                encryption_key: [0; 32],
+                read_receipts: false,
+                occupied: SolBox::get_empty_occupancy(num_spots),
+                message_slots: SolBox::get_empty_message_slots(num_spots),
+            };
+            let last_message = Pubkey::new_unique();
+            *init_box.message_slots.last_mut().unwrap() = Some(last_message);
+            init_box.occupied[(num_spots as usize - 1) / 8] |= 1 << ((num_spots - 1) % 8);
+            init_box.num_in_use = 1;
+
+            let dst: &mut [u8] = &mut vec![0; SolBox::get_packed_len(num_spots)];
+            SolBox::pack(init_box.clone(), dst).unwrap();
+            let recreated_box = SolBox::unpack(dst).unwrap();
+            assert_eq!(init_box, recreated_box);
+            assert_eq!(Some(&Some(last_message)), recreated_box.message_slots.last());
+            assert_eq!(vec![last_message], recreated_box.stored_messages());
+
+            // The bitmap must agree with num_in_use and leave its padding clear
+            let occupied_len = SolBox::get_occupancy_len(num_spots);
+            dst[SOL_BOX_HEADER_LEN + occupied_len - 1] ^= 1 << ((num_spots - 1) % 8);
+            assert_eq!(SolBox::unpack(dst), Err(ProgramError::InvalidAccountData));
+            if num_spots % 8 != 0 {
+                dst[SOL_BOX_HEADER_LEN + occupied_len - 1] = 1 << (num_spots % 8);
+                assert_eq!(SolBox::unpack(dst), Err(ProgramError::InvalidAccountData));
+            }
+            SolBox::pack(init_box.clone(), dst).unwrap();
+
+            // Capacity must agree with the account length
+            let too_long: &mut [u8] = &mut vec![0; SolBox::get_packed_len(num_spots + 1)];
+            assert_eq!(
+                SolBox::pack(init_box.clone(), too_long),
+                Err(Sol2SolError::SolBoxInvalidNumSpots.into())
+            );
+            too_long[..dst.len()].copy_from_slice(dst);
+            assert_eq!(
+                SolBox::unpack(too_long),
+                Err(Sol2SolError::SolBoxInvalidNumSpots.into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_num_spots_bounds() {
+        assert_eq!(Ok(MIN_SOL_BOX_NUM_SPOTS), SolBox::num_spots_for_len(SolBox::get_packed_len(MIN_SOL_BOX_NUM_SPOTS)));
+        assert_eq!(Ok(MAX_SOL_BOX_NUM_SPOTS), SolBox::num_spots_for_len(SolBox::get_packed_len(MAX_SOL_BOX_NUM_SPOTS)));
+        assert!(SolBox::get_packed_len(MAX_SOL_BOX_NUM_SPOTS) <= MAX_PERMITTED_DATA_INCREASE);
+        assert!(SolBox::get_packed_len(MAX_SOL_BOX_NUM_SPOTS + 1) > MAX_PERMITTED_DATA_INCREASE);
+        assert!(SolBox::check_num_spots(MIN_SOL_BOX_NUM_SPOTS - 1).is_err());
+        assert!(SolBox::check_num_spots(MAX_SOL_BOX_NUM_SPOTS + 1).is_err());
+        assert!(SolBox::num_spots_for_len(SOL_BOX_HEADER_LEN + 33).is_err());
+        assert!(SolBox::num_spots_for_len(SolBox::get_packed_len(MIN_SOL_BOX_NUM_SPOTS) + 1).is_err());
+    }
+
+    #[test]
+    fn test_add_and_remove_messages() {
+        let num_spots = 10;
+        let mut sol_box = SolBox {
+            num_spots,
+            occupied: SolBox::get_empty_occupancy(num_spots),
+            message_slots: SolBox::get_empty_message_slots(num_spots),
+            ..SolBox::default()
+        };
+        let message_keys: Vec<Pubkey> = (0..num_spots).map(|_| Pubkey::new_unique()).collect();
+        for (i, message_key) in message_keys.iter().enumerate() {
+            assert_eq!(Ok(i as u32), sol_box.add_message(message_key));
+        }
+        assert_eq!(num_spots, sol_box.num_in_use);
+        assert!(!sol_box.has_space());
+        assert_eq!(
+            sol_box.add_message(&Pubkey::new_unique()),
+            Err(Sol2SolError::SolBoxNoSpaceLeft.into())
+        );
+
+        // Freed slots are reused lowest first
+        sol_box.remove_message(8, &message_keys[8]).unwrap();
+        sol_box.remove_message(3, &message_keys[3]).unwrap();
+        assert_eq!(num_spots - 2, sol_box.num_in_use);
+        assert!(!sol_box.is_occupied(3));
+        assert_eq!(
+            sol_box.remove_message(3, &message_keys[3]),
+            Err(Sol2SolError::MessageNotInSolBox.into())
+        );
+        assert_eq!(
+            sol_box.remove_message(4, &message_keys[5]),
+            Err(Sol2SolError::MessageNotInSolBox.into())
+        );
+        assert_eq!(
+            sol_box.remove_message(num_spots, &message_keys[0]),
+            Err(Sol2SolError::MessageNotInSolBox.into())
+        );
+        let new_key = Pubkey::new_unique();
+        assert_eq!(Ok(3), sol_box.add_message(&new_key));
+        assert!(sol_box.holds(3, &new_key));
+        assert_eq!(num_spots as usize - 1, sol_box.stored_messages().len());
+        assert!(!sol_box.stored_messages().contains(&message_keys[8]));
+    }
+
+    #[test]
+    fn test_sol_box_view() {
+        let num_spots = 12;
+        let mut sol_box = SolBox {
+            owner: Pubkey::new_unique(),
+            next_box: Pubkey::new_unique(),
+            prev_box: Pubkey::new_unique(),
+            num_spots,
+            is_initialized: true,
+            message_seq: 41,
+            pending_owner: Pubkey::new_unique(),
+            access_list: Pubkey::new_unique(),
+            postage_lamports: 7,
+            postage_mint: Pubkey::new_unique(),
+            postage_token_amount: 9,
+            encryption_key: [3; 32],
+            read_receipts: true,
+            occupied: SolBox::get_empty_occupancy(num_spots),
+            message_slots: SolBox::get_empty_message_slots(num_spots),
+            ..SolBox::default()
+        };
+        let message_keys: Vec<Pubkey> = (0..num_spots).map(|_| Pubkey::new_unique()).collect();
+        for message_key in &message_keys[..9] {
+            sol_box.add_message(message_key).unwrap();
+        }
+        let mut data = vec![0; SolBox::get_packed_len(num_spots)];
+        SolBox::pack(sol_box.clone(), &mut data).unwrap();
+
+        let mut view = SolBoxView::new(&mut data[..]).unwrap();
+        assert_eq!(sol_box.owner, view.owner());
+        assert_eq!(num_spots, view.num_spots());
+        assert_eq!(9, view.num_in_use());
+        assert_eq!(41, view.message_seq());
+        assert_eq!(sol_box.access_list, view.access_list());
+        assert_eq!(7, view.postage_lamports());
+        assert_eq!(sol_box.postage_mint, view.postage_mint());
+        assert_eq!(9, view.postage_token_amount());
+        assert_eq!([3; 32], view.encryption_key());
+        assert!(view.read_receipts());
+        assert_eq!(Some(message_keys[8]), view.message_slot(8));
+        assert_eq!(None, view.message_slot(9));
+        assert_eq!(None, view.message_slot(num_spots));
+
+        // Edits in place match edits through the struct
+        view.remove_message(2, &message_keys[2]).unwrap();
+        assert_eq!(Ok(2), view.add_message(&message_keys[9]));
+        assert_eq!(Ok(9), view.add_message(&message_keys[10]));
+        view.remove_message(5, &message_keys[5]).unwrap();
+        assert_eq!(
+            view.remove_message(5, &message_keys[5]),
+            Err(Sol2SolError::MessageNotInSolBox.into())
+        );
+        view.set_message_seq(42);
+        sol_box.remove_message(2, &message_keys[2]).unwrap();
+        sol_box.add_message(&message_keys[9]).unwrap();
+        sol_box.add_message(&message_keys[10]).unwrap();
+        sol_box.remove_message(5, &message_keys[5]).unwrap();
+        sol_box.message_seq = 42;
+        assert_eq!(sol_box, SolBox::unpack(&data).unwrap());
+
+        let mut view = SolBoxView::new(&mut data[..]).unwrap();
+        while view.has_space() {
+            view.add_message(&Pubkey::new_unique()).unwrap();
+        }
+        assert_eq!(
+            view.add_message(&Pubkey::new_unique()),
+            Err(Sol2SolError::SolBoxNoSpaceLeft.into())
+        );
+        assert_eq!(num_spots, SolBox::unpack(&data).unwrap().num_in_use);
+
+        assert_eq!(
+            SolBoxView::new(&data[..data.len() - 1]).err(),
+            Some(Sol2SolError::SolBoxInvalidNumSpots.into())
+        );
+        data[2 + SOL_BOX_IS_INITIALIZED_OFFSET] = 0;
+        assert_eq!(SolBoxView::new(&data[..]).err(), Some(ProgramError::UninitializedAccount));
+    }
+
     #[test]
     fn test_message_state() {
         let recipient = Pubkey::new_unique();
         let sender = Pubkey::new_unique();
-        let msg_size: u32 = 6;
-        let msg_string: String = "penis!".to_string();
+        let msg_data: Vec<u8> = b"hello!".to_vec();
+        let message = Message {
+            slot: 7,
+            ..Message::new(recipient, sender, msg_data)
+        };
+        let dst: &mut [u8] = &mut [0; FIXED_MSG_SIZE + 6];
+        assert_eq!(Ok(Message::default()), Message::unpack_unchecked(dst));
+        assert_eq!(Err(ProgramError::UninitializedAccount), Message::unpack(dst));
+        Message::pack(message.clone(), dst).unwrap();
+
+        let recreated = Message::unpack(dst).unwrap();
+        assert_eq!(message, recreated);
+        assert_eq!(6, recreated.msg_size);
+        assert_eq!(Ok("hello!"), recreated.msg_str());
+        assert!(!recreated.is_anonymous);
+
+        let reply_token = Pubkey::new_unique();
+        let anonymous = Message::new_anonymous(recipient, reply_token, b"guess!".to_vec());
+        Message::pack(anonymous.clone(), dst).unwrap();
+        let recreated = Message::unpack(dst).unwrap();
+        assert_eq!(anonymous, recreated);
+        assert!(recreated.is_anonymous);
+        assert_eq!(reply_token, recreated.sender);
+
+        let root_pubkey = Pubkey::new_unique();
+        let reply_pubkey = Pubkey::new_unique();
+        let root = Message::new(recipient, sender, b"hello!".to_vec());
+        assert_eq!(root_pubkey, root.reply_thread_root(&root_pubkey));
+        let reply = Message::new_reply(sender, recipient, b"what?!".to_vec(), root_pubkey, root_pubkey);
+        assert_eq!(root_pubkey, reply.reply_thread_root(&reply_pubkey));
+        Message::pack(reply.clone(), dst).unwrap();
+        assert_eq!(reply, Message::unpack(dst).unwrap());
+
+        let expiring = Message {
+            expiry: MessageExpiry::UnixTimestamp(-1),
+            ..message
+        };
+        Message::pack(expiring.clone(), dst).unwrap();
+        assert_eq!(expiring, Message::unpack(dst).unwrap());
+    }
+
+    #[test]
+    fn test_message_expiry() {
+        let clock = Clock {
+            slot: 100,
+            unix_timestamp: 1_000,
+            ..Clock::default()
+        };
+        assert!(!MessageExpiry::Never.has_passed(&clock));
+        assert!(MessageExpiry::Slot(100).has_passed(&clock));
+        assert!(!MessageExpiry::Slot(101).has_passed(&clock));
+        assert!(MessageExpiry::UnixTimestamp(999).has_passed(&clock));
+        assert!(!MessageExpiry::UnixTimestamp(1_001).has_passed(&clock));
+
+        for expiry in [MessageExpiry::Never, MessageExpiry::Slot(7), MessageExpiry::UnixTimestamp(-7)] {
+            assert_eq!(Ok(expiry), MessageExpiry::unpack(&expiry.pack()));
+        }
+        assert!(MessageExpiry::unpack(&[0, 1, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_message_validation() {
+        let message = Message::new(Pubkey::new_unique(), Pubkey::new_unique(), b"hello!".to_vec());
+        assert_eq!(
+            Message::pack(message.clone(), &mut [0; FIXED_MSG_SIZE + 4]),
+            Err(Sol2SolError::MessageSizeMismatch.into())
+        );
+
         let dst: &mut [u8] = &mut [0; FIXED_MSG_SIZE + 6];
-        pack_message_into(&recipient, &sender, msg_size, &msg_string, dst);
+        Message::pack(message, dst).unwrap();
+        assert_eq!(
+            Message::unpack(&dst[..FIXED_MSG_SIZE + 5]),
+            Err(Sol2SolError::MessageSizeMismatch.into())
+        );
+        assert_eq!(
+            Message::unpack(&dst[..FIXED_MSG_SIZE - 1]),
+            Err(Sol2SolError::InvalidAccountData.into())
+        );
 
-        let (rec_recipient, rec_sender, rec_msg_size, rec_msg_string) = unpack_message_from(dst).unwrap();
-        assert_eq!(recipient, rec_recipient);
-        assert_eq!(sender, rec_sender);
-        assert_eq!(msg_size, rec_msg_size);
-        assert_eq!(msg_string, rec_msg_string);
+        // Bodies are opaque, only reading them as text needs utf-8
+        dst[FIXED_MSG_SIZE] = 0xff;
+        let opaque = Message::unpack(dst).unwrap();
+        assert_eq!(0xff, opaque.msg_data[0]);
+        assert_eq!(opaque.msg_str(), Err(Sol2SolError::InvalidMessageEncoding.into()));
+        dst[FIXED_MSG_SIZE - 75] = 2;
+        assert_eq!(Message::unpack(dst), Err(Sol2SolError::InvalidAccountData.into()));
+        dst[FIXED_MSG_SIZE - 75] = 0;
+        dst[FIXED_MSG_SIZE - 10] = MESSAGE_STATUS_ALL + 1;
+        assert_eq!(Message::unpack(dst), Err(Sol2SolError::InvalidAccountData.into()));
+        dst[FIXED_MSG_SIZE - 10] = MESSAGE_STATUS_READ | MESSAGE_STATUS_ARCHIVED;
+        assert_eq!(MESSAGE_STATUS_READ | MESSAGE_STATUS_ARCHIVED, Message::unpack(dst).unwrap().status);
+        dst[FIXED_MSG_SIZE - 9] = 3;
+        assert_eq!(Message::unpack(dst), Err(Sol2SolError::InvalidAccountData.into()));
+        dst[FIXED_MSG_SIZE - 9] = 0;
+        dst[0] = 0;
+        assert_eq!(Message::unpack(dst), Err(Sol2SolError::InvalidAccountData.into()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_access_list_state() {
+        let sender = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let mut access_list = AccessList {
+            sol_box: Pubkey::new_unique(),
+            mode: AccessListMode::Allowlist,
+            is_initialized: true,
+            capacity: 2,
+            entries: vec![],
+        };
+        assert!(!access_list.allows(&sender));
+        access_list.add_entry(&sender).unwrap();
+        access_list.add_entry(&sender).unwrap();
+        access_list.add_entry(&other).unwrap();
+        assert_eq!(
+            access_list.add_entry(&Pubkey::new_unique()),
+            Err(Sol2SolError::AccessListFull.into())
+        );
+        assert!(access_list.allows(&sender));
+
+        let dst: &mut [u8] = &mut vec![0; AccessList::get_packed_len(2)];
+        AccessList::pack(access_list.clone(), dst).unwrap();
+        assert_eq!(access_list, AccessList::unpack(dst).unwrap());
+
+        access_list.remove_entry(&sender).unwrap();
+        assert_eq!(vec![other], access_list.entries);
+        assert_eq!(
+            access_list.remove_entry(&sender),
+            Err(Sol2SolError::SenderNotInAccessList.into())
+        );
+        access_list.mode = AccessListMode::Blocklist;
+        assert!(access_list.allows(&sender));
+        assert!(!access_list.allows(&other));
+        AccessList::pack(access_list.clone(), dst).unwrap();
+        assert_eq!(access_list, AccessList::unpack(dst).unwrap());
+    }
+
+    #[test]
+    fn test_account_versions() {
+        // Version 0 box as the original program wrote it: tag, owner, next,
+        // prev, num_spots, a stale num_in_use, is_initialized, then slots
+        let owner = Pubkey::new_unique();
+        let next_box = Pubkey::new_unique();
+        let message_key = Pubkey::new_unique();
+        let mut legacy_box = vec![SOL_BOX_TAG];
+        legacy_box.extend_from_slice(owner.as_ref());
+        legacy_box.extend_from_slice(next_box.as_ref());
+        legacy_box.extend_from_slice(Pubkey::default().as_ref());
+        legacy_box.extend_from_slice(&SOL_BOX_V0_NUM_SPOTS.to_le_bytes());
+        legacy_box.extend_from_slice(&0u32.to_le_bytes());
+        legacy_box.push(1);
+        for slot in 0..SOL_BOX_V0_NUM_SPOTS {
+            let slot_key = if slot == 2 { message_key } else { LEGACY_EMPTY_SLOT };
+            legacy_box.extend_from_slice(slot_key.as_ref());
+        }
+        assert_eq!(SOL_BOX_V0_LEN, legacy_box.len());
+
+        let sol_box = SolBox::unpack(&legacy_box).unwrap();
+        assert_eq!(owner, sol_box.owner);
+        assert_eq!(next_box, sol_box.next_box);
+        assert_eq!(1, sol_box.num_in_use);
+        assert!(sol_box.is_occupied(2));
+        assert_eq!(Some(message_key), sol_box.message_slots[2]);
+
+        // Version 0 accounts are only written through `MigrateAccount`
+        assert_eq!(Err(Sol2SolError::AccountNotMigrated.into()), SolBox::pack(sol_box.clone(), &mut legacy_box));
+        assert_eq!(Err(Sol2SolError::AccountNotMigrated.into()), SolBoxView::new(&legacy_box[..]).map(|_| ()));
+        assert!(SolBox::unpack(&legacy_box[..SOL_BOX_V0_LEN - 32]).is_err());
+
+        let dst = &mut vec![0; SolBox::get_packed_len(SOL_BOX_V0_NUM_SPOTS)];
+        SolBox::pack(sol_box.clone(), dst).unwrap();
+        assert_eq!([SOL_BOX_TAG | ACCOUNT_VERSIONED_FLAG, ACCOUNT_VERSION], dst[..2]);
+        assert_eq!(sol_box, SolBox::unpack(dst).unwrap());
+        let view = SolBoxView::new(&dst[..]).unwrap();
+        assert_eq!(owner, view.owner());
+        assert_eq!(1, view.num_in_use());
+
+        // Versions this program does not know yet are rejected
+        dst[1] = ACCOUNT_VERSION + 1;
+        assert_eq!(Err(Sol2SolError::InvalidAccountData.into()), unpack_account_prefix(dst));
+        assert!(SolBox::unpack(dst).is_err());
+        assert!(SolBoxView::new(&dst[..]).is_err());
+
+        // Version 0 message: tag, recipient, sender, size, then the body
+        let sender = Pubkey::new_unique();
+        let mut legacy_message = vec![MESSAGE_TAG];
+        legacy_message.extend_from_slice(owner.as_ref());
+        legacy_message.extend_from_slice(sender.as_ref());
+        legacy_message.extend_from_slice(&2u32.to_le_bytes());
+        legacy_message.extend_from_slice(b"hi");
+        assert_eq!(FIXED_MSG_SIZE_V0 + 2, legacy_message.len());
+
+        let message = Message::unpack(&legacy_message).unwrap();
+        assert_eq!(
+            Message {
+                slot: MESSAGE_SLOT_UNKNOWN,
+                ..Message::new(owner, sender, b"hi".to_vec())
+            },
+            message
+        );
+        assert_eq!(Err(Sol2SolError::AccountNotMigrated.into()), Message::pack(message.clone(), &mut legacy_message));
+        assert!(Message::unpack(&legacy_message[..FIXED_MSG_SIZE_V0 + 1]).is_err());
+
+        let dst = &mut vec![0; Message::get_packed_len(2)];
+        Message::pack(message.clone(), dst).unwrap();
+        assert_eq!([MESSAGE_TAG | ACCOUNT_VERSIONED_FLAG, ACCOUNT_VERSION], dst[..2]);
+        assert_eq!(message, Message::unpack(dst).unwrap());
+
+        // Access lists postdate versioning
+        let access_list = AccessList {
+            sol_box: Pubkey::new_unique(),
+            is_initialized: true,
+            capacity: 1,
+            entries: vec![owner],
+            ..AccessList::default()
+        };
+        let dst = &mut vec![0; AccessList::get_packed_len(1)];
+        AccessList::pack(access_list.clone(), dst).unwrap();
+        assert_eq!(access_list, AccessList::unpack(dst).unwrap());
+        dst[0] = ACCESS_LIST_TAG;
+        assert!(AccessList::unpack(dst).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! Helpers for grouping messages into conversations off-chain
+//!
+//! Replies point at the message they answer through `reply_to`, and every
+//! message in a thread shares its `thread_root`.
+
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+use std::collections::HashMap;
+use crate::{chain::walk_forward, state::Message};
+
+/// A message and the replies to it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadNode {
+    /// Address of the message account
+    pub message_pubkey: Pubkey,
+    /// The message itself
+    pub message: Message,
+    /// Messages answering this one, in the order they were found
+    pub replies: Vec<ThreadNode>,
+}
+
+/// Rebuilds the threads of every message stored in the chains starting at `starts`
+///
+/// Pass both participants' roots to see a whole conversation: a reply is
+/// stored in the box of whoever it was sent to. Messages whose parent is not
+/// in any of the chains, e.g. because it was deleted, are returned as roots.
+/// `get_account_data` returns the raw data of an account, or `None` if it
+/// does not exist.
+pub fn build_threads<F>(starts: &[Pubkey], mut get_account_data: F) -> Result<Vec<ThreadNode>, ProgramError>
+where
+    F: FnMut(&Pubkey) -> Option<Vec<u8>>,
+{
+    let mut messages: Vec<(Pubkey, Message)> = Vec::new();
+    for start in starts {
+        for (_, sol_box) in walk_forward(start, &mut get_account_data)? {
+            for message_pubkey in sol_box.stored_messages() {
+                if let Some(data) = get_account_data(&message_pubkey) {
+                    messages.push((message_pubkey, Message::unpack(&data)?));
+                }
+            }
+        }
+    }
+    Ok(group_threads(messages))
+}
+
+/// Arranges `messages` into threads, keeping their order among siblings
+///
+/// Messages whose parent is not in `messages` are returned as roots.
+pub fn group_threads(messages: Vec<(Pubkey, Message)>) -> Vec<ThreadNode> {
+    let known: HashMap<Pubkey, usize> = messages
+        .iter()
+        .enumerate()
+        .map(|(i, (message_pubkey, _))| (*message_pubkey, i))
+        .collect();
+    let mut children: HashMap<Pubkey, Vec<usize>> = HashMap::new();
+    let mut roots: Vec<usize> = Vec::new();
+    for (i, (_, message)) in messages.iter().enumerate() {
+        if known.contains_key(&message.reply_to) {
+            children.entry(message.reply_to).or_default().push(i);
+        } else {
+            roots.push(i);
+        }
+    }
+    roots
+        .into_iter()
+        .map(|i| build_node(i, &messages, &children))
+        .collect()
+}
+
+fn build_node(i: usize, messages: &[(Pubkey, Message)], children: &HashMap<Pubkey, Vec<usize>>) -> ThreadNode {
+    let (message_pubkey, message) = &messages[i];
+    let replies = children
+        .get(message_pubkey)
+        .map(|replies| replies.iter().map(|j| build_node(*j, messages, children)).collect())
+        .unwrap_or_default();
+    ThreadNode {
+        message_pubkey: *message_pubkey,
+        message: message.clone(),
+        replies,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{SolBox, MIN_SOL_BOX_NUM_SPOTS};
+
+    fn add_sol_box(accounts: &mut HashMap<Pubkey, Vec<u8>>, owner: &Pubkey, messages: &[Pubkey]) -> Pubkey {
+        let key = Pubkey::new_unique();
+        let mut sol_box = SolBox {
+            owner: *owner,
+            next_box: key,
+            prev_box: key,
+            num_spots: MIN_SOL_BOX_NUM_SPOTS,
+            is_initialized: true,
+            occupied: SolBox::get_empty_occupancy(MIN_SOL_BOX_NUM_SPOTS),
+            message_slots: SolBox::get_empty_message_slots(MIN_SOL_BOX_NUM_SPOTS),
+            ..SolBox::default()
+        };
+        for message in messages {
+            sol_box.add_message(message).unwrap();
+        }
+        let mut data = vec![0; SolBox::get_packed_len(MIN_SOL_BOX_NUM_SPOTS)];
+        SolBox::pack(sol_box, &mut data).unwrap();
+        accounts.insert(key, data);
+        key
+    }
+
+    fn add_message(accounts: &mut HashMap<Pubkey, Vec<u8>>, message: Message) -> Pubkey {
+        let key = Pubkey::new_unique();
+        let mut data = vec![0; Message::get_packed_len(message.msg_size)];
+        Message::pack(message, &mut data).unwrap();
+        accounts.insert(key, data);
+        key
+    }
+
+    #[test]
+    fn test_build_threads() {
+        let alice = Pubkey::new_unique();
+        let bob = Pubkey::new_unique();
+        let mut accounts = HashMap::new();
+
+        let hello = Message::new(bob, alice, b"hello".to_vec());
+        let hello_pubkey = add_message(&mut accounts, hello.clone());
+        let hi = Message::new_reply(alice, bob, b"hi".to_vec(), hello_pubkey, hello.reply_thread_root(&hello_pubkey));
+        let hi_pubkey = add_message(&mut accounts, hi.clone());
+        let how_are_you = Message::new_reply(bob, alice, b"how are you".to_vec(), hi_pubkey, hi.reply_thread_root(&hi_pubkey));
+        let how_are_you_pubkey = add_message(&mut accounts, how_are_you);
+        let unrelated_pubkey = add_message(&mut accounts, Message::new(bob, alice, b"other".to_vec()));
+        let orphan = Message::new_reply(bob, alice, b"orphan".to_vec(), Pubkey::new_unique(), Pubkey::new_unique());
+        let orphan_pubkey = add_message(&mut accounts, orphan);
+
+        let alice_box = add_sol_box(&mut accounts, &alice, &[hi_pubkey]);
+        let bob_box = add_sol_box(&mut accounts, &bob, &[hello_pubkey, how_are_you_pubkey, unrelated_pubkey, orphan_pubkey]);
+
+        let threads = build_threads(&[bob_box, alice_box], |key| accounts.get(key).cloned()).unwrap();
+        let roots: Vec<Pubkey> = threads.iter().map(|node| node.message_pubkey).collect();
+        assert_eq!(vec![hello_pubkey, unrelated_pubkey, orphan_pubkey], roots);
+
+        let hello_thread = &threads[0];
+        assert_eq!(1, hello_thread.replies.len());
+        assert_eq!(hi_pubkey, hello_thread.replies[0].message_pubkey);
+        assert_eq!(how_are_you_pubkey, hello_thread.replies[0].replies[0].message_pubkey);
+        assert_eq!(hello_pubkey, hello_thread.replies[0].replies[0].message.thread_root);
+
+        // Only one side of the conversation leaves the reply's own reply dangling
+        let threads = build_threads(&[bob_box], |key| accounts.get(key).cloned()).unwrap();
+        assert_eq!(4, threads.len());
+        assert!(threads.iter().all(|node| node.replies.is_empty()));
+    }
+}
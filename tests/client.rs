@@ -9,193 +9,2221 @@ use {
         signature::Signer,
         transaction::Transaction, 
         signer::keypair::Keypair,
+        transaction::TransactionError,
+        instruction::InstructionError,
     },
     solana_program::{
-        system_program,
+        entrypoint::MAX_PERMITTED_DATA_INCREASE,
         pubkey::Pubkey,
         rent::Rent,
-        sysvar,
-        system_instruction,
-        program_pack::{Pack},
-        instruction::{AccountMeta, Instruction}
+        program_option::COption,
+        program_pack::Pack,
+        instruction::Instruction
     },
     sol2sol::{
+        error::Sol2SolError,
         processor::Processor,
         instruction::{
             Sol2SolInstruction,
             init_sol_box,
+            find_sol_box_address,
+            find_message_address,
             write_message,
+            delete_message,
+            append_sol_box,
+            close_sol_box,
+            transfer_sol_box_ownership,
+            accept_sol_box_ownership,
+            init_access_list,
+            add_access_list_entry,
+            remove_access_list_entry,
+            set_access_list_mode,
+            find_access_list_address,
+            set_postage,
+            set_token_postage,
+            set_encryption_key,
+            write_anonymous_message,
+            reply_anonymous,
+            delete_reply,
+            find_reply_address,
+            write_reply,
+            mark_messages,
+            set_read_receipts,
+            set_message_expiry,
+            purge_expired,
+            PURGE_BOUNTY_LAMPORTS,
+            migrate_account,
+        },
+        thread::build_threads,
+        envelope::{Envelope, ENVELOPE_VERSION, ENVELOPE_TAG_LEN},
+        event::{decode_events, Sol2SolEvent},
+        state::{
+            SolBox,
+            Message,
+            DEFAULT_SOL_BOX_NUM_SPOTS,
+            FIXED_MSG_SIZE,
+            MIN_SOL_BOX_NUM_SPOTS,
+            MAX_SOL_BOX_NUM_SPOTS,
+            AccessList,
+            AccessListMode,
+            MESSAGE_STATUS_READ,
+            MESSAGE_STATUS_STARRED,
+            MessageExpiry,
+            LEGACY_EMPTY_SLOT,
+            ACCOUNT_VERSION,
+            ACCOUNT_VERSIONED_FLAG,
+            MESSAGE_TAG,
+            SOL_BOX_TAG,
+            SOL_BOX_V0_LEN,
+            SOL_BOX_V0_NUM_SPOTS,
+            unpack_account_prefix,
         },
-        state::SolBox,
     },
-    std::{convert::TryInto, str::FromStr},
+    std::str::FromStr,
 };
 
-#[cfg(test)]
-
-#[tokio::test]
-async fn test_init_sol_box() {
-    let program_id = Pubkey::from_str(&"invoker111111111111111111111111111111111111").unwrap();
-    let sol_box_pair = Keypair::new();
+fn program_id() -> Pubkey {
+    Pubkey::from_str("invoker111111111111111111111111111111111111").unwrap()
+}
 
-    let program_test = ProgramTest::new(
-        &"sol2sol",
-        program_id,
+/// Loads the program into a test validator, funds each of `wallets` and adds
+/// an initialized, empty sol box at each `(sol_box, owner)` of `sol_boxes`
+fn program_test(program_id: &Pubkey, wallets: &[&Pubkey], sol_boxes: &[(&Pubkey, &Pubkey)]) -> ProgramTest {
+    let mut program_test = ProgramTest::new(
+        "sol2sol",
+        *program_id,
         processor!(Processor::process_instruction),
     );
-    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+    for wallet in wallets {
+        program_test.add_account(
+            **wallet,
+            Account {
+                lamports: 1000000000,
+                ..Account::default()
+            },
+        );
+    }
+    for (sol_box, owner) in sol_boxes {
+        add_sol_box_account(&mut program_test, program_id, sol_box, owner);
+    }
+    program_test
+}
 
-    let rent = banks_client.get_rent().await.unwrap();
-    let create_account_ix = system_instruction::create_account(
-        &payer.pubkey(),
-        &sol_box_pair.pubkey(),
-        rent.minimum_balance(SolBox::get_packed_len()) + 2,
-        SolBox::get_packed_len().try_into().unwrap(),
-        &program_id,
-    );
+/// Creates and initializes the payer's root sol box with `num_spots` slots
+async fn create_sol_box(num_spots: u32) -> (Pubkey, Pubkey, Account) {
+    let program_id = program_id();
 
+    let (mut banks_client, payer, recent_blockhash) = program_test(&program_id, &[], &[]).start().await;
+
+    let (sol_box_pubkey, _) = find_sol_box_address(&program_id, &payer.pubkey(), 0);
     let init_sol_box_ix = init_sol_box(
         &program_id,
         &payer.pubkey(),
-        &sol_box_pair.pubkey(),
+        0,
+        num_spots,
     ).unwrap();
 
     let mut transaction = Transaction::new_with_payer(
-        &[
-            create_account_ix,
-            init_sol_box_ix,
-        ],
+        &[init_sol_box_ix],
         Some(&payer.pubkey()),
     );
-    transaction.sign(&[&payer, &sol_box_pair], recent_blockhash);
+    transaction.sign(&[&payer], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
-    let sol_box_acct = banks_client.get_account(sol_box_pair.pubkey())
+    let sol_box_acct = banks_client.get_account(sol_box_pubkey)
         .await
         .expect("get_account")
         .expect("associated_account not none");
-    
-    assert_eq!(sol_box_acct.data.len(), SolBox::get_packed_len());
-    let null_messages = SolBox::get_empty_message_slots();
+    (sol_box_pubkey, payer.pubkey(), sol_box_acct)
+}
+
+#[tokio::test]
+async fn test_init_sol_box() {
+    let (sol_box_pubkey, owner_pubkey, sol_box_acct) = create_sol_box(DEFAULT_SOL_BOX_NUM_SPOTS).await;
+
+    assert_eq!(sol_box_acct.data.len(), SolBox::get_packed_len(DEFAULT_SOL_BOX_NUM_SPOTS));
+    assert_eq!(Rent::default().minimum_balance(sol_box_acct.data.len()), sol_box_acct.lamports);
+    let null_messages = SolBox::get_empty_message_slots(DEFAULT_SOL_BOX_NUM_SPOTS);
     let sol_box_state = SolBox {
-        owner: payer.pubkey(),
-        next_box: sol_box_pair.pubkey(),
-        prev_box: sol_box_pair.pubkey(),
-        num_spots: 20 as u32,
-        num_in_use: 0 as u32,
+        owner: owner_pubkey,
+        next_box: sol_box_pubkey,
+        prev_box: sol_box_pubkey,
+        num_spots: DEFAULT_SOL_BOX_NUM_SPOTS,
+        num_in_use: 0,
         is_initialized: true,
+        message_seq: 0,
+        pending_owner: Pubkey::default(),
+        access_list: Pubkey::default(),
+        postage_lamports: 0,
+        postage_mint: Pubkey::default(),
+        postage_token_amount: 0,
+        encryption_key: [0; 32],
+        read_receipts: false,
+        occupied: SolBox::get_empty_occupancy(DEFAULT_SOL_BOX_NUM_SPOTS),
         message_slots: null_messages,
     };
 
-    let data_src = array_ref![&sol_box_acct.data[..], 0, 96];
+    // Skip the account tag and version
+    let data_src = array_ref![&sol_box_acct.data[..], 2, 96];
     let (owner_src, next_box_src, prev_box_src) 
         = array_refs![data_src, 32, 32, 32];
 
-    let owner = Pubkey::new(owner_src);
-    assert_eq!(payer.pubkey(), owner);
+    let owner = Pubkey::new_from_array(*owner_src);
+    assert_eq!(owner_pubkey, owner);
 
-    let next_box = Pubkey::new(next_box_src);
-    assert_eq!(sol_box_pair.pubkey(), next_box);
+    let next_box = Pubkey::new_from_array(*next_box_src);
+    assert_eq!(sol_box_pubkey, next_box);
 
-    let prev_box = Pubkey::new(prev_box_src);
-    assert_eq!(sol_box_pair.pubkey(), prev_box);
+    let prev_box = Pubkey::new_from_array(*prev_box_src);
+    assert_eq!(sol_box_pubkey, prev_box);
 
     let recreated_data = SolBox::unpack_from_slice(&sol_box_acct.data[..]).unwrap();
     assert_eq!(sol_box_state, recreated_data);
 }
 
 #[tokio::test]
-async fn test_write_message() {
-    let program_id = Pubkey::from_str(&"invoker111111111111111111111111111111111111").unwrap();
-    let sol_box_pair = Keypair::new();
-    let message_pair = Keypair::new();
-    let payer_pair = Keypair::new();
-    let recipient = Pubkey::new_unique();
+async fn test_init_sol_box_borsh_encoded() {
+    let program_id = program_id();
 
-    let mut program_test = ProgramTest::new(
-        &"sol2sol",
-        program_id,
-        processor!(Processor::process_instruction),
+    let (mut banks_client, payer, recent_blockhash) = program_test(&program_id, &[], &[]).start().await;
+
+    let (sol_box_pubkey, _) = find_sol_box_address(&program_id, &payer.pubkey(), 0);
+    let mut init_sol_box_ix = init_sol_box(
+        &program_id,
+        &payer.pubkey(),
+        0,
+        MIN_SOL_BOX_NUM_SPOTS,
+    ).unwrap();
+    init_sol_box_ix.data = Sol2SolInstruction::unpack(&init_sol_box_ix.data).unwrap().pack_borsh();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[init_sol_box_ix],
+        Some(&payer.pubkey()),
     );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let sol_box_acct = banks_client.get_account(sol_box_pubkey).await.unwrap().unwrap();
+    let sol_box_state = SolBox::unpack(&sol_box_acct.data).unwrap();
+    assert_eq!(payer.pubkey(), sol_box_state.owner);
+    assert_eq!(MIN_SOL_BOX_NUM_SPOTS, sol_box_state.num_spots);
+}
+
+#[tokio::test]
+async fn test_init_prefunded_sol_box() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let (sol_box_pubkey, _) = find_sol_box_address(&program_id, &owner_pair.pubkey(), 0);
+
+    let mut program_test = program_test(&program_id, &[&owner_pair.pubkey()], &[]);
+    // Someone sent lamports to the address before the owner created the box
     program_test.add_account(
-        payer_pair.pubkey(), 
+        sol_box_pubkey,
         Account {
-            lamports: 1000000,
+            lamports: 1000,
             ..Account::default()
         },
     );
-    let null_messages = SolBox::get_empty_message_slots();
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let init_sol_box_ix = init_sol_box(
+        &program_id,
+        &owner_pair.pubkey(),
+        0,
+        MIN_SOL_BOX_NUM_SPOTS,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[init_sol_box_ix],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let sol_box_acct = banks_client.get_account(sol_box_pubkey).await.unwrap().unwrap();
+    assert_eq!(program_id, sol_box_acct.owner);
+    assert_eq!(Rent::default().minimum_balance(sol_box_acct.data.len()), sol_box_acct.lamports);
+    assert_eq!(owner_pair.pubkey(), SolBox::unpack(&sol_box_acct.data).unwrap().owner);
+
+    // The same index cannot be initialized twice
+    let init_sol_box_ix = init_sol_box(
+        &program_id,
+        &owner_pair.pubkey(),
+        0,
+        DEFAULT_SOL_BOX_NUM_SPOTS,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(
+        &[init_sol_box_ix],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(Sol2SolError::SolBoxAlreadyInUse as u32),
+        ),
+    );
+}
+
+#[tokio::test]
+async fn test_init_sol_box_rejects_links() {
+    let program_id = program_id();
+    let victim_box = Pubkey::new_unique();
+    let (mut banks_client, payer, recent_blockhash) = program_test(&program_id, &[], &[]).start().await;
+
+    // A new box cannot claim a place in someone else's chain
+    let (sol_box_pubkey, _) = find_sol_box_address(&program_id, &payer.pubkey(), 0);
+    for (next_box, prev_box) in [(sol_box_pubkey, victim_box), (victim_box, sol_box_pubkey)] {
+        let mut init_sol_box_ix = init_sol_box(
+            &program_id,
+            &payer.pubkey(),
+            0,
+            MIN_SOL_BOX_NUM_SPOTS,
+        ).unwrap();
+        init_sol_box_ix.data = Sol2SolInstruction::InitializeSolBox {
+            owner: payer.pubkey(),
+            num_spots: MIN_SOL_BOX_NUM_SPOTS,
+            next_box,
+            prev_box,
+            index: 0,
+        }.pack();
+        let mut transaction = Transaction::new_with_payer(
+            &[init_sol_box_ix],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer], recent_blockhash);
+        assert_eq!(
+            banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            TransactionError::InstructionError(
+                0,
+                InstructionError::Custom(Sol2SolError::InvalidSolBoxLink as u32),
+            ),
+        );
+    }
+    assert_eq!(None, banks_client.get_account(sol_box_pubkey).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_init_small_sol_box() {
+    let (_, _, sol_box_acct) = create_sol_box(MIN_SOL_BOX_NUM_SPOTS).await;
+
+    assert_eq!(sol_box_acct.data.len(), SolBox::get_packed_len(MIN_SOL_BOX_NUM_SPOTS));
+    let recreated_data = SolBox::unpack(&sol_box_acct.data[..]).unwrap();
+    assert_eq!(MIN_SOL_BOX_NUM_SPOTS, recreated_data.num_spots);
+    assert_eq!(SolBox::get_empty_message_slots(MIN_SOL_BOX_NUM_SPOTS), recreated_data.message_slots);
+}
+
+#[tokio::test]
+async fn test_init_largest_sol_box() {
+    let (_, _, sol_box_acct) = create_sol_box(MAX_SOL_BOX_NUM_SPOTS).await;
+
+    assert_eq!(sol_box_acct.data.len(), SolBox::get_packed_len(MAX_SOL_BOX_NUM_SPOTS));
+    assert!(sol_box_acct.data.len() <= MAX_PERMITTED_DATA_INCREASE);
+    assert_eq!(MAX_SOL_BOX_NUM_SPOTS, SolBox::unpack(&sol_box_acct.data[..]).unwrap().num_spots);
+}
+
+/// Adds an initialized, empty sol box owned by `owner` to the test validator
+fn add_sol_box_account(program_test: &mut ProgramTest, program_id: &Pubkey, sol_box: &Pubkey, owner: &Pubkey) {
     let sol_box_state = SolBox {
-        owner: payer_pair.pubkey(),
-        next_box: sol_box_pair.pubkey(),
-        prev_box: sol_box_pair.pubkey(),
-        num_spots: 20 as u32,
-        num_in_use: 0 as u32,
+        owner: *owner,
+        next_box: *sol_box,
+        prev_box: *sol_box,
+        num_spots: DEFAULT_SOL_BOX_NUM_SPOTS,
+        num_in_use: 0,
         is_initialized: true,
-        message_slots: null_messages,
+        message_seq: 0,
+        pending_owner: Pubkey::default(),
+        access_list: Pubkey::default(),
+        postage_lamports: 0,
+        postage_mint: Pubkey::default(),
+        postage_token_amount: 0,
+        encryption_key: [0; 32],
+        read_receipts: false,
+        occupied: SolBox::get_empty_occupancy(DEFAULT_SOL_BOX_NUM_SPOTS),
+        message_slots: SolBox::get_empty_message_slots(DEFAULT_SOL_BOX_NUM_SPOTS),
     };
-    let sol_box_dst: &mut [u8; SolBox::LEN] = &mut [0; SolBox::LEN];
+    let sol_box_dst: &mut [u8] = &mut vec![0; SolBox::get_packed_len(DEFAULT_SOL_BOX_NUM_SPOTS)];
     sol_box_state.pack_into_slice(sol_box_dst);
     program_test.add_account(
-        sol_box_pair.pubkey(), 
+        *sol_box,
         Account {
             lamports: 20,
             data: sol_box_dst.to_vec(),
-            owner: program_id,
+            owner: *program_id,
             ..Account::default()
         },
     );
-    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+}
 
-    let msg_string = "penis!".to_string();
-    let msg_size: u32 = 6;
-    let rent = banks_client.get_rent().await.unwrap();
-    let create_account_ix = system_instruction::create_account(
-        &payer_pair.pubkey(),
-        &message_pair.pubkey(),
-        rent.minimum_balance(10),
-        74,
+/// Builds the WriteMessage transaction a sender submits
+fn write_message_transaction(
+    payer: &Keypair,
+    sender_pair: &Keypair,
+    recipient: &Pubkey,
+    sol_box: &Pubkey,
+    message_seq: u64,
+    msg_string: &str,
+    recent_blockhash: solana_program::hash::Hash,
+) -> Transaction {
+    let write_message_ix = write_message(
+        &program_id(),
+        &sender_pair.pubkey(),
+        recipient,
+        sol_box,
+        message_seq,
+        msg_string.as_bytes(),
+        None,
+        None,
+    ).unwrap();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[write_message_ix],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[
+        payer,
+        sender_pair,
+    ], recent_blockhash);
+    transaction
+}
+
+#[tokio::test]
+async fn test_write_message() {
+    let program_id = program_id();
+    let sol_box_pair = Keypair::new();
+    let payer_pair = Keypair::new();
+    let recipient = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
         &program_id,
+        &[&payer_pair.pubkey()],
+        &[(&sol_box_pair.pubkey(), &recipient)],
+    ).start().await;
+
+    let msg_string = "hello!".to_string();
+    let rent = banks_client.get_rent().await.unwrap();
+    let transaction = write_message_transaction(
+        &payer,
+        &payer_pair,
+        &recipient,
+        &sol_box_pair.pubkey(),
+        0,
+        &msg_string,
+        recent_blockhash,
     );
+    banks_client.process_transaction(transaction).await.unwrap();
 
-    let write_message_ix = write_message(
+    let (message_pubkey, _) = find_message_address(&program_id, &sol_box_pair.pubkey(), 0);
+    let message_acct = banks_client.get_account(message_pubkey)
+        .await
+        .expect("get_account")
+        .expect("associated_account not none");
+    assert_eq!(FIXED_MSG_SIZE + 6, message_acct.data.len());
+    assert_eq!(rent.minimum_balance(FIXED_MSG_SIZE + 6), message_acct.lamports);
+    let message = Message::unpack(&message_acct.data).unwrap();
+    assert_eq!(Message::new(recipient, payer_pair.pubkey(), msg_string.clone().into_bytes()), message);
+
+    let sol_box_acct = banks_client.get_account(sol_box_pair.pubkey())
+        .await
+        .expect("get_account")
+        .expect("associated_account not none");
+    assert_eq!(SolBox::get_packed_len(DEFAULT_SOL_BOX_NUM_SPOTS), sol_box_acct.data.len());
+
+    let recreated_data = SolBox::unpack_from_slice(&sol_box_acct.data[..]).unwrap();
+    assert_eq!(Some(message_pubkey), recreated_data.message_slots[0]);
+    assert_eq!(1, recreated_data.message_seq);
+    assert_eq!(1, recreated_data.num_in_use);
+    assert!(recreated_data.holds(message.slot, &message_pubkey));
+
+    // A message longer than any hardcoded size gets its own, larger account
+    let long_msg_string = "x".repeat(500);
+    let transaction = write_message_transaction(
+        &payer,
+        &payer_pair,
+        &recipient,
+        &sol_box_pair.pubkey(),
+        1,
+        &long_msg_string,
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+    let (long_message_pubkey, _) = find_message_address(&program_id, &sol_box_pair.pubkey(), 1);
+    let long_message_acct = banks_client.get_account(long_message_pubkey).await.unwrap().unwrap();
+    assert_eq!(FIXED_MSG_SIZE + 500, long_message_acct.data.len());
+    assert_eq!(Ok(long_msg_string.as_str()), Message::unpack(&long_message_acct.data).unwrap().msg_str());
+
+    // Reusing a stale sequence number is rejected
+    let transaction = write_message_transaction(
+        &payer,
+        &payer_pair,
+        &recipient,
+        &sol_box_pair.pubkey(),
+        1,
+        &msg_string,
+        recent_blockhash,
+    );
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::InvalidInstructionData),
+    );
+}
+
+#[tokio::test]
+async fn test_write_message_to_box_not_owned_by_recipient() {
+    let program_id = program_id();
+    let sol_box_pair = Keypair::new();
+    let payer_pair = Keypair::new();
+    let recipient = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
         &program_id,
-        &payer_pair.pubkey(),
+        &[&payer_pair.pubkey()],
+        &[(&sol_box_pair.pubkey(), &Pubkey::new_unique())],
+    ).start().await;
+
+    let transaction = write_message_transaction(
+        &payer,
+        &payer_pair,
         &recipient,
         &sol_box_pair.pubkey(),
+        0,
+        "hello!",
+        recent_blockhash,
+    );
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(Sol2SolError::SolBoxUserOwnerMismatch as u32),
+        ),
+    );
+}
+
+#[tokio::test]
+async fn test_delete_message() {
+    let program_id = program_id();
+    let sol_box_pair = Keypair::new();
+    let message_pair = Keypair::new();
+    let owner_pair = Keypair::new();
+    let destination = Pubkey::new_unique();
+
+    let mut program_test = program_test(&program_id, &[], &[]);
+    let mut sol_box_state = SolBox {
+        owner: owner_pair.pubkey(),
+        next_box: sol_box_pair.pubkey(),
+        prev_box: sol_box_pair.pubkey(),
+        num_spots: DEFAULT_SOL_BOX_NUM_SPOTS,
+        num_in_use: 0,
+        is_initialized: true,
+        message_seq: 0,
+        pending_owner: Pubkey::default(),
+        access_list: Pubkey::default(),
+        postage_lamports: 0,
+        postage_mint: Pubkey::default(),
+        postage_token_amount: 0,
+        encryption_key: [0; 32],
+        read_receipts: false,
+        occupied: SolBox::get_empty_occupancy(DEFAULT_SOL_BOX_NUM_SPOTS),
+        message_slots: SolBox::get_empty_message_slots(DEFAULT_SOL_BOX_NUM_SPOTS),
+    };
+    sol_box_state.add_message(&message_pair.pubkey()).unwrap();
+    let sol_box_dst: &mut [u8] = &mut vec![0; SolBox::get_packed_len(DEFAULT_SOL_BOX_NUM_SPOTS)];
+    sol_box_state.pack_into_slice(sol_box_dst);
+    program_test.add_account(
+        sol_box_pair.pubkey(),
+        Account {
+            lamports: 1000000,
+            data: sol_box_dst.to_vec(),
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let msg_string = "hello!".to_string();
+    let message_dst = &mut [0; FIXED_MSG_SIZE + 6];
+    Message::new(owner_pair.pubkey(), Pubkey::new_unique(), msg_string.into_bytes()).pack_into_slice(message_dst);
+    program_test.add_account(
+        message_pair.pubkey(),
+        Account {
+            lamports: 5000,
+            data: message_dst.to_vec(),
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        destination,
+        Account {
+            lamports: 1000000,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let delete_message_ix = delete_message(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box_pair.pubkey(),
         &message_pair.pubkey(),
-        msg_size,
-        &msg_string,
+        &destination,
     ).unwrap();
 
     let mut transaction = Transaction::new_with_payer(
-        &[
-            create_account_ix,
-            write_message_ix,
-        ],
+        &[delete_message_ix],
         Some(&payer.pubkey()),
     );
-    transaction.sign(&[
-        &payer, 
-        &payer_pair, 
-        &sol_box_pair, 
-        &message_pair, 
-    ], recent_blockhash);
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
     banks_client.process_transaction(transaction).await.unwrap();
 
+    // Drained accounts are purged once the transaction lands
     let message_acct = banks_client.get_account(message_pair.pubkey())
+        .await
+        .expect("get_account");
+    assert!(message_acct.is_none());
+
+    let destination_acct = banks_client.get_account(destination)
         .await
         .expect("get_account")
-        .expect("associated_account not none");
-    assert_eq!(74, message_acct.data.len());
+        .expect("destination_account not none");
+    assert_eq!(1005000, destination_acct.lamports);
 
     let sol_box_acct = banks_client.get_account(sol_box_pair.pubkey())
         .await
         .expect("get_account")
         .expect("associated_account not none");
-    assert_eq!(SolBox::get_packed_len(), sol_box_acct.data.len());
+    let recreated_data = SolBox::unpack_from_slice(&sol_box_acct.data[..]).unwrap();
+    assert_eq!(SolBox::get_empty_message_slots(DEFAULT_SOL_BOX_NUM_SPOTS), recreated_data.message_slots);
+    assert_eq!(0, recreated_data.num_in_use);
+    assert!(recreated_data.stored_messages().is_empty());
+}
 
-    // Todo(ngundotra): check that the message can be decoded lol
+#[tokio::test]
+async fn test_append_sol_box_and_write_to_tail() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let owner = owner_pair.pubkey();
+    let (root_box, _) = find_sol_box_address(&program_id, &owner, 0);
+    let (tail_box, _) = find_sol_box_address(&program_id, &owner, 1);
 
-    let recreated_data = SolBox::unpack_from_slice(&sol_box_acct.data[..]).unwrap();
-    assert_eq!(message_pair.pubkey(), recreated_data.message_slots[0]);
-}
\ No newline at end of file
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&owner, &sender_pair.pubkey()],
+        &[],
+    ).start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            init_sol_box(&program_id, &owner, 0, MIN_SOL_BOX_NUM_SPOTS).unwrap(),
+            append_sol_box(&program_id, &owner, 0, MIN_SOL_BOX_NUM_SPOTS).unwrap(),
+        ],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let root_box_state = SolBox::unpack(
+        &banks_client.get_account(root_box).await.unwrap().unwrap().data
+    ).unwrap();
+    assert_eq!(tail_box, root_box_state.next_box);
+    assert_eq!(root_box, root_box_state.prev_box);
+    let tail_box_acct = banks_client.get_account(tail_box).await.unwrap().unwrap();
+    assert_eq!(program_id, tail_box_acct.owner);
+    assert_eq!(Rent::default().minimum_balance(tail_box_acct.data.len()), tail_box_acct.lamports);
+    let tail_box_state = SolBox::unpack(&tail_box_acct.data).unwrap();
+    assert_eq!(tail_box, tail_box_state.next_box);
+    assert_eq!(root_box, tail_box_state.prev_box);
+    assert_eq!(owner, tail_box_state.owner);
+    assert_eq!(MIN_SOL_BOX_NUM_SPOTS, tail_box_state.num_spots);
+
+    let transaction = write_message_transaction(
+        &payer,
+        &sender_pair,
+        &owner,
+        &tail_box,
+        0,
+        "hello!",
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+    let tail_box_state = SolBox::unpack(
+        &banks_client.get_account(tail_box).await.unwrap().unwrap().data
+    ).unwrap();
+    let (message_pubkey, _) = find_message_address(&program_id, &tail_box, 0);
+    assert_eq!(Some(message_pubkey), tail_box_state.message_slots[0]);
+
+    // The box after the root already exists
+    let mut transaction = Transaction::new_with_payer(
+        &[append_sol_box(&program_id, &owner, 0, MIN_SOL_BOX_NUM_SPOTS).unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(Sol2SolError::SolBoxAlreadyInUse as u32),
+        ),
+    );
+
+    // The next box goes after the new tail, at index 2
+    let (extra_box, _) = find_sol_box_address(&program_id, &owner, 2);
+    let mut transaction = Transaction::new_with_payer(
+        &[append_sol_box(&program_id, &owner, 1, MIN_SOL_BOX_NUM_SPOTS).unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let tail_box_state = SolBox::unpack(
+        &banks_client.get_account(tail_box).await.unwrap().unwrap().data
+    ).unwrap();
+    assert_eq!(extra_box, tail_box_state.next_box);
+}
+
+
+/// Adds an initialized sol box linked between `prev_box` and `next_box`
+#[allow(clippy::too_many_arguments)]
+fn add_linked_sol_box_account(
+    program_test: &mut ProgramTest,
+    program_id: &Pubkey,
+    sol_box: &Pubkey,
+    prev_box: &Pubkey,
+    next_box: &Pubkey,
+    owner: &Pubkey,
+    messages: &[Pubkey],
+    lamports: u64,
+) {
+    let mut sol_box_state = SolBox {
+        owner: *owner,
+        next_box: *next_box,
+        prev_box: *prev_box,
+        num_spots: MIN_SOL_BOX_NUM_SPOTS,
+        num_in_use: 0,
+        is_initialized: true,
+        message_seq: 0,
+        pending_owner: Pubkey::default(),
+        access_list: Pubkey::default(),
+        postage_lamports: 0,
+        postage_mint: Pubkey::default(),
+        postage_token_amount: 0,
+        encryption_key: [0; 32],
+        read_receipts: false,
+        occupied: SolBox::get_empty_occupancy(MIN_SOL_BOX_NUM_SPOTS),
+        message_slots: SolBox::get_empty_message_slots(MIN_SOL_BOX_NUM_SPOTS),
+    };
+    for message in messages {
+        sol_box_state.add_message(message).unwrap();
+    }
+    let mut sol_box_dst = vec![0; SolBox::get_packed_len(MIN_SOL_BOX_NUM_SPOTS)];
+    SolBox::pack(sol_box_state, &mut sol_box_dst).unwrap();
+    program_test.add_account(
+        *sol_box,
+        Account {
+            lamports,
+            data: sol_box_dst,
+            owner: *program_id,
+            ..Account::default()
+        },
+    );
+}
+
+#[tokio::test]
+async fn test_close_sol_box() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let root = Pubkey::new_unique();
+    let middle = Pubkey::new_unique();
+    let tail = Pubkey::new_unique();
+    let message = Pubkey::new_unique();
+    let destination = Pubkey::new_unique();
+
+    let mut program_test = program_test(&program_id, &[], &[]);
+    add_linked_sol_box_account(&mut program_test, &program_id, &root, &root, &middle, &owner_pair.pubkey(), &[], 1000);
+    add_linked_sol_box_account(&mut program_test, &program_id, &middle, &root, &tail, &owner_pair.pubkey(), &[message], 2000);
+    add_linked_sol_box_account(&mut program_test, &program_id, &tail, &middle, &tail, &owner_pair.pubkey(), &[], 4000);
+    let message_dst = &mut [0; FIXED_MSG_SIZE + 6];
+    Message::new(owner_pair.pubkey(), Pubkey::new_unique(), b"hello!".to_vec()).pack_into_slice(message_dst);
+    program_test.add_account(
+        message,
+        Account {
+            lamports: 500,
+            data: message_dst.to_vec(),
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        destination,
+        Account {
+            lamports: 1000000,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let close = |sol_box: &Pubkey, prev_box: &Pubkey, next_box: &Pubkey, messages: &[Pubkey]| {
+        let close_sol_box_ix = close_sol_box(
+            &program_id,
+            &owner_pair.pubkey(),
+            sol_box,
+            prev_box,
+            next_box,
+            &destination,
+            messages,
+        ).unwrap();
+        let mut transaction = Transaction::new_with_payer(
+            &[close_sol_box_ix],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+        transaction
+    };
+
+    // Messages have to be deleted, or cascaded
+    assert_eq!(
+        banks_client.process_transaction(close(&middle, &root, &tail, &[])).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::SolBoxNotEmpty as u32)),
+    );
+    // The root can't strand the rest of its chain
+    assert_eq!(
+        banks_client.process_transaction(close(&root, &root, &middle, &[])).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::SolBoxIsChainRoot as u32)),
+    );
+
+    banks_client.process_transaction(close(&middle, &root, &tail, &[message])).await.unwrap();
+    assert!(banks_client.get_account(middle).await.unwrap().is_none());
+    assert!(banks_client.get_account(message).await.unwrap().is_none());
+    assert_eq!(1002500, banks_client.get_account(destination).await.unwrap().unwrap().lamports);
+
+    let root_box = SolBox::unpack(&banks_client.get_account(root).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(root, root_box.prev_box);
+    assert_eq!(tail, root_box.next_box);
+    let tail_box = SolBox::unpack(&banks_client.get_account(tail).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(root, tail_box.prev_box);
+    assert_eq!(tail, tail_box.next_box);
+
+    banks_client.process_transaction(close(&tail, &root, &tail, &[])).await.unwrap();
+    let root_box = SolBox::unpack(&banks_client.get_account(root).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(root, root_box.next_box);
+
+    banks_client.process_transaction(close(&root, &root, &root, &[])).await.unwrap();
+    assert!(banks_client.get_account(root).await.unwrap().is_none());
+    assert_eq!(1007500, banks_client.get_account(destination).await.unwrap().unwrap().lamports);
+}
+
+#[tokio::test]
+async fn test_close_sol_box_checks_neighbors() {
+    let program_id = program_id();
+    let attacker_pair = Keypair::new();
+    let victim = Pubkey::new_unique();
+    let victim_root = Pubkey::new_unique();
+    let victim_tail = Pubkey::new_unique();
+    let attacker_root = Pubkey::new_unique();
+    let forged = Pubkey::new_unique();
+    let stray = Pubkey::new_unique();
+
+    let mut program_test = program_test(&program_id, &[], &[]);
+    add_linked_sol_box_account(&mut program_test, &program_id, &victim_root, &victim_root, &victim_tail, &victim, &[], 1000);
+    add_linked_sol_box_account(&mut program_test, &program_id, &victim_tail, &victim_root, &victim_tail, &victim, &[], 1000);
+    add_linked_sol_box_account(&mut program_test, &program_id, &attacker_root, &attacker_root, &attacker_root, &attacker_pair.pubkey(), &[], 1000);
+    // Boxes claiming a predecessor that does not link to them
+    add_linked_sol_box_account(&mut program_test, &program_id, &forged, &victim_root, &forged, &attacker_pair.pubkey(), &[], 1000);
+    add_linked_sol_box_account(&mut program_test, &program_id, &stray, &attacker_root, &stray, &attacker_pair.pubkey(), &[], 1000);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    for (sol_box, prev_box, error) in [
+        (forged, victim_root, Sol2SolError::SolBoxUserOwnerMismatch),
+        (stray, attacker_root, Sol2SolError::InvalidSolBoxLink),
+    ] {
+        let close_sol_box_ix = close_sol_box(
+            &program_id,
+            &attacker_pair.pubkey(),
+            &sol_box,
+            &prev_box,
+            &sol_box,
+            &attacker_pair.pubkey(),
+            &[],
+        ).unwrap();
+        let mut transaction = Transaction::new_with_payer(
+            &[close_sol_box_ix],
+            Some(&payer.pubkey()),
+        );
+        transaction.sign(&[&payer, &attacker_pair], recent_blockhash);
+        assert_eq!(
+            banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(error as u32)),
+        );
+    }
+
+    let victim_box = SolBox::unpack(&banks_client.get_account(victim_root).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(victim_tail, victim_box.next_box);
+    let attacker_box = SolBox::unpack(&banks_client.get_account(attacker_root).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(attacker_root, attacker_box.next_box);
+}
+
+#[tokio::test]
+async fn test_transfer_sol_box_ownership() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let new_owner_pair = Keypair::new();
+    let final_owner_pair = Keypair::new();
+    let root = Pubkey::new_unique();
+    let tail = Pubkey::new_unique();
+    let derived_chain = |owner: &Pubkey| -> Vec<Pubkey> {
+        (0..2).map(|index| find_sol_box_address(&program_id, owner, index).0).collect()
+    };
+
+    let mut program_test = program_test(
+        &program_id,
+        &[&owner_pair.pubkey(), &new_owner_pair.pubkey(), &final_owner_pair.pubkey()],
+        &[],
+    );
+    add_linked_sol_box_account(&mut program_test, &program_id, &root, &root, &tail, &owner_pair.pubkey(), &[], 1000000);
+    add_linked_sol_box_account(&mut program_test, &program_id, &tail, &root, &tail, &owner_pair.pubkey(), &[], 1000000);
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // A transfer has to cover the whole chain
+    let transfer_ix = transfer_sol_box_ownership(
+        &program_id,
+        &owner_pair.pubkey(),
+        &new_owner_pair.pubkey(),
+        &[root],
+        None,
+        false,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[transfer_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::SolBoxNotChainTail as u32)),
+    );
+
+    // The chain moves to the new owner's derived addresses
+    let transfer_ix = transfer_sol_box_ownership(
+        &program_id,
+        &owner_pair.pubkey(),
+        &new_owner_pair.pubkey(),
+        &[root, tail],
+        None,
+        false,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[transfer_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    let result = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+    result.result.unwrap();
+    let new_chain = derived_chain(&new_owner_pair.pubkey());
+    assert_eq!(
+        vec![
+            Sol2SolEvent::BoxMoved { sol_box: root, new_sol_box: new_chain[0], prev_box: new_chain[0], index: 0 },
+            Sol2SolEvent::BoxMoved { sol_box: tail, new_sol_box: new_chain[1], prev_box: new_chain[0], index: 1 },
+            Sol2SolEvent::OwnershipTransferred {
+                sol_box: new_chain[0],
+                owner: owner_pair.pubkey(),
+                new_owner: new_owner_pair.pubkey(),
+            },
+        ],
+        decode_events(&program_id, &result.metadata.unwrap().log_messages),
+    );
+    for sol_box in [root, tail] {
+        assert!(banks_client.get_account(sol_box).await.unwrap().is_none());
+    }
+    for (i, sol_box) in new_chain.iter().enumerate() {
+        let sol_box_acct = banks_client.get_account(*sol_box).await.unwrap().unwrap();
+        assert_eq!(Rent::default().minimum_balance(sol_box_acct.data.len()), sol_box_acct.lamports);
+        let sol_box_state = SolBox::unpack(&sol_box_acct.data).unwrap();
+        assert_eq!(new_owner_pair.pubkey(), sol_box_state.owner);
+        assert_eq!(new_chain[i.saturating_sub(1)], sol_box_state.prev_box);
+        assert_eq!(new_chain[(i + 1).min(1)], sol_box_state.next_box);
+    }
+
+    // Two-step: nothing moves until the new owner accepts
+    let transfer_ix = transfer_sol_box_ownership(
+        &program_id,
+        &new_owner_pair.pubkey(),
+        &final_owner_pair.pubkey(),
+        &new_chain,
+        None,
+        true,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[transfer_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &new_owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let root_state = SolBox::unpack(&banks_client.get_account(new_chain[0]).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(new_owner_pair.pubkey(), root_state.owner);
+    assert_eq!(final_owner_pair.pubkey(), root_state.pending_owner);
+
+    let accept_ix = accept_sol_box_ownership(&program_id, &owner_pair.pubkey(), &new_chain, None).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[accept_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::OwnerMismatch as u32)),
+    );
+
+    let accept_ix = accept_sol_box_ownership(&program_id, &final_owner_pair.pubkey(), &new_chain, None).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[accept_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &final_owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let final_chain = derived_chain(&final_owner_pair.pubkey());
+    for sol_box in final_chain.iter() {
+        let sol_box_state = SolBox::unpack(&banks_client.get_account(*sol_box).await.unwrap().unwrap().data).unwrap();
+        assert_eq!(final_owner_pair.pubkey(), sol_box_state.owner);
+        assert_eq!(Pubkey::default(), sol_box_state.pending_owner);
+    }
+
+    // A wallet that already has an inbox gets the chain after its tail
+    let (owner_root, _) = find_sol_box_address(&program_id, &owner_pair.pubkey(), 0);
+    let mut transaction = Transaction::new_with_payer(&[
+        init_sol_box(&program_id, &owner_pair.pubkey(), 0, MIN_SOL_BOX_NUM_SPOTS).unwrap(),
+    ], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let transfer = |new_owner_tail: Option<(&Pubkey, u32)>| {
+        let transfer_ix = transfer_sol_box_ownership(
+            &program_id,
+            &final_owner_pair.pubkey(),
+            &owner_pair.pubkey(),
+            &final_chain,
+            new_owner_tail,
+            false,
+        ).unwrap();
+        let mut transaction = Transaction::new_with_payer(&[transfer_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &final_owner_pair], recent_blockhash);
+        transaction
+    };
+    assert_eq!(
+        banks_client.process_transaction(transfer(None)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::SolBoxAlreadyInUse as u32)),
+    );
+    // Only the new owner's own tail can take the chain
+    assert_eq!(
+        banks_client.process_transaction(transfer(Some((&final_chain[1], 0)))).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::SolBoxUserOwnerMismatch as u32)),
+    );
+    banks_client.process_transaction(transfer(Some((&owner_root, 0)))).await.unwrap();
+    let appended: Vec<Pubkey> = (1..3).map(|index| find_sol_box_address(&program_id, &owner_pair.pubkey(), index).0).collect();
+    let root_state = SolBox::unpack(&banks_client.get_account(owner_root).await.unwrap().unwrap().data).unwrap();
+    assert_eq!((owner_root, appended[0]), (root_state.prev_box, root_state.next_box));
+    for (i, sol_box) in appended.iter().enumerate() {
+        let sol_box_state = SolBox::unpack(&banks_client.get_account(*sol_box).await.unwrap().unwrap().data).unwrap();
+        assert_eq!(owner_pair.pubkey(), sol_box_state.owner);
+        assert_eq!([owner_root, appended[0]][i], sol_box_state.prev_box);
+        assert_eq!(appended[1], sol_box_state.next_box);
+    }
+}
+
+#[tokio::test]
+async fn test_transferred_messages() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let new_owner_pair = Keypair::new();
+    let sender = Pubkey::new_unique();
+    let reply_token = Pubkey::new_unique();
+    let sol_box = Pubkey::new_unique();
+    let (new_owner_root, _) = find_sol_box_address(&program_id, &new_owner_pair.pubkey(), 0);
+    let (new_sol_box, _) = find_sol_box_address(&program_id, &new_owner_pair.pubkey(), 1);
+    let anonymous = Pubkey::new_unique();
+    let legacy = Pubkey::new_unique();
+    let expiring = Pubkey::new_unique();
+
+    let mut program_test = program_test(&program_id, &[&owner_pair.pubkey(), &new_owner_pair.pubkey()], &[]);
+    add_linked_sol_box_account(&mut program_test, &program_id, &sol_box, &sol_box, &sol_box, &owner_pair.pubkey(), &[anonymous, legacy, expiring], 1000000);
+    add_linked_sol_box_account(&mut program_test, &program_id, &new_owner_root, &new_owner_root, &new_owner_root, &new_owner_pair.pubkey(), &[], 1000000);
+    // All three name the old owner as their recipient
+    let mut legacy_dst = vec![MESSAGE_TAG];
+    legacy_dst.extend_from_slice(owner_pair.pubkey().as_ref());
+    legacy_dst.extend_from_slice(sender.as_ref());
+    legacy_dst.extend_from_slice(&5u32.to_le_bytes());
+    legacy_dst.extend_from_slice(b"hello");
+    let messages = [
+        (anonymous, Message { slot: 0, ..Message::new_anonymous(owner_pair.pubkey(), reply_token, b"psst".to_vec()) }),
+        (expiring, Message { slot: 2, expiry: MessageExpiry::Slot(0), ..Message::new(owner_pair.pubkey(), sender, b"bye".to_vec()) }),
+    ];
+    let mut accounts: Vec<(Pubkey, Vec<u8>)> = messages.iter().map(|(pubkey, message)| {
+        let mut message_dst = vec![0; Message::get_packed_len(message.msg_size)];
+        message.pack_into_slice(&mut message_dst);
+        (*pubkey, message_dst)
+    }).collect();
+    accounts.push((legacy, legacy_dst));
+    for (pubkey, data) in accounts {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: Rent::default().minimum_balance(data.len()),
+                data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let transfer_ix = transfer_sol_box_ownership(
+        &program_id,
+        &owner_pair.pubkey(),
+        &new_owner_pair.pubkey(),
+        &[sol_box],
+        Some((&new_owner_root, 0)),
+        false,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[transfer_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    // Anonymous mail is answered by whoever owns the box now
+    let reply = |owner_pair: &Keypair| {
+        let reply_ix = reply_anonymous(
+            &program_id,
+            &owner_pair.pubkey(),
+            &new_sol_box,
+            &anonymous,
+            &reply_token,
+            b"who's this?",
+        ).unwrap();
+        let mut transaction = Transaction::new_with_payer(&[reply_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, owner_pair], recent_blockhash);
+        transaction
+    };
+    assert_eq!(
+        banks_client.process_transaction(reply(&owner_pair)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::OwnerMismatch as u32)),
+    );
+    banks_client.process_transaction(reply(&new_owner_pair)).await.unwrap();
+    let (reply_pubkey, _) = find_reply_address(&program_id, &reply_token);
+    let reply_state = Message::unpack(&banks_client.get_account(reply_pubkey).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(Message::new(reply_token, new_owner_pair.pubkey(), b"who's this?".to_vec()), reply_state);
+
+    // A version 0 message takes the box's new owner as its recipient
+    let mut transaction = Transaction::new_with_payer(
+        &[migrate_account(&program_id, &payer.pubkey(), &legacy, Some(&new_sol_box)).unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let legacy_state = Message::unpack(&banks_client.get_account(legacy).await.unwrap().unwrap().data).unwrap();
+    assert_eq!((new_owner_pair.pubkey(), 1), (legacy_state.recipient, legacy_state.slot));
+
+    // Purged rent still goes back to the sender
+    let expiring_lamports = banks_client.get_balance(expiring).await.unwrap();
+    let mut transaction = Transaction::new_with_payer(&[purge_expired(
+        &program_id,
+        &payer.pubkey(),
+        &new_sol_box,
+        &expiring,
+        &sender,
+    ).unwrap()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(expiring_lamports - PURGE_BOUNTY_LAMPORTS, banks_client.get_balance(sender).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_access_list() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+    let other_box = Pubkey::new_unique();
+    let (access_list, _) = find_access_list_address(&program_id, &sol_box);
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&owner_pair.pubkey(), &sender_pair.pubkey()],
+        &[(&sol_box, &owner_pair.pubkey()), (&other_box, &owner_pair.pubkey())],
+    ).start().await;
+
+    let owner_transaction = |instruction: Instruction| {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+        transaction
+    };
+    // Identical transactions are deduplicated, so every attempt says something new
+    let send = |message_seq: u64, msg_string: &str| {
+        let write_message_ix = write_message(
+            &program_id,
+            &sender_pair.pubkey(),
+            &owner_pair.pubkey(),
+            &sol_box,
+            message_seq,
+            msg_string.as_bytes(),
+            Some(&access_list),
+            None,
+        ).unwrap();
+        let mut transaction = Transaction::new_with_payer(&[write_message_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &sender_pair], recent_blockhash);
+        transaction
+    };
+    let not_allowed = TransactionError::InstructionError(
+        0,
+        InstructionError::Custom(Sol2SolError::SenderNotAllowed as u32),
+    );
+
+    banks_client.process_transaction(owner_transaction(init_access_list(
+        &program_id,
+        &owner_pair.pubkey(),
+        &[sol_box],
+        AccessListMode::Allowlist,
+        2,
+    ).unwrap())).await.unwrap();
+    let sol_box_state = SolBox::unpack(&banks_client.get_account(sol_box).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(access_list, sol_box_state.access_list);
+
+    // A second list can't take over a box that is already linked
+    assert_eq!(
+        banks_client.process_transaction(owner_transaction(init_access_list(
+            &program_id,
+            &owner_pair.pubkey(),
+            &[other_box, sol_box],
+            AccessListMode::Blocklist,
+            2,
+        ).unwrap())).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(Sol2SolError::AccessListAlreadyLinked as u32),
+        ),
+    );
+
+    // An empty allowlist keeps everyone out
+    assert_eq!(banks_client.process_transaction(send(0, "let me in")).await.unwrap_err().unwrap(), not_allowed);
+
+    banks_client.process_transaction(owner_transaction(add_access_list_entry(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &access_list,
+        &sender_pair.pubkey(),
+    ).unwrap())).await.unwrap();
+    banks_client.process_transaction(send(0, "thanks!")).await.unwrap();
+
+    // As a blocklist the same entry keeps the sender out
+    banks_client.process_transaction(owner_transaction(set_access_list_mode(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &access_list,
+        AccessListMode::Blocklist,
+    ).unwrap())).await.unwrap();
+    assert_eq!(banks_client.process_transaction(send(1, "hello again")).await.unwrap_err().unwrap(), not_allowed);
+
+    banks_client.process_transaction(owner_transaction(remove_access_list_entry(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &access_list,
+        &sender_pair.pubkey(),
+    ).unwrap())).await.unwrap();
+    banks_client.process_transaction(send(1, "unblocked")).await.unwrap();
+
+    let access_list_state = AccessList::unpack(
+        &banks_client.get_account(access_list).await.unwrap().unwrap().data
+    ).unwrap();
+    assert_eq!(AccessListMode::Blocklist, access_list_state.mode);
+    assert!(access_list_state.entries.is_empty());
+
+    // Senders can't skip the check by leaving the list out
+    let write_message_ix = write_message(
+        &program_id,
+        &sender_pair.pubkey(),
+        &owner_pair.pubkey(),
+        &sol_box,
+        2,
+        b"hello!",
+        None,
+        None,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[write_message_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &sender_pair], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::NotEnoughAccountKeys),
+    );
+}
+
+#[tokio::test]
+async fn test_postage() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let poor_sender_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+    let postage: u64 = 5000000;
+
+    let mut program_test = program_test(&program_id, &[], &[]);
+    for (wallet, lamports) in [(&owner_pair, 1000000), (&sender_pair, 1000000000), (&poor_sender_pair, 4000000)] {
+        program_test.add_account(
+            wallet.pubkey(),
+            Account {
+                lamports,
+                ..Account::default()
+            },
+        );
+    }
+    add_sol_box_account(&mut program_test, &program_id, &sol_box, &owner_pair.pubkey());
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let set_postage_ix = set_postage(&program_id, &owner_pair.pubkey(), &[sol_box], postage).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_postage_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let sol_box_state = SolBox::unpack(&banks_client.get_account(sol_box).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(postage, sol_box_state.postage_lamports);
+
+    let transaction = write_message_transaction(
+        &payer,
+        &poor_sender_pair,
+        &owner_pair.pubkey(),
+        &sol_box,
+        0,
+        "hello!",
+        recent_blockhash,
+    );
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::InsufficientFunds as u32)),
+    );
+
+    let transaction = write_message_transaction(
+        &payer,
+        &sender_pair,
+        &owner_pair.pubkey(),
+        &sol_box,
+        0,
+        "hello!",
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+    let rent = banks_client.get_rent().await.unwrap();
+    assert_eq!(1000000 + postage, banks_client.get_balance(owner_pair.pubkey()).await.unwrap());
+    assert_eq!(
+        1000000000 - postage - rent.minimum_balance(FIXED_MSG_SIZE + 6),
+        banks_client.get_balance(sender_pair.pubkey()).await.unwrap()
+    );
+}
+
+/// Adds an initialized spl token account holding `amount` of `mint`
+fn add_token_account(
+    program_test: &mut ProgramTest,
+    token_account: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+) {
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint: *mint,
+        owner: *owner,
+        amount,
+        state: spl_token::state::AccountState::Initialized,
+        ..spl_token::state::Account::default()
+    }.pack_into_slice(&mut data);
+    program_test.add_account(
+        *token_account,
+        Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner: spl_token::id(),
+            ..Account::default()
+        },
+    );
+}
+
+#[tokio::test]
+async fn test_token_postage() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+    let other_mint = Pubkey::new_unique();
+    let sender_token = Pubkey::new_unique();
+    let sender_other_token = Pubkey::new_unique();
+    let owner_token = Pubkey::new_unique();
+    let postage: u64 = 7;
+
+    let mut program_test = program_test(&program_id, &[&sender_pair.pubkey()], &[]);
+    let mut mint_data = vec![0; spl_token::state::Mint::LEN];
+    spl_token::state::Mint {
+        mint_authority: COption::Some(owner_pair.pubkey()),
+        supply: 100,
+        decimals: 0,
+        is_initialized: true,
+        freeze_authority: COption::None,
+    }.pack_into_slice(&mut mint_data);
+    program_test.add_account(
+        mint,
+        Account {
+            lamports: Rent::default().minimum_balance(mint_data.len()),
+            data: mint_data,
+            owner: spl_token::id(),
+            ..Account::default()
+        },
+    );
+    add_token_account(&mut program_test, &sender_token, &mint, &sender_pair.pubkey(), 10);
+    add_token_account(&mut program_test, &sender_other_token, &other_mint, &sender_pair.pubkey(), 10);
+    add_token_account(&mut program_test, &owner_token, &mint, &owner_pair.pubkey(), 0);
+    add_sol_box_account(&mut program_test, &program_id, &sol_box, &owner_pair.pubkey());
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let set_token_postage_ix = set_token_postage(
+        &program_id,
+        &owner_pair.pubkey(),
+        &[sol_box],
+        &mint,
+        postage,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_token_postage_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let sol_box_state = SolBox::unpack(&banks_client.get_account(sol_box).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(mint, sol_box_state.postage_mint);
+    assert_eq!(postage, sol_box_state.postage_token_amount);
+
+    let send = |message_seq: u64, msg_string: &str, source: &Pubkey| {
+        let write_message_ix = write_message(
+            &program_id,
+            &sender_pair.pubkey(),
+            &owner_pair.pubkey(),
+            &sol_box,
+            message_seq,
+            msg_string.as_bytes(),
+            None,
+            Some((source, &owner_token)),
+        ).unwrap();
+        let mut transaction = Transaction::new_with_payer(&[write_message_ix], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &sender_pair], recent_blockhash);
+        transaction
+    };
+    let token_amount = |account: Account| spl_token::state::Account::unpack(&account.data).unwrap().amount;
+
+    assert_eq!(
+        banks_client.process_transaction(send(0, "wrong coin", &sender_other_token)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::PostageMintMismatch as u32)),
+    );
+
+    banks_client.process_transaction(send(0, "paid up", &sender_token)).await.unwrap();
+    assert_eq!(10 - postage, token_amount(banks_client.get_account(sender_token).await.unwrap().unwrap()));
+    assert_eq!(postage, token_amount(banks_client.get_account(owner_token).await.unwrap().unwrap()));
+
+    // Only 3 tokens left, not enough for a second stamp
+    assert_eq!(
+        banks_client.process_transaction(send(1, "one more", &sender_token)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::InsufficientFunds as u32)),
+    );
+}
+
+#[tokio::test]
+async fn test_encrypted_messages() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+    let encryption_key = [5; 32];
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&sender_pair.pubkey()],
+        &[(&sol_box, &owner_pair.pubkey())],
+    ).start().await;
+
+    let set_encryption_key_ix = set_encryption_key(
+        &program_id,
+        &owner_pair.pubkey(),
+        &[sol_box],
+        &encryption_key,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[set_encryption_key_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    let sol_box_state = SolBox::unpack(&banks_client.get_account(sol_box).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(encryption_key, sol_box_state.encryption_key);
+
+    // Plaintext no longer gets in
+    let transaction = write_message_transaction(
+        &payer,
+        &sender_pair,
+        &owner_pair.pubkey(),
+        &sol_box,
+        0,
+        "hello!",
+        recent_blockhash,
+    );
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::InvalidMessageEncoding as u32)),
+    );
+
+    // The ciphertext is stored as is, whatever bytes it holds
+    let envelope = Envelope {
+        version: ENVELOPE_VERSION,
+        ephemeral_key: [1; 32],
+        nonce: [2; 24],
+        ciphertext: vec![0xff; ENVELOPE_TAG_LEN + 6],
+    };
+    let write_message_ix = write_message(
+        &program_id,
+        &sender_pair.pubkey(),
+        &owner_pair.pubkey(),
+        &sol_box,
+        0,
+        &envelope.pack(),
+        None,
+        None,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[write_message_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &sender_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (message_pubkey, _) = find_message_address(&program_id, &sol_box, 0);
+    let message = Message::unpack(&banks_client.get_account(message_pubkey).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(envelope, Envelope::unpack(&message.msg_data).unwrap());
+}
+
+#[tokio::test]
+async fn test_anonymous_messages() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let relayer_pair = Keypair::new();
+    let reply_token_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&owner_pair.pubkey(), &relayer_pair.pubkey()],
+        &[(&sol_box, &owner_pair.pubkey())],
+    ).start().await;
+
+    let write_anonymous_message_ix = write_anonymous_message(
+        &program_id,
+        &relayer_pair.pubkey(),
+        &owner_pair.pubkey(),
+        &sol_box,
+        0,
+        &reply_token_pair.pubkey(),
+        b"guess who",
+        None,
+    ).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[write_anonymous_message_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &relayer_pair], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let (message_pubkey, _) = find_message_address(&program_id, &sol_box, 0);
+    let message_acct = banks_client.get_account(message_pubkey).await.unwrap().unwrap();
+    assert_eq!(
+        Message::new_anonymous(owner_pair.pubkey(), reply_token_pair.pubkey(), b"guess who".to_vec()),
+        Message::unpack(&message_acct.data).unwrap(),
+    );
+    assert!(!message_acct.data.windows(32).any(|window| window == relayer_pair.pubkey().as_ref()));
+
+    let owner_transaction = |instruction: Instruction| {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+        transaction
+    };
+    banks_client.process_transaction(owner_transaction(reply_anonymous(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &message_pubkey,
+        &reply_token_pair.pubkey(),
+        b"you!",
+    ).unwrap())).await.unwrap();
+    let (reply_pubkey, _) = find_reply_address(&program_id, &reply_token_pair.pubkey());
+    let reply = Message::unpack(&banks_client.get_account(reply_pubkey).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(Message::new(reply_token_pair.pubkey(), owner_pair.pubkey(), b"you!".to_vec()), reply);
+
+    // Each token is answered once
+    assert_eq!(
+        banks_client.process_transaction(owner_transaction(reply_anonymous(
+            &program_id,
+            &owner_pair.pubkey(),
+            &sol_box,
+            &message_pubkey,
+            &reply_token_pair.pubkey(),
+            b"you again!",
+        ).unwrap())).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::MessageAccountInUse as u32)),
+    );
+
+    // Signed messages have no token to answer through
+    let transaction = write_message_transaction(
+        &payer,
+        &relayer_pair,
+        &owner_pair.pubkey(),
+        &sol_box,
+        1,
+        "it's me",
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+    let (signed_message_pubkey, _) = find_message_address(&program_id, &sol_box, 1);
+    assert_eq!(
+        banks_client.process_transaction(owner_transaction(reply_anonymous(
+            &program_id,
+            &owner_pair.pubkey(),
+            &sol_box,
+            &signed_message_pubkey,
+            &relayer_pair.pubkey(),
+            b"hi",
+        ).unwrap())).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::MessageNotAnonymous as u32)),
+    );
+
+    // Nothing records who funded an anonymous message, so it cannot expire
+    for (authority_pair, authority) in [(&owner_pair, owner_pair.pubkey()), (&reply_token_pair, reply_token_pair.pubkey())] {
+        let mut transaction = Transaction::new_with_payer(&[set_message_expiry(
+            &program_id,
+            &authority,
+            &sol_box,
+            &message_pubkey,
+            MessageExpiry::Slot(0),
+        ).unwrap()], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, authority_pair], recent_blockhash);
+        assert_eq!(
+            banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::MessageIsAnonymous as u32)),
+        );
+    }
+
+    let reply_lamports = banks_client.get_balance(reply_pubkey).await.unwrap();
+    let delete_reply_ix = delete_reply(&program_id, &reply_token_pair.pubkey(), &relayer_pair.pubkey()).unwrap();
+    let mut transaction = Transaction::new_with_payer(&[delete_reply_ix], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &reply_token_pair], recent_blockhash);
+    let relayer_lamports = banks_client.get_balance(relayer_pair.pubkey()).await.unwrap();
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(relayer_lamports + reply_lamports, banks_client.get_balance(relayer_pair.pubkey()).await.unwrap());
+    assert!(banks_client.get_account(reply_pubkey).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_message_threads() {
+    let program_id = program_id();
+    let alice_pair = Keypair::new();
+    let bob_pair = Keypair::new();
+    let alice_box = Pubkey::new_unique();
+    let bob_box = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&alice_pair.pubkey(), &bob_pair.pubkey()],
+        &[(&alice_box, &alice_pair.pubkey()), (&bob_box, &bob_pair.pubkey())],
+    ).start().await;
+
+    let transaction = write_message_transaction(
+        &payer,
+        &alice_pair,
+        &bob_pair.pubkey(),
+        &bob_box,
+        0,
+        "hello",
+        recent_blockhash,
+    );
+    banks_client.process_transaction(transaction).await.unwrap();
+    let (hello_pubkey, _) = find_message_address(&program_id, &bob_box, 0);
+    let hello = Message::unpack(&banks_client.get_account(hello_pubkey).await.unwrap().unwrap().data).unwrap();
+
+    let bob_transaction = |instruction: Instruction| {
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &bob_pair], recent_blockhash);
+        transaction
+    };
+    banks_client.process_transaction(bob_transaction(write_reply(
+        &program_id,
+        &bob_pair.pubkey(),
+        &alice_pair.pubkey(),
+        &alice_box,
+        0,
+        b"hi",
+        &hello_pubkey,
+        &hello,
+        None,
+        None,
+    ).unwrap())).await.unwrap();
+    let (hi_pubkey, _) = find_message_address(&program_id, &alice_box, 0);
+    let hi = Message::unpack(&banks_client.get_account(hi_pubkey).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(
+        Message::new_reply(alice_pair.pubkey(), bob_pair.pubkey(), b"hi".to_vec(), hello_pubkey, hello_pubkey),
+        hi,
+    );
+
+    // A reply must point at a message, and carry that message's thread
+    let not_a_message = Message::new(alice_pair.pubkey(), bob_pair.pubkey(), b"fake".to_vec());
+    let invalid_thread = TransactionError::InstructionError(
+        0,
+        InstructionError::Custom(Sol2SolError::InvalidThreadReference as u32),
+    );
+    assert_eq!(
+        banks_client.process_transaction(bob_transaction(write_reply(
+            &program_id,
+            &bob_pair.pubkey(),
+            &alice_pair.pubkey(),
+            &alice_box,
+            1,
+            b"hi?",
+            &bob_box,
+            &not_a_message,
+            None,
+            None,
+        ).unwrap())).await.unwrap_err().unwrap(),
+        invalid_thread,
+    );
+    let mut wrong_root = write_reply(
+        &program_id,
+        &bob_pair.pubkey(),
+        &alice_pair.pubkey(),
+        &alice_box,
+        1,
+        b"hi!",
+        &hello_pubkey,
+        &hello,
+        None,
+        None,
+    ).unwrap();
+    let root_offset = wrong_root.data.len() - 32;
+    wrong_root.data[root_offset..].copy_from_slice(bob_box.as_ref());
+    assert_eq!(
+        banks_client.process_transaction(bob_transaction(wrong_root)).await.unwrap_err().unwrap(),
+        invalid_thread,
+    );
+
+    let mut accounts = std::collections::HashMap::new();
+    for key in [alice_box, bob_box, hello_pubkey, hi_pubkey] {
+        accounts.insert(key, banks_client.get_account(key).await.unwrap().unwrap().data);
+    }
+    let threads = build_threads(&[bob_box, alice_box], |key| accounts.get(key).cloned()).unwrap();
+    assert_eq!(1, threads.len());
+    assert_eq!(hello_pubkey, threads[0].message_pubkey);
+    assert_eq!(hi_pubkey, threads[0].replies[0].message_pubkey);
+}
+
+#[tokio::test]
+async fn test_mark_messages() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&sender_pair.pubkey()],
+        &[(&sol_box, &owner_pair.pubkey())],
+    ).start().await;
+
+    for (message_seq, msg_string) in ["first", "second"].iter().enumerate() {
+        let transaction = write_message_transaction(
+            &payer,
+            &sender_pair,
+            &owner_pair.pubkey(),
+            &sol_box,
+            message_seq as u64,
+            msg_string,
+            recent_blockhash,
+        );
+        banks_client.process_transaction(transaction).await.unwrap();
+    }
+    let messages = [
+        find_message_address(&program_id, &sol_box, 0).0,
+        find_message_address(&program_id, &sol_box, 1).0,
+    ];
+    let owner_transaction = |instructions: &[Instruction]| {
+        let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+        transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+        transaction
+    };
+    let receipts = |log_messages: &[String]| decode_events(&program_id, log_messages)
+        .into_iter()
+        .filter_map(|event| match event {
+            Sol2SolEvent::ReadReceipt { message, .. } => Some(message),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // Without read receipts marking is silent
+    let result = banks_client.process_transaction_with_metadata(owner_transaction(&[mark_messages(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &messages[..1],
+        MESSAGE_STATUS_READ,
+        0,
+    ).unwrap()])).await.unwrap();
+    result.result.unwrap();
+    assert!(receipts(&result.metadata.unwrap().log_messages).is_empty());
+
+    let result = banks_client.process_transaction_with_metadata(owner_transaction(&[
+        set_read_receipts(&program_id, &owner_pair.pubkey(), &[sol_box], true).unwrap(),
+        mark_messages(
+            &program_id,
+            &owner_pair.pubkey(),
+            &sol_box,
+            &messages,
+            MESSAGE_STATUS_READ | MESSAGE_STATUS_STARRED,
+            0,
+        ).unwrap(),
+    ])).await.unwrap();
+    result.result.unwrap();
+    // Only the message read for the first time gets a receipt
+    assert_eq!(vec![messages[1]], receipts(&result.metadata.unwrap().log_messages));
+
+    for message_pubkey in messages {
+        let message = Message::unpack(&banks_client.get_account(message_pubkey).await.unwrap().unwrap().data).unwrap();
+        assert_eq!(MESSAGE_STATUS_READ | MESSAGE_STATUS_STARRED, message.status);
+    }
+
+    banks_client.process_transaction(owner_transaction(&[mark_messages(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &messages[..1],
+        0,
+        MESSAGE_STATUS_STARRED,
+    ).unwrap()])).await.unwrap();
+    let message = Message::unpack(&banks_client.get_account(messages[0]).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(MESSAGE_STATUS_READ, message.status);
+
+    // Only the box owner marks, and only messages in the box
+    let mut transaction = Transaction::new_with_payer(&[mark_messages(
+        &program_id,
+        &sender_pair.pubkey(),
+        &sol_box,
+        &messages,
+        0,
+        MESSAGE_STATUS_READ,
+    ).unwrap()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &sender_pair], recent_blockhash);
+    assert_eq!(
+        banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::SolBoxUserOwnerMismatch as u32)),
+    );
+    assert_eq!(
+        banks_client.process_transaction(owner_transaction(&[mark_messages(
+            &program_id,
+            &owner_pair.pubkey(),
+            &sol_box,
+            &[sol_box],
+            0,
+            MESSAGE_STATUS_READ,
+        ).unwrap()])).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::MessageNotInSolBox as u32)),
+    );
+}
+
+#[tokio::test]
+async fn test_purge_expired() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let caller_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+
+    let mut context = program_test(
+        &program_id,
+        &[&sender_pair.pubkey(), &caller_pair.pubkey()],
+        &[(&sol_box, &owner_pair.pubkey())],
+    ).start_with_context().await;
+
+    let transaction = write_message_transaction(
+        &context.payer,
+        &sender_pair,
+        &owner_pair.pubkey(),
+        &sol_box,
+        0,
+        "self-destructs",
+        context.last_blockhash,
+    );
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let (message_pubkey, _) = find_message_address(&program_id, &sol_box, 0);
+
+    // Strangers can't set an expiry, but the sender can take the message
+    // back by expiring it
+    let mut transaction = Transaction::new_with_payer(&[set_message_expiry(
+        &program_id,
+        &caller_pair.pubkey(),
+        &sol_box,
+        &message_pubkey,
+        MessageExpiry::Slot(0),
+    ).unwrap()], Some(&context.payer.pubkey()));
+    transaction.sign(&[&context.payer, &caller_pair], context.last_blockhash);
+    assert_eq!(
+        context.banks_client.process_transaction(transaction).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::SenderMismatch as u32)),
+    );
+
+    let mut transaction = Transaction::new_with_payer(&[set_message_expiry(
+        &program_id,
+        &sender_pair.pubkey(),
+        &sol_box,
+        &message_pubkey,
+        MessageExpiry::Slot(50),
+    ).unwrap()], Some(&context.payer.pubkey()));
+    transaction.sign(&[&context.payer, &sender_pair], context.last_blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+    let message = Message::unpack(&context.banks_client.get_account(message_pubkey).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(MessageExpiry::Slot(50), message.expiry);
+
+    let purge = |recent_blockhash, payer: &Keypair| {
+        let mut transaction = Transaction::new_with_payer(&[purge_expired(
+            &program_id,
+            &caller_pair.pubkey(),
+            &sol_box,
+            &message_pubkey,
+            &sender_pair.pubkey(),
+        ).unwrap()], Some(&payer.pubkey()));
+        transaction.sign(&[payer], recent_blockhash);
+        transaction
+    };
+    assert_eq!(
+        context.banks_client.process_transaction(purge(context.last_blockhash, &context.payer)).await.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, InstructionError::Custom(Sol2SolError::MessageNotExpired as u32)),
+    );
+
+    context.warp_to_slot(60).unwrap();
+    let recent_blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let message_lamports = context.banks_client.get_balance(message_pubkey).await.unwrap();
+    let sender_lamports = context.banks_client.get_balance(sender_pair.pubkey()).await.unwrap();
+    let caller_lamports = context.banks_client.get_balance(caller_pair.pubkey()).await.unwrap();
+    context.banks_client.process_transaction(purge(recent_blockhash, &context.payer)).await.unwrap();
+
+    assert!(context.banks_client.get_account(message_pubkey).await.unwrap().is_none());
+    assert_eq!(
+        caller_lamports + PURGE_BOUNTY_LAMPORTS,
+        context.banks_client.get_balance(caller_pair.pubkey()).await.unwrap()
+    );
+    assert_eq!(
+        sender_lamports + message_lamports - PURGE_BOUNTY_LAMPORTS,
+        context.banks_client.get_balance(sender_pair.pubkey()).await.unwrap()
+    );
+    let sol_box_state = SolBox::unpack(&context.banks_client.get_account(sol_box).await.unwrap().unwrap().data).unwrap();
+    assert!(sol_box_state.stored_messages().is_empty());
+}
+
+#[tokio::test]
+async fn test_migrate_baseline_sol_box() {
+    let program_id = program_id();
+    let sol_box = Pubkey::new_unique();
+    // A box as the original program wrote it, the same bytes as the
+    // `manual_sol_box_deserialize` fixture
+    let manual_bytes = vec![
+        0x00, 0xe7, 0x38, 0xcc, 0x07, 0x5d, 0xd3, 0xe1, 0xaf, 0x7f, 0x14, 0x4b, 0xcd, 0x39, 0x35, 0x21, 0x3c, 0xe1, 0x3f, 0x0a, 0x1e, 0x12, 0x22, 0x79, 0x87, 0x70, 0x0e, 0x95, 0xf6, 0xc9, 0x8a, 0x8f, 0x38, 0x73, 0xf4, 0xef, 0x5f, 0xe3, 0xdb, 0x6f, 0x25, 0x40, 0x2d, 0xc2, 0xce, 0x13, 0x71, 0x62, 0xbc, 0x74, 0x74, 0x4f, 0xde, 0x84, 0xac, 0xae, 0x6b, 0x6e, 0x04, 0xfe, 0x34, 0xa3, 0xf3, 0x83, 0xc4, 0x73, 0xf4, 0xef, 0x5f, 0xe3, 0xdb, 0x6f, 0x25, 0x40, 0x2d, 0xc2, 0xce, 0x13, 0x71, 0x62, 0xbc, 0x74, 0x74, 0x4f, 0xde, 0x84, 0xac, 0xae, 0x6b, 0x6e, 0x04, 0xfe, 0x34, 0xa3, 0xf3, 0x83, 0xc4, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9, 0x06, 0xdd, 0xf6, 0xe1, 0xd7, 0x65, 0xa1, 0x93, 0xd9, 0xcb, 0xe1, 0x46, 0xce, 0xeb, 0x79, 0xac, 0x1c, 0xb4, 0x85, 0xed, 0x5f, 0x5b, 0x37, 0x91, 0x3a, 0x8c, 0xf5, 0x85, 0x7e, 0xff, 0x00, 0xa9
+    ];
+    let owner = Pubkey::from_str("GZbQmKYYzwjP3nbdqRWPLn98ipAni9w5eXMGp7bmZbGB").unwrap();
+
+    let mut program_test = program_test(&program_id, &[], &[]);
+    program_test.add_account(
+        sol_box,
+        Account {
+            lamports: Rent::default().minimum_balance(manual_bytes.len()),
+            data: manual_bytes.clone(),
+            owner: program_id,
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[migrate_account(&program_id, &payer.pubkey(), &sol_box, None).unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    let result = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+    result.result.unwrap();
+    assert_eq!(
+        vec![Sol2SolEvent::AccountMigrated { account: sol_box, tag: SOL_BOX_TAG, from_version: 0 }],
+        decode_events(&program_id, &result.metadata.unwrap().log_messages),
+    );
+
+    let data = banks_client.get_account(sol_box).await.unwrap().unwrap().data;
+    assert_eq!(SolBox::get_packed_len(SOL_BOX_V0_NUM_SPOTS), data.len());
+    assert_eq!(Ok((SOL_BOX_TAG, ACCOUNT_VERSION)), unpack_account_prefix(&data));
+    let sol_box_state = SolBox::unpack(&data).unwrap();
+    assert_eq!(SolBox::unpack(&manual_bytes).unwrap(), sol_box_state);
+    assert_eq!(owner, sol_box_state.owner);
+    assert_eq!(SOL_BOX_V0_NUM_SPOTS, sol_box_state.num_spots);
+    assert!(sol_box_state.stored_messages().is_empty());
+    // The legacy empty slots are now written as zeros
+    assert!(data[data.len() - SOL_BOX_V0_NUM_SPOTS as usize * 32..].iter().all(|byte| *byte == 0));
+}
+
+#[tokio::test]
+async fn test_migrate_account() {
+    let program_id = program_id();
+    let owner = Pubkey::new_unique();
+    let sol_box = Pubkey::new_unique();
+    let message_pubkey = Pubkey::new_unique();
+
+    let mut program_test = program_test(&program_id, &[], &[]);
+
+    // Accounts in the version 0 layout, funded for their old size
+    let mut sol_box_dst = vec![SOL_BOX_TAG];
+    for key in [owner, sol_box, sol_box] {
+        sol_box_dst.extend_from_slice(key.as_ref());
+    }
+    sol_box_dst.extend_from_slice(&SOL_BOX_V0_NUM_SPOTS.to_le_bytes());
+    sol_box_dst.extend_from_slice(&0u32.to_le_bytes());
+    sol_box_dst.push(1);
+    sol_box_dst.extend_from_slice(message_pubkey.as_ref());
+    for _ in 1..SOL_BOX_V0_NUM_SPOTS {
+        sol_box_dst.extend_from_slice(LEGACY_EMPTY_SLOT.as_ref());
+    }
+    assert_eq!(SOL_BOX_V0_LEN, sol_box_dst.len());
+    let mut message_dst = vec![MESSAGE_TAG];
+    message_dst.extend_from_slice(owner.as_ref());
+    message_dst.extend_from_slice(Pubkey::new_unique().as_ref());
+    message_dst.extend_from_slice(&5u32.to_le_bytes());
+    message_dst.extend_from_slice(b"hello");
+    let sol_box_state = SolBox::unpack(&sol_box_dst).unwrap();
+    let message_state = Message {
+        slot: 0,
+        ..Message::unpack(&message_dst).unwrap()
+    };
+    for (pubkey, data) in [(sol_box, sol_box_dst), (message_pubkey, message_dst)] {
+        program_test.add_account(
+            pubkey,
+            Account {
+                lamports: Rent::default().minimum_balance(data.len()),
+                data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    // Legacy accounts are still readable before they are migrated
+    let data = banks_client.get_account(sol_box).await.unwrap().unwrap().data;
+    assert_eq!(Ok((SOL_BOX_TAG, 0)), unpack_account_prefix(&data));
+    assert_eq!(Some(message_pubkey), SolBox::unpack(&data).unwrap().message_slots[0]);
+
+    // A version 0 message needs its box to recover its slot
+    let mut transaction = Transaction::new_with_payer(
+        &[migrate_account(&program_id, &payer.pubkey(), &message_pubkey, None).unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_err());
+
+    let mut transaction = Transaction::new_with_payer(&[
+        migrate_account(&program_id, &payer.pubkey(), &message_pubkey, Some(&sol_box)).unwrap(),
+        migrate_account(&program_id, &payer.pubkey(), &sol_box, None).unwrap(),
+    ], Some(&payer.pubkey()));
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let sol_box_acct = banks_client.get_account(sol_box).await.unwrap().unwrap();
+    assert_eq!(SolBox::get_packed_len(SOL_BOX_V0_NUM_SPOTS), sol_box_acct.data.len());
+    assert_eq!([SOL_BOX_TAG | ACCOUNT_VERSIONED_FLAG, ACCOUNT_VERSION], sol_box_acct.data[..2]);
+    assert_eq!(Rent::default().minimum_balance(sol_box_acct.data.len()), sol_box_acct.lamports);
+    assert_eq!(sol_box_state, SolBox::unpack(&sol_box_acct.data).unwrap());
+
+    let message_acct = banks_client.get_account(message_pubkey).await.unwrap().unwrap();
+    assert_eq!(Message::get_packed_len(5), message_acct.data.len());
+    assert_eq!([MESSAGE_TAG | ACCOUNT_VERSIONED_FLAG, ACCOUNT_VERSION], message_acct.data[..2]);
+    assert_eq!(Rent::default().minimum_balance(message_acct.data.len()), message_acct.lamports);
+    assert_eq!(message_state, Message::unpack(&message_acct.data).unwrap());
+
+    // Migrating again leaves the account alone
+    let mut transaction = Transaction::new_with_payer(
+        &[migrate_account(&program_id, &payer.pubkey(), &sol_box, None).unwrap()],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(sol_box_acct, banks_client.get_account(sol_box).await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn test_events() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&owner_pair.pubkey(), &sender_pair.pubkey()],
+        &[(&sol_box, &owner_pair.pubkey())],
+    ).start().await;
+
+    let result = banks_client.process_transaction_with_metadata(write_message_transaction(
+        &payer,
+        &sender_pair,
+        &owner_pair.pubkey(),
+        &sol_box,
+        0,
+        "logged",
+        recent_blockhash,
+    )).await.unwrap();
+    let (message_pubkey, _) = find_message_address(&program_id, &sol_box, 0);
+    assert_eq!(
+        vec![Sol2SolEvent::MessageWritten {
+            sol_box,
+            message: message_pubkey,
+            recipient: owner_pair.pubkey(),
+            sender: sender_pair.pubkey(),
+            slot: 0,
+            message_seq: 0,
+            msg_size: 6,
+            is_anonymous: false,
+            reply_to: Pubkey::default(),
+            thread_root: Pubkey::default(),
+        }],
+        decode_events(&program_id, &result.metadata.unwrap().log_messages),
+    );
+
+    let mut transaction = Transaction::new_with_payer(&[
+        mark_messages(&program_id, &owner_pair.pubkey(), &sol_box, &[message_pubkey], MESSAGE_STATUS_READ, 0).unwrap(),
+        delete_message(&program_id, &owner_pair.pubkey(), &sol_box, &message_pubkey, &owner_pair.pubkey()).unwrap(),
+    ], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    let result = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+    let log_messages = result.metadata.unwrap().log_messages;
+    assert_eq!(
+        vec![
+            Sol2SolEvent::MessageStatusChanged {
+                sol_box,
+                message: message_pubkey,
+                status: MESSAGE_STATUS_READ,
+            },
+            Sol2SolEvent::MessageDeleted {
+                sol_box,
+                message: message_pubkey,
+            },
+        ],
+        decode_events(&program_id, &log_messages),
+    );
+    // Events are attributed to the program that logged them
+    assert!(decode_events(&Pubkey::new_unique(), &log_messages).is_empty());
+}
+
+/// Records the transactions and final accounts of a small conversation to
+/// `tests/fixtures/indexer/mailbox.txt`, which `tests/indexer.rs` replays
+///
+/// Re-record with `cargo test --features test-bpf --test client -- --ignored record_indexer_fixture`
+#[tokio::test]
+#[ignore]
+async fn record_indexer_fixture() {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    let program_id = program_id();
+    let bob_pair = Keypair::new();
+
+    let (mut banks_client, alice_pair, recent_blockhash) = program_test(
+        &program_id,
+        &[&bob_pair.pubkey()],
+        &[],
+    ).start().await;
+    let alice = alice_pair.pubkey();
+    let bob = bob_pair.pubkey();
+    let (alice_box, _) = find_sol_box_address(&program_id, &alice, 0);
+    let (bob_box, _) = find_sol_box_address(&program_id, &bob, 0);
+    let (hello_pubkey, _) = find_message_address(&program_id, &alice_box, 0);
+    let (hi_pubkey, _) = find_message_address(&program_id, &bob_box, 0);
+    let (second_pubkey, _) = find_message_address(&program_id, &alice_box, 1);
+
+    let mut fixture = format!("program {}\n", program_id);
+    let record = |fixture: &mut String, instructions: &[Instruction], log_messages: &[String]| {
+        fixture.push_str("tx\n");
+        for instruction in instructions {
+            let keys: Vec<String> = instruction.accounts.iter().map(|meta| meta.pubkey.to_string()).collect();
+            fixture.push_str(&format!("ix {} {}\n", STANDARD.encode(&instruction.data), keys.join(",")));
+        }
+        for log in log_messages {
+            fixture.push_str(&format!("log {}\n", log));
+        }
+    };
+
+    let hello = Message::new(alice, bob, b"hello alice".to_vec());
+    let steps: Vec<(Vec<Instruction>, &Keypair)> = vec![
+        (vec![init_sol_box(&program_id, &alice, 0, MIN_SOL_BOX_NUM_SPOTS).unwrap()], &alice_pair),
+        (vec![init_sol_box(&program_id, &bob, 0, MIN_SOL_BOX_NUM_SPOTS).unwrap()], &bob_pair),
+        (vec![write_message(&program_id, &bob, &alice, &alice_box, 0, b"hello alice", None, None).unwrap()], &bob_pair),
+        (vec![write_reply(&program_id, &alice, &bob, &bob_box, 0, b"hi bob", &hello_pubkey, &hello, None, None).unwrap()], &alice_pair),
+        (vec![write_message(&program_id, &bob, &alice, &alice_box, 1, b"are you there?", None, None).unwrap()], &bob_pair),
+        (vec![
+            mark_messages(&program_id, &alice, &alice_box, &[hello_pubkey], MESSAGE_STATUS_READ, 0).unwrap(),
+            set_postage(&program_id, &alice, &[alice_box], 5000).unwrap(),
+        ], &alice_pair),
+        (vec![
+            mark_messages(&program_id, &bob, &bob_box, &[hi_pubkey], MESSAGE_STATUS_STARRED, 0).unwrap(),
+            set_message_expiry(&program_id, &bob, &bob_box, &hi_pubkey, MessageExpiry::Slot(1000)).unwrap(),
+        ], &bob_pair),
+        (vec![delete_message(&program_id, &alice, &alice_box, &second_pubkey, &alice).unwrap()], &alice_pair),
+    ];
+    for (instructions, signer) in steps {
+        let mut transaction = Transaction::new_with_payer(&instructions, Some(&signer.pubkey()));
+        transaction.sign(&[signer], recent_blockhash);
+        let result = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+        result.result.unwrap();
+        record(&mut fixture, &instructions, &result.metadata.unwrap().log_messages);
+    }
+
+    for pubkey in [alice_box, bob_box, hello_pubkey, hi_pubkey, second_pubkey] {
+        let account = banks_client.get_account(pubkey).await.unwrap().unwrap_or_default();
+        fixture.push_str(&format!("account {} {} {}\n", pubkey, account.owner, STANDARD.encode(&account.data)));
+    }
+    std::fs::write(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/indexer/mailbox.txt"), fixture).unwrap();
+}
+
+/// Prints the compute units WriteMessage, MarkMessages and DeleteMessage
+/// use on a full-size box
+///
+/// Builtin processors are not metered, so the numbers only mean something
+/// when the program runs as SBF: `cargo test-sbf -- test_compute_units --nocapture`
+#[tokio::test]
+async fn test_compute_units() {
+    let program_id = program_id();
+    let owner_pair = Keypair::new();
+    let sender_pair = Keypair::new();
+    let sol_box = Pubkey::new_unique();
+
+    let (mut banks_client, payer, recent_blockhash) = program_test(
+        &program_id,
+        &[&owner_pair.pubkey(), &sender_pair.pubkey()],
+        &[(&sol_box, &owner_pair.pubkey())],
+    ).start().await;
+
+    let mut write_units = Vec::new();
+    for message_seq in 0..DEFAULT_SOL_BOX_NUM_SPOTS as u64 {
+        let result = banks_client.process_transaction_with_metadata(write_message_transaction(
+            &payer,
+            &sender_pair,
+            &owner_pair.pubkey(),
+            &sol_box,
+            message_seq,
+            "hello world!",
+            recent_blockhash,
+        )).await.unwrap();
+        result.result.unwrap();
+        write_units.push(result.metadata.unwrap().compute_units_consumed);
+    }
+    let (message_pubkey, _) = find_message_address(&program_id, &sol_box, DEFAULT_SOL_BOX_NUM_SPOTS as u64 - 1);
+
+    let mut transaction = Transaction::new_with_payer(&[mark_messages(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &[message_pubkey],
+        MESSAGE_STATUS_READ,
+        0,
+    ).unwrap()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    let result = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+    result.result.unwrap();
+    let mark_units = result.metadata.unwrap().compute_units_consumed;
+
+    let mut transaction = Transaction::new_with_payer(&[delete_message(
+        &program_id,
+        &owner_pair.pubkey(),
+        &sol_box,
+        &message_pubkey,
+        &owner_pair.pubkey(),
+    ).unwrap()], Some(&payer.pubkey()));
+    transaction.sign(&[&payer, &owner_pair], recent_blockhash);
+    let result = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+    result.result.unwrap();
+    let delete_units = result.metadata.unwrap().compute_units_consumed;
+
+    println!("WriteMessage, first slot: {} CU", write_units[0]);
+    println!("WriteMessage, last slot: {} CU", write_units[write_units.len() - 1]);
+    println!("MarkMessages, one message: {} CU", mark_units);
+    println!("DeleteMessage: {} CU", delete_units);
+    let sol_box_state = SolBox::unpack(&banks_client.get_account(sol_box).await.unwrap().unwrap().data).unwrap();
+    assert_eq!(DEFAULT_SOL_BOX_NUM_SPOTS - 1, sol_box_state.num_in_use);
+}
@@ -0,0 +1,158 @@
+#![cfg(feature = "indexer")]
+
+use {
+    base64::{engine::general_purpose::STANDARD, Engine},
+    solana_program::pubkey::Pubkey,
+    sol2sol::{
+        indexer::Indexer,
+        instruction::find_message_address,
+        state::{MessageExpiry, MESSAGE_STATUS_READ, MESSAGE_STATUS_STARRED},
+    },
+    std::str::FromStr,
+};
+
+/// Recorded by the ignored `record_indexer_fixture` test in `tests/client.rs`
+const MAILBOX_FIXTURE: &str = include_str!("fixtures/indexer/mailbox.txt");
+
+struct RecordedTransaction {
+    instructions: Vec<(Vec<u8>, Vec<Pubkey>)>,
+    log_messages: Vec<String>,
+}
+
+struct Fixture {
+    program_id: Pubkey,
+    transactions: Vec<RecordedTransaction>,
+    accounts: Vec<(Pubkey, Pubkey, Vec<u8>)>,
+}
+
+fn load_fixture(src: &str) -> Fixture {
+    let mut fixture = Fixture {
+        program_id: Pubkey::default(),
+        transactions: vec![],
+        accounts: vec![],
+    };
+    for line in src.lines() {
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match kind {
+            "program" => fixture.program_id = Pubkey::from_str(rest).unwrap(),
+            "tx" => fixture.transactions.push(RecordedTransaction {
+                instructions: vec![],
+                log_messages: vec![],
+            }),
+            "ix" => {
+                let (data, keys) = rest.split_once(' ').unwrap();
+                let keys = keys.split(',').map(|key| Pubkey::from_str(key).unwrap()).collect();
+                let transaction = fixture.transactions.last_mut().unwrap();
+                transaction.instructions.push((STANDARD.decode(data).unwrap(), keys));
+            }
+            "log" => fixture.transactions.last_mut().unwrap().log_messages.push(rest.to_string()),
+            "account" => {
+                let fields: Vec<&str> = rest.split(' ').collect();
+                fixture.accounts.push((
+                    Pubkey::from_str(fields[0]).unwrap(),
+                    Pubkey::from_str(fields[1]).unwrap(),
+                    STANDARD.decode(fields.get(2).unwrap_or(&"")).unwrap(),
+                ));
+            }
+            _ => panic!("unknown fixture line {}", line),
+        }
+    }
+    fixture
+}
+
+fn replay(indexer: &mut Indexer, fixture: &Fixture, with_instructions: bool) {
+    for transaction in &fixture.transactions {
+        indexer.apply_logs(&transaction.log_messages);
+        if with_instructions {
+            for (data, keys) in &transaction.instructions {
+                indexer.apply_instruction(data, keys).unwrap();
+            }
+        }
+    }
+}
+
+fn from_snapshots(fixture: &Fixture) -> Indexer {
+    let mut indexer = Indexer::new(fixture.program_id);
+    for (pubkey, owner, data) in &fixture.accounts {
+        indexer.apply_account(pubkey, owner, data).unwrap();
+    }
+    indexer
+}
+
+#[test]
+fn test_replay_matches_snapshots() {
+    let fixture = load_fixture(MAILBOX_FIXTURE);
+    let snapshots = from_snapshots(&fixture);
+
+    let mut replayed = Indexer::new(fixture.program_id);
+    replay(&mut replayed, &fixture, true);
+    assert_eq!(snapshots, replayed);
+
+    // Replaying on top of the final state changes nothing
+    let mut indexer = from_snapshots(&fixture);
+    replay(&mut indexer, &fixture, true);
+    assert_eq!(snapshots, indexer);
+}
+
+#[test]
+fn test_events_leave_out_bodies() {
+    let fixture = load_fixture(MAILBOX_FIXTURE);
+    let snapshots = from_snapshots(&fixture);
+    let mut indexer = Indexer::new(fixture.program_id);
+    replay(&mut indexer, &fixture, false);
+
+    for owner in snapshots.owners() {
+        assert_eq!(snapshots.sol_boxes(&owner), indexer.sol_boxes(&owner));
+        let messages = indexer.messages(&owner);
+        assert_eq!(snapshots.messages(&owner).len(), messages.len());
+        for (key, message) in messages {
+            assert!(message.msg_data.is_empty());
+            let snapshot = snapshots.message(&key).unwrap();
+            assert_eq!(snapshot.msg_size, message.msg_size);
+            assert_eq!(snapshot.status, message.status);
+        }
+    }
+}
+
+#[test]
+fn test_queries() {
+    let fixture = load_fixture(MAILBOX_FIXTURE);
+    let indexer = from_snapshots(&fixture);
+    let owners = indexer.owners();
+    assert_eq!(2, owners.len());
+    let alice = *owners
+        .iter()
+        .find(|owner| indexer.sol_boxes(owner)[0].1.postage_lamports == 5000)
+        .unwrap();
+    let bob = *owners.iter().find(|owner| **owner != alice).unwrap();
+
+    let alice_box = indexer.sol_boxes(&alice)[0].0;
+    let (hello_pubkey, _) = find_message_address(&fixture.program_id, &alice_box, 0);
+    let (second_pubkey, _) = find_message_address(&fixture.program_id, &alice_box, 1);
+    let bob_box = indexer.sol_boxes(&bob)[0].0;
+    let (hi_pubkey, _) = find_message_address(&fixture.program_id, &bob_box, 0);
+
+    // The second message was deleted
+    let alice_messages = indexer.messages(&alice);
+    assert_eq!(1, alice_messages.len());
+    assert_eq!(hello_pubkey, alice_messages[0].0);
+    assert_eq!("hello alice", alice_messages[0].1.msg_str().unwrap());
+    assert!(indexer.message(&second_pubkey).is_none());
+    assert_eq!(2, indexer.sol_box(&alice_box).unwrap().message_seq);
+
+    assert!(indexer.unread(&alice).is_empty());
+    let bob_unread = indexer.unread(&bob);
+    assert_eq!(vec![hi_pubkey], bob_unread.iter().map(|(key, _)| *key).collect::<Vec<_>>());
+    assert_eq!(MESSAGE_STATUS_STARRED, bob_unread[0].1.status);
+    assert_eq!(MessageExpiry::Slot(1000), bob_unread[0].1.expiry);
+    assert_eq!(MESSAGE_STATUS_READ, indexer.message(&hello_pubkey).unwrap().status);
+
+    // Both sides see the same conversation
+    for owner in [alice, bob] {
+        let threads = indexer.threads(&owner);
+        assert_eq!(1, threads.len());
+        assert_eq!(hello_pubkey, threads[0].message_pubkey);
+        assert_eq!(hi_pubkey, threads[0].replies[0].message_pubkey);
+        assert_eq!("hi bob", threads[0].replies[0].message.msg_str().unwrap());
+    }
+}
@@ -0,0 +1,234 @@
+#![cfg(feature = "banks-client")]
+
+use {
+    solana_program_test::*,
+    solana_sdk::{
+        account::Account,
+        instruction::Instruction,
+        packet::PACKET_DATA_SIZE,
+        rent::Rent,
+        signature::Signer,
+        signer::keypair::Keypair,
+        system_instruction,
+        transaction::{Transaction, TransactionError},
+    },
+    solana_program::pubkey::Pubkey,
+    sol2sol::{
+        client::{BanksTransport, ClientError, MailboxClient, Transport},
+        error::Sol2SolError,
+        instruction::{delete_message, find_sol_box_address, set_encryption_key, set_postage, transfer_sol_box_ownership},
+        processor::Processor,
+        state::{SolBox, MIN_SOL_BOX_NUM_SPOTS, MESSAGE_STATUS_READ},
+    },
+    std::str::FromStr,
+};
+
+fn program_id() -> Pubkey {
+    Pubkey::from_str("invoker111111111111111111111111111111111111").unwrap()
+}
+
+/// Starts a bank with clients for the test payer and a second funded wallet
+async fn start() -> (MailboxClient<BanksTransport>, MailboxClient<BanksTransport>) {
+    let program_test = ProgramTest::new(
+        "sol2sol",
+        program_id(),
+        processor!(Processor::process_instruction),
+    );
+    let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+    let sender = Keypair::new();
+    let mut transaction = Transaction::new_with_payer(
+        &[system_instruction::transfer(&payer.pubkey(), &sender.pubkey(), 1_000_000_000)],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    banks_client.process_transaction(transaction).await.unwrap();
+
+    let owner = MailboxClient::new(program_id(), BanksTransport::new(banks_client.clone(), payer));
+    let sender = MailboxClient::new(program_id(), BanksTransport::new(banks_client, sender));
+    (owner, sender)
+}
+
+#[tokio::test]
+async fn test_mailbox_client() {
+    let (mut owner, mut sender) = start().await;
+    let owner_pubkey = owner.owner();
+
+    assert_eq!(
+        Err(ClientError::InboxNotFound(owner_pubkey)),
+        sender.send(&owner_pubkey, b"anyone home?").await
+    );
+    assert_eq!(
+        Err(ClientError::Program(Sol2SolError::SolBoxInvalidNumSpots.into())),
+        owner.create_inbox(MIN_SOL_BOX_NUM_SPOTS - 1).await
+    );
+
+    let root = owner.create_inbox(MIN_SOL_BOX_NUM_SPOTS).await.unwrap();
+    assert_eq!(find_sol_box_address(&program_id(), &owner_pubkey, 0).0, root);
+    assert!(owner.list().await.unwrap().is_empty());
+
+    // Fill the root, then make room with a second box
+    let mut sent = vec![];
+    for i in 0..MIN_SOL_BOX_NUM_SPOTS {
+        let body = format!("message {}", i);
+        sent.push(sender.send(&owner_pubkey, body.as_bytes()).await.unwrap());
+    }
+    assert_eq!(
+        Err(ClientError::InboxFull(owner_pubkey)),
+        sender.send(&owner_pubkey, b"overflow").await
+    );
+    let tail = owner.create_inbox(MIN_SOL_BOX_NUM_SPOTS).await.unwrap();
+    let tail_account = owner.transport().get_account(&tail).await.unwrap().unwrap();
+    assert_eq!(SolBox::get_packed_len(MIN_SOL_BOX_NUM_SPOTS), tail_account.data.len());
+    sent.push(sender.send(&owner_pubkey, b"overflow").await.unwrap());
+
+    let listed = owner.list().await.unwrap();
+    assert_eq!(sent, listed.iter().map(|(key, _)| *key).collect::<Vec<_>>());
+    assert_eq!(b"message 0".to_vec(), listed[0].1.msg_data);
+    assert_eq!(sender.owner(), listed[0].1.sender);
+    assert_eq!(b"overflow".to_vec(), listed[5].1.msg_data);
+
+    // Reading marks the message read
+    let message = owner.read(&sent[5]).await.unwrap();
+    assert_eq!(MESSAGE_STATUS_READ, message.status);
+    assert_eq!(MESSAGE_STATUS_READ, owner.list().await.unwrap()[5].1.status);
+    assert_eq!(Err(ClientError::MessageNotFound(sent[5])), sender.read(&sent[5]).await);
+
+    // Deleting frees a slot in the root again
+    owner.delete(&sent[1]).await.unwrap();
+    assert_eq!(Err(ClientError::MessageNotFound(sent[1])), owner.read(&sent[1]).await);
+    let refill = sender.send(&owner_pubkey, b"refill").await.unwrap();
+    assert_eq!(refill, owner.list().await.unwrap()[1].0);
+
+    let set_postage_ix = set_postage(&program_id(), &owner_pubkey, &[root, tail], 10_000_000_000).unwrap();
+    owner.transport().process_instructions(&[set_postage_ix], &[]).await.unwrap();
+    assert!(matches!(
+        sender.send(&owner_pubkey, b"too dear").await,
+        Err(ClientError::InsufficientFunds { .. })
+    ));
+
+    let balance_before = owner.transport().get_account(&owner_pubkey).await.unwrap().unwrap().lamports;
+    owner.close().await.unwrap();
+    for key in sent.iter().chain([&root, &tail, &refill]) {
+        assert_eq!(None, owner.transport().get_account(key).await.unwrap());
+    }
+    let balance_after = owner.transport().get_account(&owner_pubkey).await.unwrap().unwrap().lamports;
+    assert!(balance_after > balance_before);
+    assert_eq!(Err(ClientError::InboxNotFound(owner_pubkey)), owner.list().await);
+}
+
+/// `BanksTransport` recording the serialized size of every transaction
+struct SizeRecorder {
+    inner: BanksTransport,
+    sizes: Vec<usize>,
+}
+
+impl Transport for SizeRecorder {
+    fn payer(&self) -> Pubkey {
+        self.inner.payer()
+    }
+
+    async fn get_account(&mut self, pubkey: &Pubkey) -> Result<Option<Account>, ClientError> {
+        self.inner.get_account(pubkey).await
+    }
+
+    async fn get_rent(&mut self) -> Result<Rent, ClientError> {
+        self.inner.get_rent().await
+    }
+
+    async fn process_instructions(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), ClientError> {
+        let recent_blockhash = self.inner.banks_client.get_latest_blockhash().await.unwrap();
+        let mut transaction = Transaction::new_with_payer(instructions, Some(&self.inner.payer.pubkey()));
+        let mut all_signers = vec![&self.inner.payer];
+        all_signers.extend_from_slice(signers);
+        transaction.sign(&all_signers, recent_blockhash);
+        self.sizes.push(bincode::serialized_size(&transaction).unwrap() as usize);
+        self.inner.process_instructions(instructions, signers).await
+    }
+}
+
+#[tokio::test]
+async fn test_mailbox_client_batches_close() {
+    let (owner, mut sender) = start().await;
+    let owner_pubkey = owner.owner();
+    let mut owner = MailboxClient::new(
+        program_id(),
+        SizeRecorder { inner: owner.into_transport(), sizes: vec![] },
+    );
+    let num_spots = 40;
+    let root = owner.create_inbox(num_spots).await.unwrap();
+    let mut sent = vec![];
+    for i in 0..num_spots {
+        sent.push(sender.send(&owner_pubkey, &i.to_le_bytes()).await.unwrap());
+    }
+
+    owner.transport().sizes.clear();
+    owner.close().await.unwrap();
+    for key in sent.iter().chain([&root]) {
+        assert_eq!(None, owner.transport().get_account(key).await.unwrap());
+    }
+    let sizes = &owner.transport().sizes;
+    assert!(sizes.len() > 2);
+    assert!(sizes.iter().all(|size| *size <= PACKET_DATA_SIZE), "{:?}", sizes);
+}
+
+#[tokio::test]
+async fn test_mailbox_client_transaction_error() {
+    let (mut owner, mut sender) = start().await;
+    let root = owner.create_inbox(MIN_SOL_BOX_NUM_SPOTS).await.unwrap();
+    let message = sender.send(&owner.owner(), b"hello").await.unwrap();
+
+    // The program rejects a sender deleting from someone else's inbox
+    let delete_ix = delete_message(&program_id(), &sender.owner(), &root, &message, &sender.owner()).unwrap();
+    assert!(matches!(
+        sender.transport().process_instructions(&[delete_ix], &[]).await,
+        Err(ClientError::Transaction(TransactionError::InstructionError(0, _)))
+    ));
+    assert_eq!(Err(ClientError::MessageNotFound(message)), sender.delete(&message).await);
+}
+
+#[tokio::test]
+async fn test_mailbox_client_seals_to_encryption_key() {
+    let (mut owner, mut sender) = start().await;
+    let owner_pubkey = owner.owner();
+    let root = owner.create_inbox(MIN_SOL_BOX_NUM_SPOTS).await.unwrap();
+    let secret_key = [42; 32];
+    #[cfg(feature = "encryption")]
+    let encryption_key = sol2sol::envelope::public_key(&secret_key);
+    #[cfg(not(feature = "encryption"))]
+    let encryption_key = secret_key;
+    let set_key_ix = set_encryption_key(&program_id(), &owner_pubkey, &[root], &encryption_key).unwrap();
+    owner.transport().process_instructions(&[set_key_ix], &[]).await.unwrap();
+
+    let sent = sender.send(&owner_pubkey, b"for your eyes only").await;
+    #[cfg(feature = "encryption")]
+    {
+        let message = owner.read(&sent.unwrap()).await.unwrap();
+        let envelope = sol2sol::envelope::Envelope::unpack(&message.msg_data).unwrap();
+        assert_eq!(b"for your eyes only".to_vec(), sol2sol::envelope::open(&secret_key, &envelope).unwrap());
+    }
+    #[cfg(not(feature = "encryption"))]
+    assert_eq!(Err(ClientError::EncryptionRequired(root)), sent);
+}
+
+#[tokio::test]
+async fn test_mailbox_client_after_transfer() {
+    let (mut owner, mut new_owner) = start().await;
+    let root = owner.create_inbox(MIN_SOL_BOX_NUM_SPOTS).await.unwrap();
+    let message = owner.send(&owner.owner(), b"note to self").await.unwrap();
+
+    let transfer_ix = transfer_sol_box_ownership(&program_id(), &owner.owner(), &new_owner.owner(), &[root], None, false).unwrap();
+    owner.transport().process_instructions(&[transfer_ix], &[]).await.unwrap();
+
+    // The message still names the previous owner, but is read from the new inbox
+    assert_eq!(Err(ClientError::MessageNotFound(message)), owner.read(&message).await);
+    let read = new_owner.read(&message).await.unwrap();
+    assert_eq!(owner.owner(), read.recipient);
+    assert_eq!(MESSAGE_STATUS_READ, read.status);
+    new_owner.delete(&message).await.unwrap();
+    assert!(new_owner.list().await.unwrap().is_empty());
+}